@@ -0,0 +1,43 @@
+//! Compare the cost of upgrading a connection with noise against TLS.
+use criterion::{criterion_group, criterion_main, Criterion};
+use libp2p::identity;
+
+use substrate_padawan::scratch::upgrade::{Event, Role, Security, Upgrade};
+
+/// Exchange bytes between the two peers until both complete the upgrade
+fn upgrade(security: Security, dialer: &identity::Keypair, listener: &identity::Keypair) {
+    let mut dialer = Upgrade::new(Role::Dialer, dialer.clone()).with_security(&[security]);
+    let mut listener = Upgrade::new(Role::Listener, listener.clone()).with_security(&[security]);
+    loop {
+        let (to_listener, to_dialer) = (dialer.poll_transmit(), listener.poll_transmit());
+        if to_listener.is_none() && to_dialer.is_none() {
+            break;
+        }
+        if let Some(bytes) = to_listener {
+            listener.handle_input(&bytes);
+        }
+        if let Some(bytes) = to_dialer {
+            dialer.handle_input(&bytes);
+        }
+    }
+    for mut upgrade in [dialer, listener] {
+        let established = std::iter::from_fn(|| upgrade.poll_event())
+            .any(|event| matches!(event, Event::Established));
+        assert!(established);
+    }
+}
+
+fn handshakes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("handshake");
+    let dialer = identity::Keypair::generate_ed25519();
+    let listener = identity::Keypair::generate_ed25519();
+    for security in [Security::Noise, Security::Tls] {
+        group.bench_function(security.to_string(), |b| {
+            b.iter(|| upgrade(security, &dialer, &listener))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, handshakes);
+criterion_main!(benches);
@@ -0,0 +1,135 @@
+//! Compare the allocation-free noise frame path against allocating
+//! fresh buffers for every frame, and measure the throughput of a
+//! secure stream that reuses its buffers.
+use bytes::BytesMut;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::runtime::Runtime;
+
+use substrate_padawan::scratch::connection::Connection;
+use substrate_padawan::scratch::noise::cipher::{CipherState, KEY_SIZE};
+use substrate_padawan::scratch::noise::pool::{BufferPool, BUFFER_CAPACITY};
+use substrate_padawan::scratch::noise::stream::NoiseStream;
+use substrate_padawan::scratch::noise::wire;
+
+const TAG_SIZE: usize = 16;
+
+fn cipher_pair() -> (CipherState, CipherState) {
+    let key = [7; KEY_SIZE];
+    (CipherState::new(&key), CipherState::new(&key))
+}
+
+/// Encrypt, frame, and decrypt a message, allocating buffers for every step
+fn roundtrip_allocating(sender: &mut CipherState, receiver: &mut CipherState, message: &[u8]) {
+    let mut encrypted = vec![0; message.len() + 1024];
+    let n = sender.encrypt(message, &mut encrypted).unwrap();
+    encrypted.truncate(n);
+    let mut frame = Vec::new();
+    frame.extend_from_slice(&(n as u16).to_be_bytes());
+    frame.extend_from_slice(&encrypted);
+
+    let payload = frame[2..].to_vec();
+    let mut decrypted = vec![0; payload.len()];
+    let n = receiver.decrypt(&payload, &mut decrypted).unwrap();
+    decrypted.truncate(n);
+    black_box(decrypted);
+}
+
+/// Encrypt, frame, and decrypt a message in place, reusing the `buffer`
+fn roundtrip_in_place(
+    sender: &mut CipherState,
+    receiver: &mut CipherState,
+    message: &[u8],
+    buffer: &mut BytesMut,
+) {
+    wire::encrypt_frame(sender, message, buffer).unwrap();
+    let mut payload = wire::decode_frame(buffer).unwrap().unwrap();
+    receiver.decrypt_in_place(&mut payload).unwrap();
+    black_box(payload);
+}
+
+fn frames(c: &mut Criterion) {
+    let mut group = c.benchmark_group("frames");
+    for size in [64, 1024, wire::MAX_PLAINTEXT_SIZE] {
+        let message = vec![42; size];
+        group.throughput(Throughput::Bytes((size + TAG_SIZE) as u64));
+        group.bench_with_input(BenchmarkId::new("allocating", size), &message, |b, m| {
+            let (mut sender, mut receiver) = cipher_pair();
+            b.iter(|| roundtrip_allocating(&mut sender, &mut receiver, m));
+        });
+        group.bench_with_input(BenchmarkId::new("in_place", size), &message, |b, m| {
+            let (mut sender, mut receiver) = cipher_pair();
+            let mut buffer = BytesMut::with_capacity(BUFFER_CAPACITY);
+            b.iter(|| roundtrip_in_place(&mut sender, &mut receiver, m, &mut buffer));
+        });
+    }
+    group.finish();
+}
+
+/// Establish a pair of secure streams over an in-memory pipe
+async fn stream_pair() -> (NoiseStream<DuplexStream>, NoiseStream<DuplexStream>) {
+    let (dialer, listener) = tokio::io::duplex(wire::MAX_FRAME_SIZE);
+    let (mut dialer, mut listener) = (Connection::from(dialer), Connection::from(listener));
+    let (dialed, listened) = tokio::join!(dialer.dial(), listener.listen());
+    dialed.unwrap();
+    listened.unwrap();
+    (
+        dialer.into_stream().unwrap(),
+        listener.into_stream().unwrap(),
+    )
+}
+
+/// Write a message to one stream and read it whole from the other
+async fn transfer(
+    writer: &mut NoiseStream<DuplexStream>,
+    reader: &mut NoiseStream<DuplexStream>,
+    message: &[u8],
+    received: &mut [u8],
+) {
+    let write = async {
+        writer.write_all(message).await.unwrap();
+        writer.flush().await.unwrap();
+    };
+    let read = async { reader.read_exact(received).await.unwrap() };
+    tokio::join!(write, read);
+    black_box(received);
+}
+
+fn stream(c: &mut Criterion) {
+    let mut group = c.benchmark_group("stream");
+    let runtime = Runtime::new().unwrap();
+    let (mut dialer, mut listener) = runtime.block_on(stream_pair());
+    for size in [1024, wire::MAX_PLAINTEXT_SIZE, 1 << 20] {
+        let message = vec![42; size];
+        let mut received = vec![0; size];
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::new("transfer", size), &message, |b, m| {
+            b.iter(|| runtime.block_on(transfer(&mut dialer, &mut listener, m, &mut received)));
+        });
+    }
+    group.finish();
+}
+
+fn buffers(c: &mut Criterion) {
+    let mut group = c.benchmark_group("buffers");
+    let message = [42; 1024];
+    group.bench_function("allocating", |b| {
+        b.iter(|| {
+            let mut buffer = BytesMut::with_capacity(BUFFER_CAPACITY);
+            buffer.extend_from_slice(&message);
+            black_box(buffer);
+        })
+    });
+    group.bench_function("pooled", |b| {
+        let pool = BufferPool::default();
+        b.iter(|| {
+            let mut buffer = pool.acquire();
+            buffer.extend_from_slice(&message);
+            pool.release(black_box(buffer));
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, frames, stream, buffers);
+criterion_main!(benches);
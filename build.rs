@@ -5,5 +5,8 @@ fn main() -> Result<()> {
         &["src/scratch/noise/proto/handshake_payload.proto"],
         &["src/"],
     )?;
+    if std::env::var_os("CARGO_FEATURE_PLAINTEXT").is_some() {
+        prost_build::compile_protos(&["src/scratch/plaintext/proto/exchange.proto"], &["src/"])?;
+    }
     Ok(())
 }
@@ -1,7 +1,18 @@
 use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::time::Duration;
 
 use clap::Parser;
-use substrate_padawan::{error, scratch::connection};
+use libp2p::{multiaddr, PeerId};
+use substrate_padawan::identity::{self, KeyType};
+use substrate_padawan::{
+    error,
+    scratch::{
+        connection,
+        noise::config::NoiseConfig,
+        upgrade::{Muxer, Security},
+    },
+};
 use tokio::net::{TcpListener, TcpStream};
 use tracing_subscriber::{EnvFilter, FmtSubscriber};
 
@@ -21,7 +32,10 @@ fn use_tracing_subscriber() {
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct CliArgs {
-    /// The ip address of the peer node
+    /// The ip address of the peer node.
+    ///
+    /// A multiaddr is also accepted, e.g. `/ip4/127.0.0.1/tcp/30333/p2p/<peer-id>`,
+    /// in which case the peer is required to authenticate as the given peer id.
     ip: String,
     /// The tcp port that the peer node listens to
     #[arg(long, short, default_value_t = 30333)]
@@ -31,6 +45,66 @@ struct CliArgs {
     /// If not given the node listens to a random tcp port.
     #[arg(long, short, default_value_t = 0)]
     listen_port: u16,
+    /// The hex-encoded ed25519 secret of the node key
+    #[arg(long, conflicts_with = "node_key_file")]
+    node_key: Option<String>,
+    /// The file holding the node key.
+    ///
+    /// The key may be stored as a raw 32-byte ed25519 secret, its hex encoding,
+    /// or a libp2p protobuf-encoded keypair. A new key is generated
+    /// and stored in the file if it does not exist.
+    #[arg(long)]
+    node_key_file: Option<PathBuf>,
+    /// The type of the identity key generated if no node key is given,
+    /// or if the node key file does not exist.
+    ///
+    /// One of `ed25519`, `secp256k1` or `ecdsa`.
+    #[arg(long, default_value_t = KeyType::Ed25519)]
+    key_type: KeyType,
+    /// Rotate the static noise key, shared by all connections, every given number of seconds.
+    ///
+    /// If not given the same static key is used for the lifetime of the node.
+    #[arg(long)]
+    noise_key_rotation: Option<u64>,
+    /// The comma-separated security protocols, `noise` or `tls`, in order of preference.
+    ///
+    /// Builds with the `plaintext` feature also accept `plaintext`, which disables encryption.
+    #[arg(long, value_delimiter = ',', default_value = "noise")]
+    security: Vec<Security>,
+    /// The comma-separated stream muxers, `yamux` or `mplex`, in order of preference
+    #[arg(long, value_delimiter = ',', default_value = "yamux,mplex")]
+    muxer: Vec<Muxer>,
+    /// The noise protocol of the handshake.
+    ///
+    /// Peers other than `substrate-scratch` nodes configured alike only speak the default.
+    #[arg(long, default_value_t = NoiseConfig::default())]
+    noise_protocol: NoiseConfig,
+    /// The hex-encoded static noise key of the peer node,
+    /// required by the `IK` and `XXfallback` patterns
+    #[arg(long)]
+    noise_remote_key: Option<String>,
+}
+
+impl CliArgs {
+    /// Resolve the address of the peer node, along with its expected peer id if given
+    fn remote(&self) -> error::Result<(Ipv4Addr, u16, Option<PeerId>)> {
+        if !self.ip.starts_with('/') {
+            return Ok((self.ip.parse()?, self.port, None));
+        }
+        let unsupported = || error::PadawanError::UnsupportedAddress(self.ip.clone());
+        let (mut ip, mut port, mut peer_id) = (None, self.port, None);
+        for protocol in self.ip.parse::<multiaddr::Multiaddr>()?.iter() {
+            match protocol {
+                multiaddr::Protocol::Ip4(addr) => ip = Some(addr),
+                multiaddr::Protocol::Tcp(tcp) => port = tcp,
+                multiaddr::Protocol::P2p(hash) => {
+                    peer_id = Some(PeerId::from_multihash(hash).map_err(|_| unsupported())?)
+                }
+                _ => return Err(unsupported()),
+            }
+        }
+        Ok((ip.ok_or_else(unsupported)?, port, peer_id))
+    }
 }
 
 #[tokio::main]
@@ -39,10 +113,31 @@ async fn main() -> error::Result<()> {
     env_logger::init();
 
     let args = CliArgs::parse();
-    let ipv4 = args.ip.parse::<Ipv4Addr>()?;
-    let dialer = TcpStream::connect((ipv4, args.port)).await?;
+    let keypair = identity::resolve(
+        args.node_key.as_deref(),
+        args.node_key_file.as_deref(),
+        args.key_type,
+    )?;
+    let (ipv4, port, peer_id) = args.remote()?;
+    let dialer = TcpStream::connect((ipv4, port)).await?;
     let localhost = Ipv4Addr::new(127, 0, 0, 1);
     let listener = TcpListener::bind((localhost, args.listen_port)).await?;
     tracing::info!("Listening on {}", listener.local_addr()?);
-    connection::Padawan::new(dialer, listener).start().await
+    let mut padawan = connection::Padawan::with_keypair(dialer, listener, keypair);
+    if let Some(peer_id) = peer_id {
+        padawan = padawan.with_remote_peer(peer_id);
+    }
+    if let Some(secs) = args.noise_key_rotation {
+        padawan = padawan.with_key_rotation(Duration::from_secs(secs));
+    }
+    let mut noise_config = args.noise_protocol;
+    if let Some(key) = args.noise_remote_key {
+        noise_config = noise_config.with_remote_static(&hex::decode(key)?);
+    }
+    padawan
+        .with_security(&args.security)
+        .with_muxers(&args.muxer)
+        .with_noise_config(noise_config)
+        .start()
+        .await
 }
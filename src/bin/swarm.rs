@@ -1,10 +1,11 @@
 use std::net::Ipv4Addr;
+use std::path::PathBuf;
 use std::time::Duration;
 
 use clap::Parser;
 use libp2p::multiaddr;
 use libp2p::swarm::dummy;
-use substrate_padawan::{error, swarm};
+use substrate_padawan::{error, identity, swarm};
 use tracing_subscriber::FmtSubscriber;
 
 fn use_tracing_subscriber() {
@@ -20,7 +21,10 @@ fn use_tracing_subscriber() {
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct CliArgs {
-    /// The ip address of the peer node
+    /// The ip address of the peer node.
+    ///
+    /// A multiaddr is also accepted, e.g. `/ip4/127.0.0.1/tcp/30333/p2p/<peer-id>`,
+    /// in which case the peer is required to authenticate as the given peer id.
     ip: String,
     /// The tcp port that the peer node listens to
     #[arg(long, short, default_value_t = 30333)]
@@ -28,6 +32,16 @@ struct CliArgs {
     /// The tcp timeout in secs
     #[arg(long)]
     timeout: Option<u64>,
+    /// The hex-encoded ed25519 secret of the node key
+    #[arg(long, conflicts_with = "node_key_file")]
+    node_key: Option<String>,
+    /// The file holding the node key.
+    ///
+    /// The key may be stored as a raw 32-byte ed25519 secret, its hex encoding,
+    /// or a libp2p protobuf-encoded keypair. A new key is generated
+    /// and stored in the file if it does not exist.
+    #[arg(long)]
+    node_key_file: Option<PathBuf>,
 }
 
 #[tokio::main]
@@ -36,10 +50,21 @@ async fn main() -> error::Result<()> {
     env_logger::init();
 
     let args = CliArgs::parse();
-    let mut remote = multiaddr::Multiaddr::from(args.ip.parse::<Ipv4Addr>()?);
-    remote.push(multiaddr::Protocol::Tcp(args.port));
+    let keypair = identity::resolve(
+        args.node_key.as_deref(),
+        args.node_key_file.as_deref(),
+        identity::KeyType::default(),
+    )?;
+    let remote = if args.ip.starts_with('/') {
+        args.ip.parse::<multiaddr::Multiaddr>()?
+    } else {
+        let mut remote = multiaddr::Multiaddr::from(args.ip.parse::<Ipv4Addr>()?);
+        remote.push(multiaddr::Protocol::Tcp(args.port));
+        remote
+    };
 
-    let padawan = swarm::Padawan::new(dummy::Behaviour, args.timeout.map(Duration::from_secs));
+    let padawan = swarm::Padawan::new(dummy::Behaviour, args.timeout.map(Duration::from_secs))
+        .with_keypair(keypair);
     tracing::info!("Local peer id: {:?}", padawan.peer_id());
 
     swarm::handshake(padawan, remote).await
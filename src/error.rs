@@ -1,5 +1,5 @@
 //! Library specific errors
-use libp2p::{core::transport, multiaddr, swarm::DialError};
+use libp2p::{core::transport, multiaddr, swarm::DialError, PeerId};
 use std::net::AddrParseError;
 use thiserror::Error;
 
@@ -10,20 +10,61 @@ pub enum PadawanError {
     UnexpectedMultistream,
     #[error("invalid encoding of a multistream protocol")]
     InvalidMultistreamEncoding,
+    #[error("exceeded maximum multistream message size")]
+    MultistreamMessageSizeExceeded,
+    #[error("invalid multistream protocol name: {0:?}")]
+    InvalidProtocolName(String),
     #[error(transparent)]
     VarintDecode(#[from] unsigned_varint::decode::Error),
+    #[error("remote peer does not support any of the proposed protocols")]
+    NegotiationFailed,
+    #[error("protocol negotiation timed out after {0:?}")]
+    NegotiationTimeout(std::time::Duration),
     #[error("handshake failed")]
     HandshakeFailed,
     #[error("exceeded maximum noise frame size")]
     NoiseFrameSizeExceeded,
+    #[error("unsupported noise protocol {0}")]
+    UnsupportedNoiseProtocol(String),
+    #[error("noise handshake failed, the remote peer may not use {0}")]
+    NoiseProtocolMismatch(String),
+    #[error("noise nonces exhausted, the connection must be closed")]
+    NonceExhausted,
+    #[error("could not decrypt noise message")]
+    DecryptFailed,
     #[error("missing remote noise key")]
     MissingRemoteNoiseKey,
+    #[error("unsupported security protocol {0}")]
+    UnsupportedSecurityProtocol(String),
+    #[error("unsupported stream muxer {0}")]
+    UnsupportedMuxer(String),
+    #[error("invalid libp2p tls certificate: {0}")]
+    InvalidCertificate(String),
     #[error("could not verify remote peer identity")]
     IdVerification,
+    #[error("expected remote peer {expected}, found {actual}")]
+    PeerIdMismatch {
+        expected: Box<PeerId>,
+        actual: Box<PeerId>,
+    },
+    #[error("yamux protocol violation: {0}")]
+    YamuxProtocol(String),
+    #[error("remote peer terminated the yamux session with code {0}")]
+    YamuxGoAway(u32),
+    #[error("mplex protocol violation: {0}")]
+    MplexProtocol(String),
+    #[error("stream muxer session closed")]
+    SessionClosed,
+    #[error("unsupported dial address: {0}")]
+    UnsupportedAddress(String),
     #[error(transparent)]
     SigningError(#[from] libp2p::identity::error::SigningError),
     #[error(transparent)]
     KeyDecodeError(#[from] libp2p::identity::error::DecodingError),
+    #[error("unsupported identity key type: {0}")]
+    UnsupportedKeyType(String),
+    #[error(transparent)]
+    HexDecode(#[from] hex::FromHexError),
     #[error(transparent)]
     EncodePayload(#[from] prost::EncodeError),
     #[error(transparent)]
@@ -31,6 +72,10 @@ pub enum PadawanError {
     #[error(transparent)]
     Snow(#[from] snow::error::Error),
     #[error(transparent)]
+    Certificate(#[from] rcgen::RcgenError),
+    #[error(transparent)]
+    Tls(#[from] rustls::Error),
+    #[error(transparent)]
     Io(#[from] std::io::Error),
     #[error(transparent)]
     AddressParse(#[from] AddrParseError),
@@ -42,5 +87,17 @@ pub enum PadawanError {
     Multiaddr(#[from] multiaddr::Error),
 }
 
+/// Surface the error from within an IO stream, e.g. a secure channel
+impl From<PadawanError> for std::io::Error {
+    fn from(err: PadawanError) -> Self {
+        use std::io::ErrorKind;
+        match err {
+            PadawanError::Io(err) => err,
+            PadawanError::NonceExhausted => Self::new(ErrorKind::BrokenPipe, err),
+            err => Self::new(ErrorKind::InvalidData, err),
+        }
+    }
+}
+
 /// Alias for a `std::result::Result` that always return an error of type [`PadawanError`][].
 pub type Result<T> = std::result::Result<T, PadawanError>;
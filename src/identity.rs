@@ -0,0 +1,385 @@
+//! Persistent identity of the local node.
+//!
+//! A node key can be stored in a file with any of the following encodings:
+//!
+//! * The raw 32-byte secret of an ed25519 keypair
+//! * The hex encoding of the above, as accepted by substrate's `--node-key`
+//! * The `libp2p` protobuf encoding of a keypair, of any [`KeyType`][]
+//! * The PKCS#8 DER encoding of an RSA keypair, with the `rsa` feature
+//!
+//! Ephemeral identities can be generated for any of the [`KeyType`][]s.
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+#[cfg(feature = "ecdsa")]
+use libp2p::identity::ecdsa;
+#[cfg(feature = "secp256k1")]
+use libp2p::identity::secp256k1;
+use libp2p::identity::{ed25519, Keypair};
+#[cfg(any(feature = "secp256k1", feature = "ecdsa"))]
+use unsigned_varint as varint;
+
+use crate::error::{PadawanError, Result};
+
+/// The length of an ed25519 secret key
+const SECRET_LENGTH: usize = 32;
+
+/// The protobuf tags of the key type and data fields of a private key
+#[cfg(any(feature = "secp256k1", feature = "ecdsa"))]
+const PROTOBUF_TAGS: (u8, u8) = (0x08, 0x12);
+
+/// The protobuf key type of secp256k1 keys
+#[cfg(feature = "secp256k1")]
+const PROTOBUF_SECP256K1: u8 = 2;
+
+/// The protobuf key type of ECDSA keys
+#[cfg(feature = "ecdsa")]
+const PROTOBUF_ECDSA: u8 = 3;
+
+/// The types of identity keys that can be generated for the local node.
+///
+/// RSA keys cannot be generated, but can be [`load`][]ed from a PKCS#8 file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KeyType {
+    #[default]
+    Ed25519,
+    #[cfg(feature = "secp256k1")]
+    Secp256k1,
+    #[cfg(feature = "ecdsa")]
+    Ecdsa,
+}
+
+impl KeyType {
+    /// Generate a new keypair of this type
+    pub fn generate(self) -> Keypair {
+        match self {
+            Self::Ed25519 => Keypair::generate_ed25519(),
+            #[cfg(feature = "secp256k1")]
+            Self::Secp256k1 => Keypair::generate_secp256k1(),
+            #[cfg(feature = "ecdsa")]
+            Self::Ecdsa => Keypair::generate_ecdsa(),
+        }
+    }
+
+    /// Generate a new keypair of this type, along with its encoding in a key file.
+    ///
+    /// Ed25519 keypairs are stored as their raw secret, and any other type
+    /// in the `libp2p` protobuf encoding.
+    fn generate_encoded(self) -> (Keypair, Vec<u8>) {
+        match self {
+            Self::Ed25519 => {
+                let keypair = ed25519::Keypair::generate();
+                let secret = keypair.secret().as_ref().to_vec();
+                (Keypair::Ed25519(keypair), secret)
+            }
+            #[cfg(feature = "secp256k1")]
+            Self::Secp256k1 => {
+                let keypair = secp256k1::Keypair::generate();
+                let encoded = encode_protobuf(PROTOBUF_SECP256K1, &keypair.secret().to_bytes());
+                (Keypair::Secp256k1(keypair), encoded)
+            }
+            #[cfg(feature = "ecdsa")]
+            Self::Ecdsa => {
+                let keypair = ecdsa::Keypair::generate();
+                let encoded = encode_protobuf(PROTOBUF_ECDSA, &keypair.secret().to_bytes());
+                (Keypair::Ecdsa(keypair), encoded)
+            }
+        }
+    }
+}
+
+impl fmt::Display for KeyType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Ed25519 => "ed25519",
+            #[cfg(feature = "secp256k1")]
+            Self::Secp256k1 => "secp256k1",
+            #[cfg(feature = "ecdsa")]
+            Self::Ecdsa => "ecdsa",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for KeyType {
+    type Err = PadawanError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "ed25519" => Ok(Self::Ed25519),
+            #[cfg(feature = "secp256k1")]
+            "secp256k1" => Ok(Self::Secp256k1),
+            #[cfg(feature = "ecdsa")]
+            "ecdsa" => Ok(Self::Ecdsa),
+            _ => Err(PadawanError::UnsupportedKeyType(s.to_owned())),
+        }
+    }
+}
+
+/// Create an ed25519 keypair from a raw 32-byte secret
+///
+/// # Errors
+///
+/// Fails if the secret is not a valid ed25519 secret key.
+pub fn from_secret(secret: &[u8]) -> Result<Keypair> {
+    let mut secret = secret.to_vec();
+    let secret = ed25519::SecretKey::from_bytes(&mut secret)?;
+    Ok(Keypair::Ed25519(secret.into()))
+}
+
+/// Create an ed25519 keypair from the hex encoding of its secret
+///
+/// # Errors
+///
+/// Fails in case of invalid hex, or if the decoded bytes
+/// are not a valid ed25519 secret key.
+pub fn from_hex(secret: &str) -> Result<Keypair> {
+    let secret = secret.trim();
+    let secret = secret.strip_prefix("0x").unwrap_or(secret);
+    from_secret(&hex::decode(secret)?)
+}
+
+/// Decode a keypair from any of the supported encodings
+///
+/// # Errors
+///
+/// Fails if the bytes cannot be decoded as a keypair.
+pub fn decode(bytes: &[u8]) -> Result<Keypair> {
+    if bytes.len() == SECRET_LENGTH {
+        return from_secret(bytes);
+    }
+    if let Ok(encoded) = std::str::from_utf8(bytes) {
+        let encoded = encoded.trim();
+        if encoded.len() == 2 * SECRET_LENGTH || encoded.starts_with("0x") {
+            return from_hex(encoded);
+        }
+    }
+    #[cfg(any(feature = "secp256k1", feature = "ecdsa"))]
+    if let Some(keypair) = decode_protobuf(bytes) {
+        return keypair;
+    }
+    match Keypair::from_protobuf_encoding(bytes) {
+        Ok(keypair) => Ok(keypair),
+        #[cfg(feature = "rsa")]
+        Err(err) => Keypair::rsa_from_pkcs8(&mut bytes.to_vec()).map_err(|_| err.into()),
+        #[cfg(not(feature = "rsa"))]
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Encode a private key in the `libp2p` protobuf encoding.
+///
+/// `libp2p` can only encode ed25519 keys in this way.
+#[cfg(any(feature = "secp256k1", feature = "ecdsa"))]
+fn encode_protobuf(key_type: u8, data: &[u8]) -> Vec<u8> {
+    let mut varint = [0; 10];
+    let mut encoded = vec![PROTOBUF_TAGS.0, key_type, PROTOBUF_TAGS.1];
+    encoded.extend_from_slice(varint::encode::usize(data.len(), &mut varint));
+    encoded.extend_from_slice(data);
+    encoded
+}
+
+/// Decode the `libp2p` protobuf encoding of the private keys that `libp2p`
+/// cannot decode, i.e. any key other than ed25519.
+///
+/// Returns `None` if the bytes do not encode such a key.
+#[cfg(any(feature = "secp256k1", feature = "ecdsa"))]
+fn decode_protobuf(bytes: &[u8]) -> Option<Result<Keypair>> {
+    let (key_type, rest) = match bytes {
+        [tag, key_type, data_tag, rest @ ..] if (*tag, *data_tag) == PROTOBUF_TAGS => {
+            (*key_type, rest)
+        }
+        _ => return None,
+    };
+    let data = match varint::decode::usize(rest) {
+        Ok((len, data)) if len == data.len() => data,
+        _ => return None,
+    };
+    let keypair = match key_type {
+        #[cfg(feature = "secp256k1")]
+        PROTOBUF_SECP256K1 => secp256k1::SecretKey::from_bytes(data.to_vec())
+            .map(|secret| Keypair::Secp256k1(secret.into())),
+        #[cfg(feature = "ecdsa")]
+        PROTOBUF_ECDSA => {
+            ecdsa::SecretKey::from_bytes(data).map(|secret| Keypair::Ecdsa(secret.into()))
+        }
+        _ => return None,
+    };
+    Some(keypair.map_err(Into::into))
+}
+
+/// Read a keypair from a key file
+///
+/// # Errors
+///
+/// Fails if the file cannot be read or decoded.
+pub fn load(path: impl AsRef<Path>) -> Result<Keypair> {
+    decode(&fs::read(path)?)
+}
+
+/// Read a keypair from a key file, or generate a new keypair of the given
+/// `key_type` and store it in the file if it does not exist.
+///
+/// The key type of an existing file is determined by its encoding.
+///
+/// # Errors
+///
+/// Fails if an existing file cannot be decoded, or in case of IO errors.
+pub fn load_or_generate(path: impl AsRef<Path>, key_type: KeyType) -> Result<Keypair> {
+    let path = path.as_ref();
+    if path.exists() {
+        return load(path);
+    }
+    let (keypair, encoded) = key_type.generate_encoded();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    write_secret(path, &encoded)?;
+    tracing::info!("Generated new {} node key at {}", key_type, path.display());
+    Ok(keypair)
+}
+
+#[cfg(unix)]
+fn write_secret(path: &Path, secret: &[u8]) -> Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(secret)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn write_secret(path: &Path, secret: &[u8]) -> Result<()> {
+    fs::write(path, secret)?;
+    Ok(())
+}
+
+/// Resolve the keypair of the local node from the command-line options.
+///
+/// An explicit hex-encoded `node_key` takes precedence over the `node_key_file`.
+/// A missing `node_key_file` is created with a new keypair of the given `key_type`.
+/// If neither is given, an ephemeral keypair of the given `key_type` is generated.
+///
+/// # Errors
+///
+/// Fails if the given key cannot be decoded, or the key file cannot be
+/// loaded or created.
+pub fn resolve(
+    node_key: Option<&str>,
+    node_key_file: Option<&Path>,
+    key_type: KeyType,
+) -> Result<Keypair> {
+    match (node_key, node_key_file) {
+        (Some(node_key), _) => from_hex(node_key),
+        (None, Some(path)) => load_or_generate(path, key_type),
+        (None, None) => Ok(key_type.generate()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::PeerId;
+
+    fn secret() -> (Keypair, Vec<u8>) {
+        let keypair = ed25519::Keypair::generate();
+        let secret = keypair.secret().as_ref().to_vec();
+        (Keypair::Ed25519(keypair), secret)
+    }
+
+    fn peer_id(keypair: &Keypair) -> PeerId {
+        keypair.public().to_peer_id()
+    }
+
+    #[test]
+    fn decode_encodings() {
+        let (keypair, secret) = secret();
+        let expected = peer_id(&keypair);
+        let hex = hex::encode(&secret);
+        for encoded in [
+            secret.clone(),
+            hex.clone().into_bytes(),
+            format!("0x{}\n", hex).into_bytes(),
+            keypair.to_protobuf_encoding().unwrap(),
+        ] {
+            assert_eq!(peer_id(&decode(&encoded).unwrap()), expected);
+        }
+    }
+
+    #[test]
+    fn decode_invalid() {
+        assert!(decode(b"not a key").is_err());
+        assert!(matches!(
+            from_hex(&"zz".repeat(SECRET_LENGTH)),
+            Err(PadawanError::HexDecode(_))
+        ));
+    }
+
+    #[test]
+    fn generate_on_first_run() {
+        let path = std::env::temp_dir()
+            .join(format!("padawan-{}", std::process::id()))
+            .join("node.key");
+        let _ = fs::remove_file(&path);
+
+        let generated = load_or_generate(&path, KeyType::Ed25519).unwrap();
+        assert_eq!(fs::read(&path).unwrap().len(), SECRET_LENGTH);
+        let loaded = load_or_generate(&path, KeyType::Ed25519).unwrap();
+        assert_eq!(peer_id(&generated), peer_id(&loaded));
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[cfg(feature = "secp256k1")]
+    #[test]
+    fn generate_key_type_on_first_run() {
+        let path = std::env::temp_dir()
+            .join(format!("padawan-secp256k1-{}", std::process::id()))
+            .join("node.key");
+        let _ = fs::remove_file(&path);
+
+        let generated = resolve(None, Some(&path), KeyType::Secp256k1).unwrap();
+        assert!(matches!(generated, Keypair::Secp256k1(_)));
+        let loaded = resolve(None, Some(&path), KeyType::Ed25519).unwrap();
+        assert!(matches!(loaded, Keypair::Secp256k1(_)));
+        assert_eq!(peer_id(&generated), peer_id(&loaded));
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn resolve_precedence() {
+        let (keypair, secret) = secret();
+        let missing = Path::new("/nonexistent/node.key");
+        let resolved = resolve(
+            Some(&hex::encode(secret)),
+            Some(missing),
+            KeyType::default(),
+        )
+        .unwrap();
+        assert_eq!(peer_id(&resolved), peer_id(&keypair));
+    }
+
+    #[test]
+    fn key_type_from_str() {
+        assert_eq!("ed25519".parse::<KeyType>().unwrap(), KeyType::Ed25519);
+        #[cfg(feature = "secp256k1")]
+        assert_eq!("Secp256k1".parse::<KeyType>().unwrap(), KeyType::Secp256k1);
+        assert!(matches!(
+            "dsa".parse::<KeyType>(),
+            Err(PadawanError::UnsupportedKeyType(_))
+        ));
+    }
+
+    #[cfg(feature = "rsa")]
+    #[test]
+    fn decode_rsa_pkcs8() {
+        let keypair = decode(include_bytes!("../testdata/rsa-2048.pk8")).unwrap();
+        assert!(matches!(keypair, Keypair::Rsa(_)));
+    }
+}
@@ -4,6 +4,9 @@
 //!
 //! * [`scratch`][]: A low-level implementation of the handshake.
 //! * [`swarm`][]: A high-level implementation using `libp2p-swarm` API.
+//!
+//! Both share the persistent node keys of [`identity`][].
 pub mod error;
+pub mod identity;
 pub mod scratch;
 pub mod swarm;
@@ -1,27 +1,45 @@
 //! Handle connections between peers implementing the `libp2p` networking stack.
+use std::io;
+use std::time::Duration;
+
+use bytes::BytesMut;
 use futures::{stream::FuturesUnordered, StreamExt};
 use libp2p::{identity, PeerId};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
 
 use crate::error::PadawanError;
+use crate::identity::KeyType;
+
+use super::mplex;
+use super::multiplexed::Multiplexed;
+use super::multistream_select::{framed::FramedRead, mirror, ProtocolName, Version};
+use super::noise::{config::NoiseConfig, keys::StaticKeys, pool::BufferPool, stream::NoiseStream};
+use super::secure::SecureStream;
+use super::tls::stream::TlsStream;
+use super::upgrade::{Event, Muxer, Role, SecureTransport, Security, Upgrade};
+use super::yamux;
 
-use super::multistream_select::{mirror, Protocol};
-use super::noise;
+/// The number of bytes requested from the wire on each read
+const READ_CHUNK_SIZE: usize = 4096;
 
 /// Represent the state of the `libp2p` upgrade negotionation
 /// that includes:
 ///
 /// * `multistream_select`
-/// * `noise` handshake
-/// * `yamux` negotiation
-#[derive(Default)]
+/// * `noise`, `tls`, or `plaintext` handshake
+/// * `yamux` or `mplex` negotiation
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 pub enum HandshakeState {
     Established,
     #[default]
     Initialization,
     Negotiation,
     Noise,
-    Multiplex(Box<noise::Transport>),
+    Tls,
+    #[cfg(feature = "plaintext")]
+    Plaintext,
+    Multiplex,
     Failed,
 }
 
@@ -35,26 +53,88 @@ impl HandshakeState {
 ///
 /// Capable of bidirectional communication with its peers.
 pub struct Padawan {
-    dialer: Connection,
+    dialer: Connection<TcpStream>,
     listener: TcpListener,
     keypair: identity::Keypair,
     peer_id: PeerId,
+    pool: BufferPool,
+    static_keys: StaticKeys,
+    noise_config: NoiseConfig,
+    security: Vec<Security>,
+    muxers: Vec<Muxer>,
 }
 
 impl Padawan {
-    /// Create a new local node
-    pub fn new(dialer: TcpStream, listener: TcpListener) -> Self {
-        let keypair = identity::Keypair::generate_ed25519();
+    /// Create a new local node with an auto-generated identity of the given type
+    pub fn new(dialer: TcpStream, listener: TcpListener, key_type: KeyType) -> Self {
+        Self::with_keypair(dialer, listener, key_type.generate())
+    }
+
+    /// Create a new local node with the identity of the given [`Keypair`][`identity::Keypair`]
+    pub fn with_keypair(
+        dialer: TcpStream,
+        listener: TcpListener,
+        keypair: identity::Keypair,
+    ) -> Self {
         let peer_id = PeerId::from_public_key(&keypair.public());
         tracing::info!("Local peer id: {}", peer_id);
+        let pool = BufferPool::default();
+        let static_keys = StaticKeys::new(keypair.clone());
+        let dialer = Connection::new(dialer, keypair.clone(), Some(peer_id))
+            .with_pool(pool.clone())
+            .with_static_keys(static_keys.clone());
         Self {
-            dialer: Connection::new(dialer, keypair.clone(), Some(peer_id)),
+            dialer,
             listener,
             keypair,
             peer_id,
+            pool,
+            static_keys,
+            noise_config: Default::default(),
+            security: vec![Security::Noise],
+            muxers: vec![Muxer::Yamux, Muxer::Mplex],
         }
     }
 
+    /// Rotate the static noise key, shared by all connections, once it is
+    /// older than the given `period`
+    pub fn with_key_rotation(mut self, period: Duration) -> Self {
+        self.static_keys = StaticKeys::new(self.keypair.clone()).with_rotation(period);
+        self.dialer = self.dialer.with_static_keys(self.static_keys.clone());
+        self
+    }
+
+    /// Speak the noise protocol of the given `config` on all connections.
+    ///
+    /// The static key of the remote peer, if any, is only used when dialing.
+    pub fn with_noise_config(mut self, config: NoiseConfig) -> Self {
+        self.dialer = self.dialer.with_noise_config(config.clone());
+        self.noise_config = config;
+        self
+    }
+
+    /// Propose, or accept, the given security protocols on all connections,
+    /// in order of preference
+    pub fn with_security(mut self, protocols: &[Security]) -> Self {
+        self.dialer = self.dialer.with_security(protocols);
+        self.security = protocols.to_vec();
+        self
+    }
+
+    /// Propose, or accept, the given stream muxers on all connections,
+    /// in order of preference
+    pub fn with_muxers(mut self, muxers: &[Muxer]) -> Self {
+        self.dialer = self.dialer.with_muxers(muxers);
+        self.muxers = muxers.to_vec();
+        self
+    }
+
+    /// Require the dialed peer to authenticate as the given [`PeerId`][]
+    pub fn with_remote_peer(mut self, peer_id: PeerId) -> Self {
+        self.dialer = self.dialer.with_expected_peer(peer_id);
+        self
+    }
+
     /// Start dialing and accepting new connections
     pub async fn start(mut self) -> Result<(), PadawanError> {
         let mut dial_listen = FuturesUnordered::new();
@@ -62,10 +142,18 @@ impl Padawan {
         dial_listen.push(tokio::spawn(async move {
             loop {
                 let (keypair, peer_id) = (self.keypair.clone(), self.peer_id);
+                let (pool, static_keys) = (self.pool.clone(), self.static_keys.clone());
+                let (noise_config, security) = (self.noise_config.clone(), self.security.clone());
+                let muxers = self.muxers.clone();
                 if let Ok((socket, addr)) = self.listener.accept().await {
                     tracing::info!("Incoming connection {}", addr);
                     tokio::spawn(async move {
-                        let mut listener = Connection::new(socket, keypair, Some(peer_id));
+                        let mut listener = Connection::new(socket, keypair, Some(peer_id))
+                            .with_pool(pool)
+                            .with_static_keys(static_keys)
+                            .with_noise_config(noise_config)
+                            .with_security(&security)
+                            .with_muxers(&muxers);
                         listener.listen().await
                     });
                 }
@@ -79,163 +167,579 @@ impl Padawan {
 }
 
 /// Represent a connection of the local node acting either as a dialer or listener
-pub struct Connection {
-    wire: TcpStream,
+///
+/// The connection can be established over any stream implementing
+/// [`AsyncRead`][] and [`AsyncWrite`][], e.g. a [`TcpStream`][].
+pub struct Connection<S = TcpStream> {
+    wire: S,
     state: HandshakeState,
+    version: Version,
     keypair: identity::Keypair,
     peer_id: PeerId,
+    expected_peer: Option<PeerId>,
+    remote_peer_id: Option<PeerId>,
+    early_muxer: bool,
+    /// The role of the local peer in the handshake, once started
+    role: Option<Role>,
+    /// The secure transport of the established connection, along with
+    /// any encrypted bytes received beyond the handshake
+    transport: Option<(SecureTransport, BytesMut)>,
+    /// The pool of the buffers used by the secure stream
+    pool: BufferPool,
+    /// The static noise key shared with the other connections of the local node
+    static_keys: Option<StaticKeys>,
+    /// The noise protocol of the handshake
+    noise_config: NoiseConfig,
+    /// The security protocols, in order of preference
+    security: Vec<Security>,
+    /// The stream muxers, in order of preference
+    muxers: Vec<Muxer>,
+    /// The stream muxer agreed with the remote peer, once established
+    muxer: Option<Muxer>,
+    /// Bytes read from the wire while probing, but not yet processed
+    received: BytesMut,
 }
 
-impl From<TcpStream> for Connection {
+impl<S: AsyncRead + AsyncWrite + Unpin> From<S> for Connection<S> {
     /// Create a new connection with auto-generated [`PeerId`][].
-    fn from(wire: TcpStream) -> Self {
-        let keypair = identity::Keypair::generate_ed25519();
-        let peer_id = PeerId::from_public_key(&keypair.public());
-        Self {
-            wire,
-            state: Default::default(),
-            keypair,
-            peer_id,
-        }
+    fn from(wire: S) -> Self {
+        Self::new(wire, identity::Keypair::generate_ed25519(), None)
     }
 }
 
-impl Connection {
+impl<S: AsyncRead + AsyncWrite + Unpin> Connection<S> {
     /// Create a new connection associated with the given [`Keypair`][`identity::Keypair`].
-    pub fn new(wire: TcpStream, keypair: identity::Keypair, peer_id: Option<PeerId>) -> Self {
+    pub fn new(wire: S, keypair: identity::Keypair, peer_id: Option<PeerId>) -> Self {
         let peer_id = peer_id.unwrap_or_else(|| PeerId::from_public_key(&keypair.public()));
         Self {
             wire,
             state: Default::default(),
+            version: Default::default(),
             keypair,
             peer_id,
+            expected_peer: None,
+            remote_peer_id: None,
+            early_muxer: true,
+            role: None,
+            transport: None,
+            pool: Default::default(),
+            static_keys: None,
+            noise_config: Default::default(),
+            security: vec![Security::Noise],
+            muxers: vec![Muxer::Yamux, Muxer::Mplex],
+            muxer: None,
+            received: BytesMut::new(),
         }
     }
 
+    /// Require the remote peer to authenticate as the given [`PeerId`][].
+    ///
+    /// The handshake fails with [`PadawanError::PeerIdMismatch`][] otherwise.
+    pub fn with_expected_peer(mut self, peer_id: PeerId) -> Self {
+        self.expected_peer = Some(peer_id);
+        self
+    }
+
+    /// Enable or disable the negotiation of the multiplex protocol within the noise handshake.
+    ///
+    /// When enabled, the default, the encrypted `multistream_select` exchange for the
+    /// multiplex protocol is skipped if both peers advertise a common stream muxer.
+    pub fn with_early_muxer(mut self, enabled: bool) -> Self {
+        self.early_muxer = enabled;
+        self
+    }
+
+    /// Use buffers from the given pool for the secure stream, e.g. to share
+    /// them with the other connections of the local node
+    pub fn with_pool(mut self, pool: BufferPool) -> Self {
+        self.pool = pool;
+        self
+    }
+
+    /// Use the static noise key of the local node, instead of generating one
+    /// for this connection
+    pub fn with_static_keys(mut self, static_keys: StaticKeys) -> Self {
+        self.static_keys = Some(static_keys);
+        self
+    }
+
+    /// Speak the noise protocol of the given `config`, instead of the one of `libp2p`
+    pub fn with_noise_config(mut self, config: NoiseConfig) -> Self {
+        self.noise_config = config;
+        self
+    }
+
+    /// Propose, or accept as a listener, the given security protocols in order
+    /// of preference, instead of noise alone
+    pub fn with_security(mut self, protocols: &[Security]) -> Self {
+        self.security = protocols.to_vec();
+        self
+    }
+
+    /// Propose, or accept as a listener, the given stream muxers in order
+    /// of preference, instead of yamux then mplex
+    pub fn with_muxers(mut self, muxers: &[Muxer]) -> Self {
+        self.muxers = muxers.to_vec();
+        self
+    }
+
+    /// Set the `multistream_select` variant used when dialing.
+    ///
+    /// [`V1Lazy`][`Version::V1Lazy`] only applies to negotiations of a single
+    /// candidate. Since yamux then mplex are proposed by default, the stream
+    /// muxer is only negotiated lazily once restricted to one through
+    /// [`with_muxers`][`Self::with_muxers`], and never when agreed during the
+    /// noise handshake.
+    pub fn with_version(mut self, version: Version) -> Self {
+        self.version = version;
+        self
+    }
+
     pub fn peer_id(&self) -> &PeerId {
         &self.peer_id
     }
 
+    /// The authenticated [`PeerId`][] of the remote peer, once the handshake completes
+    pub fn remote_peer_id(&self) -> Option<&PeerId> {
+        self.remote_peer_id.as_ref()
+    }
+
+    /// The stream muxer agreed with the remote peer, once the handshake completes
+    pub fn muxer(&self) -> Option<Muxer> {
+        self.muxer
+    }
+
+    /// Get a secure stream over the established connection, whichever security
+    /// protocol was agreed.
+    ///
+    /// Returns `None` if the handshake has not completed.
+    pub fn into_secure_stream(self) -> Option<SecureStream<S>> {
+        let stream = match self.transport? {
+            (SecureTransport::Noise(transport), received) => {
+                let stream = NoiseStream::new(self.wire, transport)
+                    .with_received(received)
+                    .with_pool(self.pool);
+                SecureStream::Noise(Box::new(stream))
+            }
+            (SecureTransport::Tls(session), received) => {
+                let stream = TlsStream::new(self.wire, session).with_received(received);
+                SecureStream::Tls(Box::new(stream))
+            }
+            #[cfg(feature = "plaintext")]
+            (SecureTransport::Plaintext, received) => SecureStream::Plaintext {
+                inner: self.wire,
+                received,
+            },
+        };
+        Some(stream)
+    }
+
+    /// Get a secure stream over the established connection.
+    ///
+    /// Returns `None` if the noise handshake has not completed.
+    pub fn into_stream(self) -> Option<NoiseStream<S>> {
+        match self.into_secure_stream()? {
+            SecureStream::Noise(stream) => Some(*stream),
+            _ => None,
+        }
+    }
+
+    /// Get a yamux session over the secure stream of the established connection.
+    ///
+    /// The dialer opens streams as the client of the session, and the listener as
+    /// the server. Returns `None` if the handshake has not completed, or another
+    /// stream muxer was agreed.
+    pub fn into_yamux(self) -> Option<yamux::Session<SecureStream<S>>> {
+        if self.muxer != Some(Muxer::Yamux) {
+            return None;
+        }
+        let mode = match self.role? {
+            Role::Dialer => yamux::Mode::Client,
+            Role::Listener => yamux::Mode::Server,
+        };
+        Some(yamux::Session::new(self.into_secure_stream()?, mode))
+    }
+
+    /// Get an mplex session over the secure stream of the established connection.
+    ///
+    /// Returns `None` if the handshake has not completed, or another stream
+    /// muxer was agreed.
+    pub fn into_mplex(self) -> Option<mplex::Session<SecureStream<S>>> {
+        if self.muxer != Some(Muxer::Mplex) {
+            return None;
+        }
+        Some(mplex::Session::new(self.into_secure_stream()?))
+    }
+
+    /// Get the established connection over the agreed stream muxer, for opening
+    /// substreams and dispatching inbound ones per protocol.
+    ///
+    /// Returns `None` if the handshake has not completed.
+    pub fn into_multiplexed(self) -> Option<Multiplexed<SecureStream<S>>> {
+        match self.muxer? {
+            Muxer::Yamux => self.into_yamux().map(Multiplexed::from),
+            Muxer::Mplex => self.into_mplex().map(Multiplexed::from),
+        }
+    }
+
+    /// Get a secure stream over a connection established with TLS.
+    ///
+    /// Returns `None` if the TLS handshake has not completed.
+    pub fn into_tls_stream(self) -> Option<TlsStream<S>> {
+        match self.into_secure_stream()? {
+            SecureStream::Tls(stream) => Some(*stream),
+            _ => None,
+        }
+    }
+
+    /// Get the underlying stream of a connection established with the plaintext
+    /// protocol, along with any bytes received beyond the handshake.
+    ///
+    /// Returns `None` if the plaintext handshake has not completed.
+    #[cfg(feature = "plaintext")]
+    pub fn into_plaintext_stream(self) -> Option<(S, BytesMut)> {
+        match self.into_secure_stream()? {
+            SecureStream::Plaintext { inner, received } => Some((inner, received)),
+            _ => None,
+        }
+    }
+
     /// The inner state of the handshake
     pub fn handshake_state(&self) -> &HandshakeState {
         &self.state
     }
 
-    /// Perform the handshake with the remote peer as a dialer
-    pub async fn dial(&mut self) -> Result<(), PadawanError> {
-        let (mut read, mut write) = self.wire.split();
-        loop {
-            match self.state {
-                HandshakeState::Initialization => {
-                    tracing::info!("Initializing handshake");
-                    let hello = Protocol::Multistream;
-                    if let Ok(true) = mirror::concurrent(&mut read, &mut write, hello).await {
-                        self.state = HandshakeState::Negotiation;
-                    } else {
-                        self.state = HandshakeState::Failed;
-                    }
-                }
-                HandshakeState::Negotiation => {
-                    tracing::info!("Negotiating protocol");
-                    let noise = Protocol::Noise;
-                    if let Ok(true) = mirror::dial(&mut read, &mut write, noise).await {
-                        self.state = HandshakeState::Noise;
-                    } else {
-                        self.state = HandshakeState::Failed;
-                    }
-                }
-                HandshakeState::Noise => {
-                    let mut handshake = noise::libp2p::NoiseHandshake::dialer()?;
-                    handshake.hello(&mut write).await?;
-                    handshake.recv_identity(&mut read).await?;
-                    handshake.send_identity(&mut write, &self.keypair).await?;
-                    let transport = handshake.into_inner().try_into()?;
-                    self.state = HandshakeState::Multiplex(Box::new(transport));
+    /// Ask the remote peer for the security protocols it supports.
+    ///
+    /// Performs the initial `multistream_select` exchange if needed,
+    /// after which [`dial`][`Connection::dial`] can resume the handshake.
+    pub async fn probe(&mut self) -> Result<Vec<ProtocolName>, PadawanError> {
+        let (read, mut write) = tokio::io::split(&mut self.wire);
+        let mut read = FramedRead::new(read).with_buffered(&self.received);
+        if let HandshakeState::Initialization = self.state {
+            tracing::info!("Initializing handshake");
+            let hello = &ProtocolName::MULTISTREAM;
+            self.state = match mirror::concurrent(&mut read, &mut write, hello).await {
+                Ok(true) => HandshakeState::Negotiation,
+                Ok(false) => HandshakeState::Failed,
+                Err(err) => {
+                    self.state = HandshakeState::Failed;
+                    return Err(err);
                 }
-                HandshakeState::Multiplex(ref mut transport) => {
-                    tracing::info!("Negotiating multiplex protocol");
-                    let headers = Protocol::Multistream;
-                    if mirror::dial_noise(&mut read, &mut write, headers, transport)
-                        .await
-                        .is_err()
-                    {
-                        self.state = HandshakeState::Failed;
-                        continue;
-                    }
-                    let yamux = Protocol::Yamux;
-                    if mirror::dial_noise(&mut read, &mut write, yamux, transport)
-                        .await
-                        .is_ok()
-                    {
-                        self.state = HandshakeState::Established;
-                        tracing::info!("Connection established");
-                    } else {
-                        self.state = HandshakeState::Failed;
-                    }
-                }
-                HandshakeState::Failed => return Err(PadawanError::HandshakeFailed),
-                HandshakeState::Established => break,
-            }
+            };
         }
-        Ok(())
+        if !matches!(self.state, HandshakeState::Negotiation) {
+            return Err(PadawanError::HandshakeFailed);
+        }
+        let listed = mirror::list(&mut read, &mut write).await;
+        // Keep any bytes received beyond the response for the handshake
+        self.received = read.into_parts().1;
+        listed
+    }
+
+    /// Perform the handshake with the remote peer as a dialer
+    pub async fn dial(&mut self) -> Result<(), PadawanError> {
+        self.upgrade(Role::Dialer).await
     }
 
     /// Perform the handshake with the remote peer as a listener
     pub async fn listen(&mut self) -> Result<(), PadawanError> {
-        let (mut read, mut write) = self.wire.split();
+        self.upgrade(Role::Listener).await
+    }
+
+    /// Drive the [`Upgrade`][] state machine over the wire
+    async fn upgrade(&mut self, role: Role) -> Result<(), PadawanError> {
+        match self.state {
+            HandshakeState::Established => return Ok(()),
+            HandshakeState::Failed => return Err(PadawanError::HandshakeFailed),
+            _ => {}
+        }
+        tracing::info!("Initializing handshake");
+        self.role = Some(role);
+        let mut upgrade = Upgrade::new(role, self.keypair.clone())
+            .with_version(self.version)
+            .with_early_muxer(self.early_muxer)
+            .with_noise_config(self.noise_config.clone())
+            .with_security(&self.security)
+            .with_muxers(&self.muxers);
+        if let HandshakeState::Negotiation = self.state {
+            upgrade = upgrade.headers_exchanged();
+        }
+        if let Some(peer_id) = self.expected_peer {
+            upgrade = upgrade.with_expected_peer(peer_id);
+        }
+        if let Some(static_keys) = &self.static_keys {
+            upgrade = upgrade.with_static_keys(static_keys.clone());
+        }
+        if !self.received.is_empty() {
+            upgrade.handle_input(&self.received.split());
+        }
+        let mut chunk = [0_u8; READ_CHUNK_SIZE];
         loop {
-            match self.state {
-                HandshakeState::Initialization => {
-                    tracing::info!("Initializing handshake");
-                    let hello = Protocol::Multistream;
-                    if let Ok(true) = mirror::concurrent(&mut read, &mut write, hello).await {
-                        self.state = HandshakeState::Negotiation;
-                    } else {
-                        self.state = HandshakeState::Failed;
-                    }
-                }
-                HandshakeState::Negotiation => {
-                    tracing::info!("Negotiating protocol");
-                    let noise = Protocol::Noise;
-                    if mirror::listen(&mut read, &mut write, noise).await.is_ok() {
-                        self.state = HandshakeState::Noise;
-                    } else {
-                        self.state = HandshakeState::Failed;
-                    }
-                }
-                HandshakeState::Noise => {
-                    let mut handshake = noise::libp2p::NoiseHandshake::listener()?;
-                    handshake.recv_hello(&mut read).await?;
-                    handshake.send_identity(&mut write, &self.keypair).await?;
-                    handshake.recv_identity(&mut read).await?;
-                    let transport = handshake.into_inner().try_into()?;
-                    self.state = HandshakeState::Multiplex(Box::new(transport));
-                }
-                HandshakeState::Multiplex(ref mut transport) => {
-                    tracing::info!("Negotiating multiplex protocol");
-                    let headers = Protocol::Multistream;
-                    if mirror::listen_noise(&mut read, &mut write, headers, transport)
-                        .await
-                        .is_err()
-                    {
-                        self.state = HandshakeState::Failed;
-                        continue;
+            while let Some(bytes) = upgrade.poll_transmit() {
+                self.wire.write_all(&bytes).await?;
+            }
+            self.wire.flush().await?;
+            while let Some(event) = upgrade.poll_event() {
+                match event {
+                    Event::ProtocolAgreed(protocol) => tracing::info!("Agreed on {}", protocol),
+                    Event::RemoteIdentified(peer_id) => {
+                        tracing::debug!("Remote peer identified as {}", peer_id)
                     }
-                    let yamux = Protocol::Yamux;
-                    if mirror::listen_noise(&mut read, &mut write, yamux, transport)
-                        .await
-                        .is_ok()
-                    {
+                    Event::Established => {
                         self.state = HandshakeState::Established;
+                        self.remote_peer_id = upgrade.remote_peer_id().copied();
+                        self.muxer = upgrade.muxer();
+                        self.transport = upgrade.into_transport();
                         tracing::info!("Connection established");
-                    } else {
+                        return Ok(());
+                    }
+                    Event::Failed { phase, reason } => {
+                        tracing::debug!("Handshake failed during {:?}", phase);
                         self.state = HandshakeState::Failed;
+                        return Err(reason);
                     }
                 }
-                HandshakeState::Failed => return Err(PadawanError::HandshakeFailed),
-                HandshakeState::Established => break,
             }
+            self.state = upgrade.state();
+            let n = self.wire.read(&mut chunk).await?;
+            if n == 0 {
+                self.state = HandshakeState::Failed;
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            upgrade.handle_input(&chunk[..n]);
         }
-        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch::multistream_select::Message;
+    use crate::scratch::session::{self, spawn_pair};
+    use tokio::io::DuplexStream;
+
+    /// The ends of a TCP connection, as a dialer and a listener
+    async fn tcp() -> (Connection, Connection) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (dialer, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        (
+            Connection::from(dialer.unwrap()),
+            Connection::from(accepted.unwrap().0),
+        )
+    }
+
+    /// The ends of an in-memory stream, as a dialer and a listener
+    fn in_memory() -> (Connection<DuplexStream>, Connection<DuplexStream>) {
+        let (dialer, listener) = tokio::io::duplex(1024);
+        (Connection::from(dialer), Connection::from(listener))
+    }
+
+    /// Dial and listen with the given connections, as configured by each test
+    async fn handshake<S>(
+        mut dialer: Connection<S>,
+        mut listener: Connection<S>,
+    ) -> (Connection<S>, Connection<S>)
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (dialed, listened) = tokio::join!(dialer.dial(), listener.listen());
+        dialed.unwrap();
+        listened.unwrap();
+        (dialer, listener)
+    }
+
+    /// Run the sessions of an established connection, and send a message
+    /// over a stream opened by the dialer
+    async fn send_over<M>(
+        dialer: session::Session<SecureStream<DuplexStream>, M>,
+        listener: session::Session<SecureStream<DuplexStream>, M>,
+    ) -> (session::Control<M>, session::Control<M>)
+    where
+        M: session::Muxer + Send + 'static,
+        M::Id: Send,
+        M::Frame: Send,
+        M::Stream: Send,
+        M::Substream: AsyncRead + AsyncWrite + Unpin + Send,
+        M::Request: Send,
+    {
+        let (dialer, listener) = spawn_pair(dialer, listener);
+        let mut outbound = dialer.open_stream().await.unwrap();
+        outbound.write_all(b"ping").await.unwrap();
+        outbound.shutdown().await.unwrap();
+        let mut inbound = listener.accept().await.unwrap();
+        let mut received = Vec::new();
+        inbound.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"ping");
+        (dialer, listener)
+    }
+
+    #[tokio::test]
+    async fn dial_listen() {
+        let (dialer, listener) = tcp().await;
+        handshake(dialer.with_version(Version::V1), listener).await;
+    }
+
+    #[tokio::test]
+    async fn dial_listen_in_memory() {
+        let (dialer, listener) = in_memory();
+        handshake(dialer, listener).await;
+    }
+
+    #[tokio::test]
+    async fn dial_expected_peer() {
+        let (dialer, listener) = in_memory();
+        let listener_id = *listener.peer_id();
+        let (dialer, listener) = handshake(dialer.with_expected_peer(listener_id), listener).await;
+        assert_eq!(dialer.remote_peer_id(), Some(&listener_id));
+        assert_eq!(listener.remote_peer_id(), Some(dialer.peer_id()));
+    }
+
+    #[tokio::test]
+    async fn dial_unexpected_peer() {
+        let (dialer, listener) = tokio::io::duplex(1024);
+        let mut listener = Connection::from(listener);
+        tokio::spawn(async move { listener.listen().await });
+        let mut dialer = Connection::from(dialer).with_expected_peer(PeerId::random());
+        let dialed = dialer.dial().await;
+        assert!(matches!(dialed, Err(PadawanError::PeerIdMismatch { .. })));
+        assert!(dialer.remote_peer_id().is_none());
+        assert_eq!(dialer.handshake_state(), &HandshakeState::Failed);
+    }
+
+    #[tokio::test]
+    async fn probe_then_dial() {
+        let (dialer, mut listener) = tokio::io::duplex(1024);
+        let mut dialer = Connection::from(dialer).with_security(&[Security::Tls]);
+        // The listener rejects the next proposal along with the response to `ls`
+        let mut responses = ProtocolName::MULTISTREAM.encode();
+        responses.extend(Message::Protocols(vec![ProtocolName::NOISE]).encode());
+        responses.extend(Message::NotAvailable.encode());
+        listener.write_all(&responses).await.unwrap();
+        assert_eq!(dialer.probe().await.unwrap(), vec![ProtocolName::NOISE]);
+        assert!(matches!(
+            dialer.dial().await,
+            Err(PadawanError::NegotiationFailed)
+        ));
+
+        let (dialer, listener) = tokio::io::duplex(1024);
+        drop(listener);
+        let mut dialer = Connection::from(dialer);
+        assert!(matches!(dialer.probe().await, Err(PadawanError::Io(_))));
+        assert_eq!(dialer.handshake_state(), &HandshakeState::Failed);
+    }
+
+    #[tokio::test]
+    async fn dial_listen_without_early_muxer() {
+        let (dialer, listener) = in_memory();
+        handshake(dialer.with_early_muxer(false), listener).await;
+    }
+
+    #[tokio::test]
+    async fn dial_listen_tls() {
+        let (dialer, listener) = in_memory();
+        let listener = listener.with_security(&[Security::Tls]);
+        let listener_id = *listener.peer_id();
+        let dialer = dialer
+            .with_security(&[Security::Tls])
+            .with_expected_peer(listener_id);
+        let (dialer, listener) = handshake(dialer, listener).await;
+        assert_eq!(dialer.remote_peer_id(), Some(&listener_id));
+        assert_eq!(listener.remote_peer_id(), Some(dialer.peer_id()));
+    }
+
+    #[cfg(feature = "plaintext")]
+    #[tokio::test]
+    async fn dial_listen_plaintext() {
+        let (dialer, listener) = in_memory();
+        let (dialer, listener) = handshake(
+            dialer.with_security(&[Security::Plaintext]),
+            listener.with_security(&[Security::Plaintext]),
+        )
+        .await;
+        assert_eq!(listener.remote_peer_id(), Some(dialer.peer_id()));
+
+        let (mut dialer, _) = dialer.into_plaintext_stream().unwrap();
+        let (mut listener, received) = listener.into_plaintext_stream().unwrap();
+        assert!(received.is_empty());
+        dialer.write_all(b"ping").await.unwrap();
+        let mut message = [0; 4];
+        listener.read_exact(&mut message).await.unwrap();
+        assert_eq!(&message, b"ping");
+    }
+
+    #[tokio::test]
+    async fn multiplex_streams() {
+        let (dialer, listener) = in_memory();
+        let (dialer, listener) = handshake(dialer, listener).await;
+        let (_, listener) =
+            send_over(dialer.into_yamux().unwrap(), listener.into_yamux().unwrap()).await;
+        assert!(listener.ping().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn multiplex_any_security() {
+        let protocols = [
+            Security::Tls,
+            #[cfg(feature = "plaintext")]
+            Security::Plaintext,
+        ];
+        for security in protocols {
+            let (dialer, listener) = in_memory();
+            let (dialer, listener) = handshake(
+                dialer.with_security(&[security]),
+                listener.with_security(&[security]),
+            )
+            .await;
+            assert_eq!(dialer.muxer(), Some(Muxer::Yamux));
+            send_over(dialer.into_yamux().unwrap(), listener.into_yamux().unwrap()).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn multiplex_mplex() {
+        let (dialer, listener) = in_memory();
+        let (dialer, listener) = handshake(dialer, listener.with_muxers(&[Muxer::Mplex])).await;
+        assert_eq!(dialer.muxer(), Some(Muxer::Mplex));
+        assert_eq!(listener.muxer(), Some(Muxer::Mplex));
+        send_over(dialer.into_mplex().unwrap(), listener.into_mplex().unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn dispatch_multiplexed() {
+        let (dialer, listener) = in_memory();
+        let (dialer, listener) = handshake(dialer, listener).await;
+        let echo = ProtocolName::new("/echo/1.0.0").unwrap();
+        let dialer = dialer.into_multiplexed().unwrap();
+        let listener =
+            listener
+                .into_multiplexed()
+                .unwrap()
+                .with_handler(echo.clone(), |stream| async move {
+                    let (mut read, mut write) = tokio::io::split(stream);
+                    tokio::io::copy(&mut read, &mut write).await.unwrap();
+                    write.shutdown().await.unwrap();
+                });
+        let control = dialer.control();
+        tokio::spawn(dialer.run());
+        tokio::spawn(listener.run());
+
+        let (mut stream, protocol) = control
+            .open_stream(std::slice::from_ref(&echo))
+            .await
+            .unwrap();
+        assert_eq!(protocol, echo);
+        stream.write_all(b"ping").await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"ping");
+    }
+
+    #[tokio::test]
+    async fn dial_lazy_listen() {
+        let (dialer, listener) = tcp().await;
+        handshake(dialer.with_version(Version::V1Lazy), listener).await;
     }
 }
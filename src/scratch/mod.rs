@@ -3,5 +3,14 @@
 //!
 //! [libp2p-conn-spec]: https://github.com/libp2p/specs/blob/master/connections/README.md
 pub mod connection;
+pub mod mplex;
+pub mod multiplexed;
 pub mod multistream_select;
 pub mod noise;
+#[cfg(feature = "plaintext")]
+pub mod plaintext;
+pub mod secure;
+pub mod session;
+pub mod tls;
+pub mod upgrade;
+pub mod yamux;
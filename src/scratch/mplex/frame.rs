@@ -0,0 +1,192 @@
+//! The frames of the [mplex specification][mplex-spec].
+//!
+//! Every frame starts with a `varint` header combining the stream number and
+//! the flag, followed by the `varint` length of the body.
+//!
+//! [mplex-spec]: https://github.com/libp2p/specs/blob/master/mplex/README.md
+use bytes::{BufMut, Bytes, BytesMut};
+use unsigned_varint as varint;
+
+use crate::error::PadawanError;
+
+/// The maximum size of the body of a frame, as set by the specification
+pub const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+/// The identifier of a stream, as seen by the local peer.
+///
+/// Both peers number the streams they open independently, so the number is
+/// qualified by the peer that opened the stream.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub struct StreamId {
+    pub num: u64,
+    /// Whether the stream was opened by the local peer
+    pub local: bool,
+}
+
+/// A decoded frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    /// Opens a stream
+    Open { id: StreamId },
+    /// Carries data of a stream
+    Data { id: StreamId, data: Bytes },
+    /// Half-closes a stream
+    Close { id: StreamId },
+    /// Resets a stream
+    Reset { id: StreamId },
+}
+
+impl Frame {
+    pub fn id(&self) -> StreamId {
+        match self {
+            Self::Open { id } | Self::Data { id, .. } | Self::Close { id } | Self::Reset { id } => {
+                *id
+            }
+        }
+    }
+
+    /// The flag on the wire, as sent by the local peer
+    fn flag(&self) -> u64 {
+        let (initiator, receiver) = match self {
+            Self::Open { .. } => return 0,
+            Self::Data { .. } => (2, 1),
+            Self::Close { .. } => (4, 3),
+            Self::Reset { .. } => (6, 5),
+        };
+        if self.id().local {
+            initiator
+        } else {
+            receiver
+        }
+    }
+}
+
+/// Append a frame to the `out` buffer
+pub fn encode_frame(frame: &Frame, out: &mut BytesMut) {
+    let body: &[u8] = match frame {
+        Frame::Data { data, .. } => data,
+        _ => &[],
+    };
+    let mut header = [0; 10];
+    let mut length = [0; 10];
+    let header = varint::encode::u64(frame.id().num << 3 | frame.flag(), &mut header);
+    let length = varint::encode::usize(body.len(), &mut length);
+    out.reserve(header.len() + length.len() + body.len());
+    out.put_slice(header);
+    out.put_slice(length);
+    out.put_slice(body);
+}
+
+/// Split the next complete frame from the front of the `buffer`.
+///
+/// Returns `None` if more bytes are needed. Stream names of new streams are discarded.
+///
+/// # Errors
+///
+/// Fails if the header is invalid, or the body exceeds [`MAX_MESSAGE_SIZE`][].
+pub fn decode_frame(buffer: &mut BytesMut) -> Result<Option<Frame>, PadawanError> {
+    let (header, rest) = match varint::decode::u64(buffer) {
+        Ok(decoded) => decoded,
+        Err(varint::decode::Error::Insufficient) => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let (length, body) = match varint::decode::usize(rest) {
+        Ok(decoded) => decoded,
+        Err(varint::decode::Error::Insufficient) => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    if length > MAX_MESSAGE_SIZE {
+        return Err(PadawanError::MplexProtocol(
+            "frame exceeds the maximum message size".to_string(),
+        ));
+    }
+    if body.len() < length {
+        buffer.reserve(length - body.len());
+        return Ok(None);
+    }
+    let start = buffer.len() - body.len();
+    let mut frame = buffer.split_to(start + length);
+    let data = frame.split_off(start).freeze();
+    let num = header >> 3;
+    // The flags of the receiver of a stream are odd
+    let id = |flag: u64| StreamId {
+        num,
+        local: flag % 2 == 1,
+    };
+    let frame = match header & 0b111 {
+        0 => Frame::Open { id: id(0) },
+        flag @ (1 | 2) => Frame::Data { id: id(flag), data },
+        flag @ (3 | 4) => Frame::Close { id: id(flag) },
+        flag @ (5 | 6) => Frame::Reset { id: id(flag) },
+        _ => return Err(PadawanError::MplexProtocol("unknown flag".to_string())),
+    };
+    Ok(Some(frame))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let local = StreamId {
+            num: 300,
+            local: true,
+        };
+        let remote = StreamId {
+            num: 300,
+            local: false,
+        };
+        let frames = [
+            Frame::Open { id: local },
+            Frame::Data {
+                id: local,
+                data: Bytes::from_static(b"hello"),
+            },
+            Frame::Close { id: remote },
+            Frame::Reset { id: local },
+        ];
+        let mut buffer = BytesMut::new();
+        for frame in &frames {
+            encode_frame(frame, &mut buffer);
+        }
+        // Frames sent by the local peer are received by the remote peer for the
+        // stream it did not open, and conversely
+        let mirrored = |id: StreamId| StreamId {
+            num: id.num,
+            local: !id.local,
+        };
+        let mut partial = buffer.split_to(4);
+        assert!(decode_frame(&mut partial).unwrap().is_some());
+        assert!(decode_frame(&mut partial).unwrap().is_none());
+        partial.unsplit(buffer);
+        let Some(Frame::Data { id, data }) = decode_frame(&mut partial).unwrap() else {
+            panic!("expected a data frame");
+        };
+        assert_eq!(id, mirrored(local));
+        assert_eq!(&data[..], b"hello");
+        assert_eq!(
+            decode_frame(&mut partial).unwrap(),
+            Some(Frame::Close { id: local })
+        );
+        assert_eq!(
+            decode_frame(&mut partial).unwrap(),
+            Some(Frame::Reset { id: remote })
+        );
+        assert!(partial.is_empty());
+    }
+
+    #[test]
+    fn reject_invalid_frames() {
+        let mut unknown = BytesMut::from(&[0b1111, 0][..]);
+        assert!(matches!(
+            decode_frame(&mut unknown),
+            Err(PadawanError::MplexProtocol(_))
+        ));
+        let mut oversized = BytesMut::new();
+        oversized.put_u8(0b1010);
+        let mut length = [0; 10];
+        oversized.put_slice(varint::encode::usize(MAX_MESSAGE_SIZE + 1, &mut length));
+        assert!(decode_frame(&mut oversized).is_err());
+    }
+}
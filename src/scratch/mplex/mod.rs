@@ -0,0 +1,317 @@
+//! Implementation of the [mplex][mplex-spec] stream multiplexer.
+//!
+//! Mplex is only spoken by older `libp2p` peers, and is negotiated when the
+//! remote peer does not support [`yamux`][`crate::scratch::yamux`]. Like the
+//! latter, a [`Session`][] multiplexes [`Substream`][]s as any other
+//! [`session`][`crate::scratch::session`] of a stream muxer, while [`Mplex`][]
+//! handles the frames of the protocol.
+//!
+//! [mplex-spec]: https://github.com/libp2p/specs/blob/master/mplex/README.md
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Waker};
+
+use bytes::BytesMut;
+
+use super::session::{self, Muxer, SessionState};
+use crate::error::PadawanError;
+
+use frame::{Frame, StreamId};
+use stream::StreamState;
+pub use stream::Substream;
+
+pub mod frame;
+pub mod stream;
+
+/// The maximum size of the data carried by a single frame, so that
+/// streams sending at the same time are interleaved
+pub const SPLIT_SEND_SIZE: usize = 8 * 1024;
+
+/// The maximum size of the unread data of a stream, beyond which it is reset
+pub const MAX_BUFFERED: usize = 1024 * 1024;
+
+/// The size of the data handed over to the session but not yet sent,
+/// beyond which writes are pending
+const MAX_BACKLOG: usize = 1024 * 1024;
+
+/// Requests to the session from its [`Control`][] and [`Substream`][]s
+pub(crate) type Command = session::Command<Mplex>;
+
+/// A handle for opening and accepting streams, and closing the session
+pub type Control = session::Control<Mplex>;
+
+/// An mplex session over the stream `S`
+pub type Session<S> = session::Session<S, Mplex>;
+
+/// The data written by the streams but not yet sent by the session
+#[derive(Debug, Default)]
+pub(crate) struct Backlog {
+    queued: usize,
+    writers: Vec<Waker>,
+}
+
+impl Backlog {
+    /// Account for `n` bytes written by a stream, unless the backlog is full,
+    /// in which case the writer is woken once it drains
+    fn reserve(&mut self, n: usize, cx: &mut Context<'_>) -> bool {
+        if self.queued >= MAX_BACKLOG {
+            self.writers.push(cx.waker().clone());
+            return false;
+        }
+        self.queued += n;
+        true
+    }
+
+    /// Account for `n` bytes sent by the session
+    fn release(&mut self, n: usize) {
+        self.queued = self.queued.saturating_sub(n);
+        if self.queued < MAX_BACKLOG {
+            self.writers.drain(..).for_each(Waker::wake);
+        }
+    }
+}
+
+impl<S> Session<S> {
+    /// Create a new session over the given stream
+    pub fn new(io: S) -> Self {
+        Self::with_muxer(io, Mplex::default())
+    }
+}
+
+/// The frame handling of an mplex session.
+///
+/// Closing the session through its [`Control`][] resets all the streams, while
+/// the session fails right away if the remote peer violates the protocol.
+#[derive(Debug, Default)]
+pub struct Mplex {
+    /// The number of the next stream opened by the local peer
+    next_num: u64,
+    backlog: Arc<Mutex<Backlog>>,
+}
+
+impl Muxer for Mplex {
+    type Id = StreamId;
+    type Frame = Frame;
+    type Stream = StreamState;
+    type Substream = Substream;
+    type Request = Infallible;
+
+    const NAME: &'static str = "mplex";
+
+    fn on_input(session: &mut SessionState<Self>) -> Result<(), PadawanError> {
+        while let Some(frame) = frame::decode_frame(&mut session.received)? {
+            session.on_frame(frame)?;
+        }
+        Ok(())
+    }
+
+    fn encode(frame: &Frame, out: &mut BytesMut) {
+        frame::encode_frame(frame, out);
+    }
+
+    fn reset(id: StreamId) -> Frame {
+        Frame::Reset { id }
+    }
+
+    fn open_stream(session: &mut SessionState<Self>) -> Result<Substream, PadawanError> {
+        if session.closing {
+            return Err(PadawanError::SessionClosed);
+        }
+        let id = StreamId {
+            num: session.muxer.next_num,
+            local: true,
+        };
+        session.muxer.next_num += 1;
+        let state = Arc::new(Mutex::new(StreamState::default()));
+        session.streams.insert(id, state.clone());
+        session.send(&Frame::Open { id });
+        Ok(session.substream(id, state))
+    }
+
+    fn on_request(_: &mut SessionState<Self>, request: Infallible) {
+        match request {}
+    }
+
+    fn on_close(session: &mut SessionState<Self>) {
+        let ids: Vec<_> = session.streams.keys().copied().collect();
+        for id in ids {
+            session.send(&Frame::Reset { id });
+        }
+        session.terminate();
+    }
+
+    fn on_sent(session: &mut SessionState<Self>, n: usize) {
+        session
+            .muxer
+            .backlog
+            .lock()
+            .expect("backlog lock")
+            .release(n);
+    }
+
+    fn on_terminate(session: &mut SessionState<Self>) {
+        session
+            .muxer
+            .backlog
+            .lock()
+            .expect("backlog lock")
+            .release(usize::MAX);
+    }
+}
+
+impl SessionState<Mplex> {
+    fn on_frame(&mut self, frame: Frame) -> Result<(), PadawanError> {
+        let id = frame.id();
+        if let Frame::Open { .. } = frame {
+            return self.on_open(id);
+        }
+        let state = match self.streams.get(&id) {
+            Some(state) => state.clone(),
+            None => {
+                tracing::trace!("Ignoring frame of closed stream {:?}", id);
+                return Ok(());
+            }
+        };
+        let mut state = state.lock().expect("stream state lock");
+        let reset = match frame {
+            Frame::Data { data, .. } => {
+                state.buffer.extend_from_slice(&data);
+                let overflow = state.buffer.len() > MAX_BUFFERED;
+                if overflow {
+                    tracing::debug!("Resetting stream {:?} with too much unread data", id);
+                    state.buffer.clear();
+                    self.send(&Frame::Reset { id });
+                }
+                overflow
+            }
+            Frame::Close { .. } => {
+                state.remote_closed = true;
+                false
+            }
+            Frame::Reset { .. } => true,
+            Frame::Open { .. } => unreachable!("handled above"),
+        };
+        if reset {
+            state.reset = true;
+        }
+        state.wake();
+        drop(state);
+        if reset {
+            self.streams.remove(&id);
+        }
+        Ok(())
+    }
+
+    /// Accept a stream opened by the remote peer
+    fn on_open(&mut self, id: StreamId) -> Result<(), PadawanError> {
+        if self.streams.contains_key(&id) {
+            return Err(PadawanError::MplexProtocol(
+                "stream number already in use".to_string(),
+            ));
+        }
+        if self.closing {
+            self.send(&Frame::Reset { id });
+            return Ok(());
+        }
+        let state = Arc::new(Mutex::new(StreamState::default()));
+        let substream = self.substream(id, state.clone());
+        self.streams.insert(id, state);
+        self.accept(substream);
+        Ok(())
+    }
+
+    fn substream(&self, id: StreamId, state: Arc<Mutex<StreamState>>) -> Substream {
+        Substream::new(id, state, self.muxer.backlog.clone(), self.commands())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch::session::spawn_pair;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn session_pair() -> (Control, Control) {
+        let (dialer, listener) = tokio::io::duplex(4096);
+        spawn_pair(Session::new(dialer), Session::new(listener))
+    }
+
+    #[tokio::test]
+    async fn open_and_accept() {
+        let (dialer, listener) = session_pair();
+        let mut outbound = dialer.open_stream().await.unwrap();
+        let mut inbound = listener.accept().await.unwrap();
+        assert_eq!(outbound.id().num, inbound.id().num);
+        assert!(outbound.id().local);
+        assert!(!inbound.id().local);
+
+        outbound.write_all(b"ping").await.unwrap();
+        let mut received = [0; 4];
+        inbound.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"ping");
+        inbound.write_all(b"pong").await.unwrap();
+        inbound.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        outbound.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"pong");
+
+        // Both peers number their streams independently
+        let outbound = listener.open_stream().await.unwrap();
+        assert_eq!(outbound.id().num, 0);
+        assert_eq!(dialer.accept().await.unwrap().id(), inbound.id());
+    }
+
+    #[tokio::test]
+    async fn transfer_concurrently() {
+        let (dialer, listener) = session_pair();
+        let sent: Vec<u8> = (0..4 * MAX_BUFFERED).map(|i| i as u8).collect();
+        let mut transfers = Vec::new();
+        for _ in 0..3 {
+            let mut outbound = dialer.open_stream().await.unwrap();
+            let (sent, expected) = (sent.clone(), sent.clone());
+            transfers.push(tokio::spawn(async move {
+                outbound.write_all(&sent).await.unwrap();
+                outbound.shutdown().await.unwrap();
+            }));
+            let mut inbound = listener.accept().await.unwrap();
+            transfers.push(tokio::spawn(async move {
+                let mut received = Vec::new();
+                inbound.read_to_end(&mut received).await.unwrap();
+                assert!(received == expected);
+            }));
+        }
+        for transfer in transfers {
+            transfer.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn reset_unread_overflow() {
+        let (dialer, listener) = session_pair();
+        let mut outbound = dialer.open_stream().await.unwrap();
+        let mut inbound = listener.accept().await.unwrap();
+        // Beyond what the backlog of the dialer and the buffer of the listener can hold
+        let sent = vec![42; 4 * MAX_BUFFERED];
+        let err = outbound.write_all(&sent).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+        let mut received = Vec::new();
+        let err = inbound.read_to_end(&mut received).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+    }
+
+    #[tokio::test]
+    async fn reset_on_close() {
+        let (dialer, listener) = session_pair();
+        let mut outbound = dialer.open_stream().await.unwrap();
+        let mut inbound = listener.accept().await.unwrap();
+        dialer.close().unwrap();
+        assert!(matches!(
+            dialer.open_stream().await,
+            Err(PadawanError::SessionClosed)
+        ));
+        assert!(outbound.write_all(b"ping").await.is_err());
+        let mut received = Vec::new();
+        assert!(inbound.read_to_end(&mut received).await.is_err());
+        assert!(listener.accept().await.is_none());
+    }
+}
@@ -0,0 +1,158 @@
+//! The substreams multiplexed over an mplex session.
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use bytes::{Buf, Bytes, BytesMut};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc;
+
+use super::frame::{Frame, StreamId};
+use super::{Backlog, Command, SPLIT_SEND_SIZE};
+use crate::scratch::session;
+
+/// The state of a stream shared between the session and the [`Substream`][]
+#[derive(Debug, Default)]
+pub struct StreamState {
+    /// Data received but not yet read
+    pub(super) buffer: BytesMut,
+    /// Whether the local peer sent its last data
+    pub(super) local_closed: bool,
+    /// Whether the remote peer sent its last data
+    pub(super) remote_closed: bool,
+    /// Whether the stream was reset, or the session terminated
+    pub(super) reset: bool,
+    read_waker: Option<Waker>,
+}
+
+impl session::Stream for StreamState {
+    fn reset(&mut self) {
+        self.reset = true;
+        self.wake();
+    }
+}
+
+impl StreamState {
+    /// Wake the pending reader of the stream
+    pub(super) fn wake(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// A bidirectional stream multiplexed over an mplex session.
+///
+/// Mplex has no flow control, so writes are only pending while the session has
+/// a backlog of frames to send, and a stream whose unread data exceed the limit
+/// of the session is reset. Shutting down the stream half-closes it, while
+/// dropping it resets it unless both directions are already closed.
+pub struct Substream {
+    id: StreamId,
+    state: Arc<Mutex<StreamState>>,
+    backlog: Arc<Mutex<Backlog>>,
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+impl Substream {
+    pub(super) fn new(
+        id: StreamId,
+        state: Arc<Mutex<StreamState>>,
+        backlog: Arc<Mutex<Backlog>>,
+        commands: mpsc::UnboundedSender<Command>,
+    ) -> Self {
+        Self {
+            id,
+            state,
+            backlog,
+            commands,
+        }
+    }
+
+    /// The identifier of the stream within the session
+    pub fn id(&self) -> StreamId {
+        self.id
+    }
+
+    fn send(&self, frame: Frame) -> io::Result<()> {
+        self.commands
+            .send(Command::Frame(frame))
+            .map_err(|_| io::ErrorKind::BrokenPipe.into())
+    }
+}
+
+impl std::fmt::Debug for Substream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Substream").field("id", &self.id).finish()
+    }
+}
+
+impl AsyncRead for Substream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut state = self.state.lock().expect("stream state lock");
+        if state.buffer.is_empty() {
+            if state.remote_closed {
+                return Poll::Ready(Ok(()));
+            }
+            if state.reset {
+                return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+            }
+            state.read_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = state.buffer.len().min(buf.remaining());
+        buf.put_slice(&state.buffer[..n]);
+        state.buffer.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for Substream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let state = self.state.lock().expect("stream state lock");
+        if state.reset {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
+        if state.local_closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let n = buf.len().min(SPLIT_SEND_SIZE);
+        if !self.backlog.lock().expect("backlog lock").reserve(n, cx) {
+            return Poll::Pending;
+        }
+        let data = Bytes::copy_from_slice(&buf[..n]);
+        self.send(Frame::Data { id: self.id, data })?;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Frames are handed over to the session right away
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut state = self.state.lock().expect("stream state lock");
+        if !state.local_closed && !state.reset {
+            state.local_closed = true;
+            self.send(Frame::Close { id: self.id })?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for Substream {
+    fn drop(&mut self) {
+        let state = self.state.lock().expect("stream state lock");
+        let reset = !(state.reset || state.local_closed && state.remote_closed);
+        let _ = self.commands.send(Command::Drop { id: self.id, reset });
+    }
+}
@@ -0,0 +1,511 @@
+//! Substreams negotiated for a protocol over the stream muxer of an established connection.
+//!
+//! Peers open a substream per protocol, e.g. block announces, sync, kademlia or
+//! identify, and negotiate it with its own `multistream_select` exchange. Outbound
+//! substreams are opened through the [`Control`][] of a [`Multiplexed`][] connection,
+//! while inbound substreams are dispatched to the handler registered for the
+//! protocol selected by the remote peer.
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use bytes::{Buf, BytesMut};
+use futures::future::BoxFuture;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::Semaphore;
+
+use crate::error::PadawanError;
+
+use super::multistream_select::{framed::FramedRead, mirror, ProtocolName};
+use super::upgrade::Role;
+use super::{mplex, yamux};
+
+/// The time allowed to the remote peer for negotiating the protocol of a substream
+pub const NEGOTIATION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The maximum number of inbound substreams negotiated or handled at once
+pub const MAX_DISPATCHES: usize = 128;
+
+/// Handle the inbound substreams of a protocol
+pub type Handler = Box<dyn Fn(Substream) -> BoxFuture<'static, ()> + Send + Sync>;
+
+/// The session of either stream muxer
+enum Session<S> {
+    Yamux(yamux::Session<S>),
+    Mplex(mplex::Session<S>),
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Session<S> {
+    async fn run(self) -> Result<(), PadawanError> {
+        match self {
+            Self::Yamux(session) => session.run().await,
+            Self::Mplex(session) => session.run().await,
+        }
+    }
+}
+
+/// The control of either stream muxer
+#[derive(Debug, Clone)]
+enum MuxerControl {
+    Yamux(yamux::Control),
+    Mplex(mplex::Control),
+}
+
+impl MuxerControl {
+    async fn open_stream(&self) -> Result<Muxed, PadawanError> {
+        match self {
+            Self::Yamux(control) => control.open_stream().await.map(Muxed::Yamux),
+            Self::Mplex(control) => control.open_stream().await.map(Muxed::Mplex),
+        }
+    }
+
+    async fn accept(&self) -> Option<Muxed> {
+        match self {
+            Self::Yamux(control) => control.accept().await.map(Muxed::Yamux),
+            Self::Mplex(control) => control.accept().await.map(Muxed::Mplex),
+        }
+    }
+
+    fn close(&self) -> Result<(), PadawanError> {
+        match self {
+            Self::Yamux(control) => control.close(),
+            Self::Mplex(control) => control.close(),
+        }
+    }
+}
+
+/// A substream of either stream muxer, before its protocol is negotiated
+#[derive(Debug)]
+enum Muxed {
+    Yamux(yamux::Substream),
+    Mplex(mplex::Substream),
+}
+
+impl AsyncRead for Muxed {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Yamux(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Mplex(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Muxed {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Yamux(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Mplex(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Yamux(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Mplex(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Yamux(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Mplex(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// A substream negotiated for a protocol.
+///
+/// Any data the remote peer sent right after the negotiation are yielded first.
+#[derive(Debug)]
+pub struct Substream {
+    inner: Muxed,
+    /// Bytes received beyond the negotiation
+    buffered: BytesMut,
+    protocol: ProtocolName,
+}
+
+impl Substream {
+    /// The protocol agreed for the substream
+    pub fn protocol(&self) -> &ProtocolName {
+        &self.protocol
+    }
+}
+
+impl AsyncRead for Substream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.buffered.is_empty() {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        let n = this.buffered.len().min(buf.remaining());
+        buf.put_slice(&this.buffered[..n]);
+        this.buffered.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for Substream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// A handle for opening substreams, and closing the connection
+#[derive(Debug, Clone)]
+pub struct Control {
+    muxer: MuxerControl,
+    negotiation_timeout: Duration,
+}
+
+impl Control {
+    /// Open a substream for the first of the given `protocols`, in order of
+    /// preference, that the remote peer supports.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`PadawanError::NegotiationFailed`][] if the remote peer
+    /// rejects all the protocols, with [`PadawanError::NegotiationTimeout`][]
+    /// if it does not negotiate in time, or if the session is closed.
+    pub async fn open_stream(
+        &self,
+        protocols: &[ProtocolName],
+    ) -> Result<(Substream, ProtocolName), PadawanError> {
+        let negotiated = negotiate(self.muxer.open_stream().await?, Role::Dialer, protocols);
+        let stream = tokio::time::timeout(self.negotiation_timeout, negotiated)
+            .await
+            .map_err(|_| PadawanError::NegotiationTimeout(self.negotiation_timeout))??;
+        let protocol = stream.protocol.clone();
+        Ok((stream, protocol))
+    }
+
+    /// Close the session of the stream muxer
+    pub fn close(&self) -> Result<(), PadawanError> {
+        self.muxer.close()
+    }
+}
+
+/// An established connection over the stream muxer session `S`, along with
+/// the handlers of the protocols it accepts on inbound substreams
+pub struct Multiplexed<S> {
+    session: Session<S>,
+    control: MuxerControl,
+    handlers: Vec<(ProtocolName, Handler)>,
+    negotiation_timeout: Duration,
+    max_dispatches: usize,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> From<yamux::Session<S>> for Multiplexed<S> {
+    fn from(session: yamux::Session<S>) -> Self {
+        Self {
+            control: MuxerControl::Yamux(session.control()),
+            session: Session::Yamux(session),
+            handlers: Vec::new(),
+            negotiation_timeout: NEGOTIATION_TIMEOUT,
+            max_dispatches: MAX_DISPATCHES,
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> From<mplex::Session<S>> for Multiplexed<S> {
+    fn from(session: mplex::Session<S>) -> Self {
+        Self {
+            control: MuxerControl::Mplex(session.control()),
+            session: Session::Mplex(session),
+            handlers: Vec::new(),
+            negotiation_timeout: NEGOTIATION_TIMEOUT,
+            max_dispatches: MAX_DISPATCHES,
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Multiplexed<S> {
+    /// Accept inbound substreams for the given `protocol`, and dispatch them to the `handler`
+    pub fn with_handler<F, Fut>(mut self, protocol: ProtocolName, handler: F) -> Self
+    where
+        F: Fn(Substream) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let handler: Handler = Box::new(move |stream| Box::pin(handler(stream)));
+        self.handlers.push((protocol, handler));
+        self
+    }
+
+    /// Drop the inbound substreams, and fail opening the outbound substreams,
+    /// whose protocol is not negotiated within the given `timeout`, instead of
+    /// [`NEGOTIATION_TIMEOUT`][].
+    ///
+    /// Applies to the handles of the connection obtained afterwards.
+    pub fn with_negotiation_timeout(mut self, timeout: Duration) -> Self {
+        self.negotiation_timeout = timeout;
+        self
+    }
+
+    /// Negotiate and handle at most `max` inbound substreams at once,
+    /// instead of [`MAX_DISPATCHES`][]
+    pub fn with_max_dispatches(mut self, max: usize) -> Self {
+        self.max_dispatches = max;
+        self
+    }
+
+    /// Get a handle to the connection
+    pub fn control(&self) -> Control {
+        Control {
+            muxer: self.control.clone(),
+            negotiation_timeout: self.negotiation_timeout,
+        }
+    }
+
+    /// Drive the session of the stream muxer until it terminates, dispatching
+    /// every inbound substream to the handler of the protocol selected by the
+    /// remote peer.
+    ///
+    /// Substreams are negotiated and handled concurrently, on tasks of their own.
+    /// Those for unsupported protocols, not negotiated in time, or beyond the
+    /// [maximum][`Self::with_max_dispatches`] dispatched at once, are dropped.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the session of the stream muxer fails.
+    pub async fn run(self) -> Result<(), PadawanError> {
+        let Self {
+            session,
+            control,
+            handlers,
+            negotiation_timeout,
+            max_dispatches,
+        } = self;
+        let handlers = Arc::new(handlers);
+        let dispatches = Arc::new(Semaphore::new(max_dispatches));
+        let accept = async {
+            while let Some(stream) = control.accept().await {
+                let Ok(permit) = dispatches.clone().try_acquire_owned() else {
+                    tracing::debug!(
+                        "Dropping inbound substream beyond {} dispatched at once",
+                        max_dispatches
+                    );
+                    continue;
+                };
+                let handlers = handlers.clone();
+                tokio::spawn(async move {
+                    dispatch(stream, handlers, negotiation_timeout).await;
+                    drop(permit);
+                });
+            }
+        };
+        tokio::join!(session.run(), accept).0
+    }
+}
+
+/// Negotiate the protocol of an inbound substream within the `timeout`, and
+/// pass it to its handler
+async fn dispatch(stream: Muxed, handlers: Arc<Vec<(ProtocolName, Handler)>>, timeout: Duration) {
+    let supported: Vec<_> = handlers
+        .iter()
+        .map(|(protocol, _)| protocol.clone())
+        .collect();
+    let negotiated = negotiate(stream, Role::Listener, &supported);
+    let stream = match tokio::time::timeout(timeout, negotiated).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(err)) => {
+            tracing::debug!("Dropping inbound substream: {}", err);
+            return;
+        }
+        Err(_) => {
+            tracing::debug!(
+                "Dropping inbound substream not negotiated within {:?}",
+                timeout
+            );
+            return;
+        }
+    };
+    tracing::debug!("Inbound substream for {}", stream.protocol);
+    if let Some((_, handler)) = handlers
+        .iter()
+        .find(|(protocol, _)| *protocol == stream.protocol)
+    {
+        handler(stream).await;
+    }
+}
+
+/// Run the `multistream_select` exchange over a new substream, proposing the
+/// `protocols` as a dialer, or accepting them as a listener
+async fn negotiate(
+    stream: Muxed,
+    role: Role,
+    protocols: &[ProtocolName],
+) -> Result<Substream, PadawanError> {
+    let (read, mut write) = tokio::io::split(stream);
+    let mut read = FramedRead::new(read);
+    if !mirror::concurrent(&mut read, &mut write, &ProtocolName::MULTISTREAM).await? {
+        return Err(PadawanError::UnexpectedMultistream);
+    }
+    let protocol = match role {
+        Role::Dialer => mirror::select(&mut read, &mut write, protocols).await?,
+        Role::Listener => mirror::handle(&mut read, &mut write, protocols).await?,
+    };
+    let (read, buffered) = read.into_parts();
+    Ok(Substream {
+        inner: read.unsplit(write),
+        buffered,
+        protocol,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch::upgrade::Muxer;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    fn pair(muxer: Muxer) -> (Multiplexed<DuplexStream>, Multiplexed<DuplexStream>) {
+        let (dialer, listener) = tokio::io::duplex(4096);
+        match muxer {
+            Muxer::Yamux => (
+                yamux::Session::new(dialer, yamux::Mode::Client).into(),
+                yamux::Session::new(listener, yamux::Mode::Server).into(),
+            ),
+            Muxer::Mplex => (
+                mplex::Session::new(dialer).into(),
+                mplex::Session::new(listener).into(),
+            ),
+        }
+    }
+
+    async fn echo(stream: Substream) {
+        let (mut read, mut write) = tokio::io::split(stream);
+        tokio::io::copy(&mut read, &mut write).await.unwrap();
+        write.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn open_and_dispatch() {
+        let echo_protocol = ProtocolName::new("/echo/1.0.0").unwrap();
+        let unknown = ProtocolName::new("/unknown/1.0.0").unwrap();
+        for muxer in [Muxer::Yamux, Muxer::Mplex] {
+            let (dialer, listener) = pair(muxer);
+            let listener = listener
+                .with_handler(ProtocolName::new("/ipfs/id/1.0.0").unwrap(), |_| async {})
+                .with_handler(echo_protocol.clone(), echo);
+            let control = dialer.control();
+            tokio::spawn(dialer.run());
+            tokio::spawn(listener.run());
+
+            let proposed = [unknown.clone(), echo_protocol.clone()];
+            let (mut stream, protocol) = control.open_stream(&proposed).await.unwrap();
+            assert_eq!(protocol, echo_protocol);
+            stream.write_all(b"hello").await.unwrap();
+            stream.shutdown().await.unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received, b"hello");
+
+            assert!(matches!(
+                control.open_stream(std::slice::from_ref(&unknown)).await,
+                Err(PadawanError::NegotiationFailed)
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn drop_stalled_negotiation() {
+        for muxer in [Muxer::Yamux, Muxer::Mplex] {
+            let (dialer, listener) = pair(muxer);
+            let listener = listener
+                .with_handler(ProtocolName::new("/echo/1.0.0").unwrap(), echo)
+                .with_negotiation_timeout(Duration::from_millis(50));
+            let control = dialer.control.clone();
+            tokio::spawn(dialer.run());
+            tokio::spawn(listener.run());
+
+            // The substream is opened, but its protocol is never proposed
+            let mut stream = control.open_stream().await.unwrap();
+            let mut received = Vec::new();
+            let read =
+                tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut received));
+            assert!(read.await.unwrap().is_err());
+            assert_eq!(received, ProtocolName::MULTISTREAM.encode());
+        }
+    }
+
+    #[tokio::test]
+    async fn time_out_outbound_negotiation() {
+        let echo_protocol = ProtocolName::new("/echo/1.0.0").unwrap();
+        for muxer in [Muxer::Yamux, Muxer::Mplex] {
+            let (dialer, listener) = pair(muxer);
+            let timeout = Duration::from_millis(50);
+            let dialer = dialer.with_negotiation_timeout(timeout);
+            let control = dialer.control();
+            tokio::spawn(dialer.run());
+            // The substreams are accepted, but their protocol is never negotiated
+            let _accepted = listener.control;
+            tokio::spawn(listener.session.run());
+
+            assert!(matches!(
+                control.open_stream(std::slice::from_ref(&echo_protocol)).await,
+                Err(PadawanError::NegotiationTimeout(elapsed)) if elapsed == timeout
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn bound_concurrent_dispatches() {
+        let echo_protocol = ProtocolName::new("/echo/1.0.0").unwrap();
+        let proposed = std::slice::from_ref(&echo_protocol);
+        for muxer in [Muxer::Yamux, Muxer::Mplex] {
+            let (dialer, listener) = pair(muxer);
+            let listener = listener
+                .with_handler(echo_protocol.clone(), echo)
+                .with_max_dispatches(1);
+            let control = dialer.control();
+            tokio::spawn(dialer.run());
+            tokio::spawn(listener.run());
+
+            // The substream beyond the one being handled is dropped
+            let (mut handled, _) = control.open_stream(proposed).await.unwrap();
+            assert!(control.open_stream(proposed).await.is_err());
+
+            handled.shutdown().await.unwrap();
+            let mut received = Vec::new();
+            handled.read_to_end(&mut received).await.unwrap();
+            // The dispatch completes shortly after the handler shuts the substream down
+            let reopen = async {
+                loop {
+                    match control.open_stream(proposed).await {
+                        Ok((stream, _)) => return stream,
+                        Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+                    }
+                }
+            };
+            assert!(tokio::time::timeout(Duration::from_secs(5), reopen)
+                .await
+                .is_ok());
+        }
+    }
+}
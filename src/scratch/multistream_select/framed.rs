@@ -0,0 +1,243 @@
+//! Buffered decoding of the length-prefixed messages
+//! of the `multistream_select` protocol.
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::{Buf, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+use unsigned_varint as varint;
+
+use crate::error::PadawanError;
+
+/// The maximum size of a message excluding its length prefix.
+///
+/// Matches the limit of a two-byte `varint` prefix used by `libp2p`.
+pub const MAX_MESSAGE_SIZE: usize = (1 << 14) - 1;
+
+/// The number of bytes requested from the underlying stream on each read
+const READ_CHUNK_SIZE: usize = 1024;
+
+/// Accumulate bytes and split them into length-prefixed messages.
+#[derive(Debug, Clone)]
+pub struct Decoder {
+    buffer: BytesMut,
+    max_size: usize,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self {
+            buffer: BytesMut::new(),
+            max_size: MAX_MESSAGE_SIZE,
+        }
+    }
+}
+
+impl Decoder {
+    /// Set the maximum accepted message size
+    pub fn with_max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Append received bytes to the internal buffer
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// The bytes received but not yet consumed
+    pub fn remaining(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    /// Take the bytes received but not yet consumed
+    pub fn take_remaining(&mut self) -> BytesMut {
+        self.buffer.split()
+    }
+
+    /// Split the next complete message from the internal buffer.
+    ///
+    /// Returns the message including its length prefix, or `None` if
+    /// more bytes are needed.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the length prefix is invalid or exceeds the maximum message size.
+    pub fn next_message(&mut self) -> Result<Option<Vec<u8>>, PadawanError> {
+        decode_message(&mut self.buffer, self.max_size)
+    }
+}
+
+/// Split the next complete message from the front of the `buffer`.
+///
+/// Returns the message including its length prefix, or `None` if
+/// more bytes are needed.
+///
+/// # Errors
+///
+/// Fails if the length prefix is invalid or exceeds `max_size`.
+pub fn decode_message(
+    buffer: &mut BytesMut,
+    max_size: usize,
+) -> Result<Option<Vec<u8>>, PadawanError> {
+    let (len, body) = match varint::decode::usize(buffer) {
+        Ok(decoded) => decoded,
+        Err(varint::decode::Error::Insufficient) => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    if len > max_size {
+        return Err(PadawanError::MultistreamMessageSizeExceeded);
+    }
+    if body.len() < len {
+        return Ok(None);
+    }
+    let end = buffer.len() - body.len() + len;
+    Ok(Some(buffer.split_to(end).to_vec()))
+}
+
+/// Read length-prefixed messages from an underlying stream.
+///
+/// Bytes read beyond the last requested message are retained, and are
+/// yielded first when reading from this type as an [`AsyncRead`][].
+pub struct FramedRead<R> {
+    inner: R,
+    decoder: Decoder,
+}
+
+impl<R> FramedRead<R> {
+    /// Wrap the given stream
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            decoder: Default::default(),
+        }
+    }
+
+    /// Set the maximum accepted message size
+    pub fn with_max_size(mut self, max_size: usize) -> Self {
+        self.decoder = self.decoder.with_max_size(max_size);
+        self
+    }
+
+    /// Prepend bytes already read from the underlying stream
+    pub fn with_buffered(mut self, buffered: &[u8]) -> Self {
+        self.decoder.extend(buffered);
+        self
+    }
+
+    /// The bytes received but not yet consumed
+    pub fn buffered(&self) -> &[u8] {
+        self.decoder.remaining()
+    }
+
+    /// Get a mutable reference to the underlying stream
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Get the underlying stream along with any bytes not yet consumed
+    pub fn into_parts(mut self) -> (R, BytesMut) {
+        let remaining = self.decoder.take_remaining();
+        (self.inner, remaining)
+    }
+}
+
+impl<R: AsyncRead + Unpin> FramedRead<R> {
+    /// Read the next message from the stream.
+    ///
+    /// Returns the message including its length prefix.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the stream is closed before a complete message
+    /// is received, or in case of invalid encoding.
+    pub async fn next_message(&mut self) -> Result<Vec<u8>, PadawanError> {
+        loop {
+            if let Some(message) = self.decoder.next_message()? {
+                tracing::trace!("multistream read {:?}", message.as_slice());
+                return Ok(message);
+            }
+            let mut chunk = [0_u8; READ_CHUNK_SIZE];
+            let n = self.inner.read(&mut chunk).await?;
+            if n == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            self.decoder.extend(&chunk[..n]);
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for FramedRead<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let buffered = &mut this.decoder.buffer;
+        if buffered.is_empty() {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        let n = buffered.len().min(buf.remaining());
+        buf.put_slice(&buffered[..n]);
+        buffered.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch::multistream_select::{Message, ProtocolName};
+    use tokio::io::AsyncWriteExt;
+
+    #[test]
+    fn decode_partial_and_coalesced() {
+        let mut decoder = Decoder::default();
+        let mut bytes = ProtocolName::MULTISTREAM.encode();
+        bytes.extend_from_slice(&Message::NotAvailable.encode());
+        bytes.extend_from_slice(b"leftover");
+
+        decoder.extend(&bytes[..1]);
+        assert!(decoder.next_message().unwrap().is_none());
+        decoder.extend(&bytes[1..]);
+        assert_eq!(
+            decoder.next_message().unwrap().unwrap(),
+            ProtocolName::MULTISTREAM.encode()
+        );
+        assert_eq!(
+            decoder.next_message().unwrap().unwrap(),
+            Message::NotAvailable.encode()
+        );
+        assert_eq!(decoder.remaining(), b"leftover");
+    }
+
+    #[test]
+    fn decode_oversized() {
+        let mut decoder = Decoder::default().with_max_size(4);
+        decoder.extend(&ProtocolName::NOISE.encode());
+        assert!(matches!(
+            decoder.next_message(),
+            Err(PadawanError::MultistreamMessageSizeExceeded)
+        ));
+    }
+
+    #[tokio::test]
+    async fn read_leftover() {
+        let (mut remote, local) = tokio::io::duplex(64);
+        let mut sent = ProtocolName::NOISE.encode();
+        sent.extend_from_slice(b"noise frame");
+        remote.write_all(&sent).await.unwrap();
+        drop(remote);
+
+        let mut framed = FramedRead::new(local);
+        assert_eq!(
+            framed.next_message().await.unwrap(),
+            ProtocolName::NOISE.encode()
+        );
+        let mut leftover = Vec::new();
+        framed.read_to_end(&mut leftover).await.unwrap();
+        assert_eq!(leftover, b"noise frame");
+    }
+}
@@ -1,10 +1,10 @@
 //! Handle communications where the listener is expected
 //! to the send the same data as the ones received from the
 //! dialer.
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::tcp::{ReadHalf, WriteHalf};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 
-use super::Protocol;
+use super::framed::FramedRead;
+use super::{Message, ProtocolName};
 use crate::error::PadawanError;
 use crate::scratch::noise;
 
@@ -14,22 +14,19 @@ use crate::scratch::noise;
 /// For example, when sending the `/multistream/1.0.0` headers.
 ///
 /// Returns `true` if the protocols match.
-pub async fn concurrent<'a>(
-    read: &mut ReadHalf<'a>,
-    write: &mut WriteHalf<'a>,
-    protocol: Protocol,
+pub async fn concurrent<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    read: &mut FramedRead<R>,
+    write: &mut W,
+    protocol: &ProtocolName,
 ) -> Result<bool, PadawanError> {
-    let (mut send, mut recv) = (false, false);
+    let (mut send, mut response) = (false, None);
     let encoded = protocol.encode();
-    let mut response = vec![0_u8; encoded.len()];
     loop {
         tokio::select!(
-            res = read.read_exact(&mut response), if !recv  => {
-                let n_read = res?;
-                tracing::trace!("multistream read {:?}", response.as_slice());
-                recv = n_read > 0;
+            res = read.next_message(), if response.is_none() => {
+                response = Some(res?);
             },
-            res = write.write(&encoded), if !send => {
+            res = write.write_all(&encoded), if !send => {
                 res?;
                 tracing::trace!("multistream wrote {:?} ", &encoded[..]);
                 send = true;
@@ -37,26 +34,20 @@ pub async fn concurrent<'a>(
             else => break
         );
     }
-    Ok(response == encoded.as_slice())
+    Ok(response.as_deref() == Some(encoded.as_slice()))
 }
 
 /// First write to the stream and then get the peer response.
 ///
 /// Returns `true` if the messages match.
-pub async fn dial<'a>(
-    read: &mut ReadHalf<'a>,
-    write: &mut WriteHalf<'a>,
-    protocol: Protocol,
+pub async fn dial<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    read: &mut FramedRead<R>,
+    write: &mut W,
+    protocol: &ProtocolName,
 ) -> Result<bool, PadawanError> {
     let encoded = protocol.encode();
-    let mut response = vec![0_u8; encoded.len()];
     write.write_all(&encoded).await?;
-    loop {
-        if read.read_exact(&mut response).await? > 0 {
-            break;
-        }
-    }
-    Ok(encoded.as_slice() == response)
+    Ok(read.next_message().await? == encoded)
 }
 
 /// First read from the stream and then send the matching response
@@ -65,69 +56,391 @@ pub async fn dial<'a>(
 /// # Errors
 ///
 /// Fails if the incoming payload has invalid encoding.
-pub async fn listen<'a>(
-    read: &mut ReadHalf<'a>,
-    write: &mut WriteHalf<'a>,
-    protocol: Protocol,
+pub async fn listen<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    read: &mut FramedRead<R>,
+    write: &mut W,
+    protocol: &ProtocolName,
 ) -> Result<(), PadawanError> {
-    let encoded = protocol.encode();
-    let mut incoming = vec![0_u8; encoded.len()];
+    match Message::decode(&read.next_message().await?)? {
+        Message::Protocol(ref incoming) if incoming == protocol => {
+            Ok(write.write_all(&incoming.encode()).await?)
+        }
+        _ => Err(PadawanError::UnexpectedMultistream),
+    }
+}
+
+/// Propose the given `protocols` to the remote peer in order of preference,
+/// moving to the next candidate whenever the remote answers with `na`.
+///
+/// Returns the protocol agreed with the remote peer.
+///
+/// # Errors
+///
+/// Fails if the remote peer rejects all the protocols, or if it
+/// responds with anything other than the proposed protocol or `na`.
+pub async fn select<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    read: &mut FramedRead<R>,
+    write: &mut W,
+    protocols: &[ProtocolName],
+) -> Result<ProtocolName, PadawanError> {
+    for protocol in protocols {
+        write.write_all(&protocol.encode()).await?;
+        match Message::decode(&read.next_message().await?)? {
+            Message::Protocol(ref response) if response == protocol => return Ok(protocol.clone()),
+            Message::NotAvailable => tracing::debug!("Remote peer does not support {}", protocol),
+            _ => return Err(PadawanError::UnexpectedMultistream),
+        }
+    }
+    Err(PadawanError::NegotiationFailed)
+}
+
+/// Send the headers along with a single `protocol` proposal without
+/// awaiting the confirmation of the remote peer.
+///
+/// The confirmation must be validated with [`confirm`][] before
+/// reading any further data from the remote peer.
+pub async fn dial_lazy<W: AsyncWrite + Unpin>(
+    write: &mut W,
+    protocol: &ProtocolName,
+) -> Result<(), PadawanError> {
+    let mut proposal = ProtocolName::MULTISTREAM.encode();
+    proposal.extend_from_slice(&protocol.encode());
+    write.write_all(&proposal).await?;
+    tracing::trace!("multistream wrote {:?} ", proposal.as_slice());
+    Ok(())
+}
+
+/// Validate the deferred confirmation of a proposal made with [`dial_lazy`][].
+///
+/// # Errors
+///
+/// Fails with [`PadawanError::NegotiationFailed`][] if the remote peer
+/// rejects the protocol.
+pub async fn confirm<R: AsyncRead + Unpin>(
+    read: &mut FramedRead<R>,
+    protocol: &ProtocolName,
+) -> Result<(), PadawanError> {
+    if Message::decode(&read.next_message().await?)? != Message::Protocol(ProtocolName::MULTISTREAM)
+    {
+        return Err(PadawanError::UnexpectedMultistream);
+    }
+    match Message::decode(&read.next_message().await?)? {
+        Message::Protocol(ref response) if response == protocol => Ok(()),
+        Message::NotAvailable => Err(PadawanError::NegotiationFailed),
+        _ => Err(PadawanError::UnexpectedMultistream),
+    }
+}
+
+/// Ask the remote peer for the protocols it supports.
+///
+/// # Errors
+///
+/// Fails if the remote peer responds with anything other
+/// than a list of protocols.
+pub async fn list<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    read: &mut FramedRead<R>,
+    write: &mut W,
+) -> Result<Vec<ProtocolName>, PadawanError> {
+    write.write_all(&Message::ListProtocols.encode()).await?;
+    match Message::decode(&read.next_message().await?)? {
+        Message::Protocols(protocols) => Ok(protocols),
+        _ => Err(PadawanError::UnexpectedMultistream),
+    }
+}
+
+/// Answer the protocol proposals of the remote peer until one of
+/// the `supported` protocols is proposed.
+///
+/// Unsupported or invalid proposals are answered with `na`, and `ls`
+/// requests with the `supported` protocols.
+///
+/// Returns the protocol agreed with the remote peer.
+pub async fn handle<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    read: &mut FramedRead<R>,
+    write: &mut W,
+    supported: &[ProtocolName],
+) -> Result<ProtocolName, PadawanError> {
     loop {
-        if read.read_exact(&mut incoming).await? > 0 {
-            break;
+        let response = match Message::decode_proposal(&read.next_message().await?)? {
+            Message::Protocol(incoming) if supported.contains(&incoming) => {
+                write.write_all(&incoming.encode()).await?;
+                return Ok(incoming);
+            }
+            Message::ListProtocols => Message::Protocols(supported.to_vec()),
+            incoming => {
+                tracing::debug!("Rejecting unsupported proposal {:?}", incoming);
+                Message::NotAvailable
+            }
+        };
+        write.write_all(&response.encode()).await?;
+    }
+}
+
+/// Send a single message as a noise transport frame.
+async fn send_noise<W: AsyncWrite + Unpin>(
+    write: &mut W,
+    message: &[u8],
+    transport: &mut noise::Transport,
+) -> Result<(), PadawanError> {
+    write.write_all(transport.encrypt_frame(message)?).await?;
+    Ok(())
+}
+
+/// Receive a single message over noise transport.
+///
+/// Frames are received and decrypted until a complete message is available.
+async fn recv_noise<R: AsyncRead + Unpin>(
+    read: &mut R,
+    transport: &mut noise::Transport,
+) -> Result<Vec<u8>, PadawanError> {
+    loop {
+        if let Some(message) = transport.buffer().messages().next_message()? {
+            return Ok(message);
         }
+        noise::wire::recv(read, transport.buffer().encrypted()).await?;
+        transport.decrypt_frame()?;
     }
-    let incoming = Protocol::decode(&incoming)?;
-    if incoming != protocol {
+}
+
+/// Like [`select`][] but for use during noise transport.
+pub async fn select_noise<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    read: &mut R,
+    write: &mut W,
+    protocols: &[ProtocolName],
+    transport: &mut noise::Transport,
+) -> Result<ProtocolName, PadawanError> {
+    for protocol in protocols {
+        send_noise(write, &protocol.encode(), transport).await?;
+        match Message::decode(&recv_noise(read, transport).await?)? {
+            Message::Protocol(ref response) if response == protocol => return Ok(protocol.clone()),
+            Message::NotAvailable => tracing::debug!("Remote peer does not support {}", protocol),
+            _ => return Err(PadawanError::UnexpectedMultistream),
+        }
+    }
+    Err(PadawanError::NegotiationFailed)
+}
+
+/// Like [`dial_lazy`][] but for use during noise transport.
+pub async fn dial_lazy_noise<W: AsyncWrite + Unpin>(
+    write: &mut W,
+    protocol: &ProtocolName,
+    transport: &mut noise::Transport,
+) -> Result<(), PadawanError> {
+    send_noise(write, &ProtocolName::MULTISTREAM.encode(), transport).await?;
+    send_noise(write, &protocol.encode(), transport).await
+}
+
+/// Like [`confirm`][] but for use during noise transport.
+pub async fn confirm_noise<R: AsyncRead + Unpin>(
+    read: &mut R,
+    protocol: &ProtocolName,
+    transport: &mut noise::Transport,
+) -> Result<(), PadawanError> {
+    let headers = Message::decode(&recv_noise(read, transport).await?)?;
+    if headers != Message::Protocol(ProtocolName::MULTISTREAM) {
         return Err(PadawanError::UnexpectedMultistream);
     }
-    Ok(write.write_all(&incoming.encode()).await?)
+    match Message::decode(&recv_noise(read, transport).await?)? {
+        Message::Protocol(ref response) if response == protocol => Ok(()),
+        Message::NotAvailable => Err(PadawanError::NegotiationFailed),
+        _ => Err(PadawanError::UnexpectedMultistream),
+    }
+}
+
+/// Like [`list`][] but for use during noise transport.
+pub async fn list_noise<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    read: &mut R,
+    write: &mut W,
+    transport: &mut noise::Transport,
+) -> Result<Vec<ProtocolName>, PadawanError> {
+    send_noise(write, &Message::ListProtocols.encode(), transport).await?;
+    match Message::decode(&recv_noise(read, transport).await?)? {
+        Message::Protocols(protocols) => Ok(protocols),
+        _ => Err(PadawanError::UnexpectedMultistream),
+    }
+}
+
+/// Like [`handle`][] but for use during noise transport.
+pub async fn handle_noise<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    read: &mut R,
+    write: &mut W,
+    supported: &[ProtocolName],
+    transport: &mut noise::Transport,
+) -> Result<ProtocolName, PadawanError> {
+    loop {
+        let response = match Message::decode_proposal(&recv_noise(read, transport).await?)? {
+            Message::Protocol(incoming) if supported.contains(&incoming) => {
+                send_noise(write, &incoming.encode(), transport).await?;
+                return Ok(incoming);
+            }
+            Message::ListProtocols => Message::Protocols(supported.to_vec()),
+            incoming => {
+                tracing::debug!("Rejecting unsupported proposal {:?}", incoming);
+                Message::NotAvailable
+            }
+        };
+        send_noise(write, &response.encode(), transport).await?;
+    }
 }
 
 /// Like [`dial`][] but for use during noise transport.
-pub async fn dial_noise<'a>(
-    read: &mut ReadHalf<'a>,
-    write: &mut WriteHalf<'a>,
-    protocol: Protocol,
+pub async fn dial_noise<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    read: &mut R,
+    write: &mut W,
+    protocol: &ProtocolName,
     transport: &mut noise::Transport,
 ) -> Result<bool, PadawanError> {
     let encoded = protocol.encode();
-    // Send
-    let buffer = transport.buffer().write();
-    let n = encoded.as_slice().read(buffer).await?;
-    buffer.truncate(n);
-    let encrypted = transport.encrypt()?;
-    noise::wire::send(write, encrypted).await?;
-
-    // Receive
-    let buffer = transport.buffer().encrypted();
-    noise::wire::recv(read, buffer).await?;
-    let decrypted = transport.decrypt()?;
-    Ok(encoded.as_slice() == decrypted)
+    send_noise(write, &encoded, transport).await?;
+    Ok(recv_noise(read, transport).await? == encoded)
 }
 
 /// Like [`listen`][] but for use during noise transport.
-pub async fn listen_noise<'a>(
-    read: &mut ReadHalf<'a>,
-    write: &mut WriteHalf<'a>,
-    protocol: Protocol,
+pub async fn listen_noise<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    read: &mut R,
+    write: &mut W,
+    protocol: &ProtocolName,
     transport: &mut noise::Transport,
 ) -> Result<(), PadawanError> {
-    // Receive
-    let buffer = transport.buffer().encrypted();
-    noise::wire::recv(read, buffer).await?;
-    let decrypted = transport.decrypt()?;
+    match Message::decode(&recv_noise(read, transport).await?)? {
+        Message::Protocol(ref incoming) if incoming == protocol => {
+            send_noise(write, &incoming.encode(), transport).await
+        }
+        _ => Err(PadawanError::UnexpectedMultistream),
+    }
+}
 
-    let incoming = Protocol::decode(decrypted)?;
-    if incoming != protocol {
-        return Err(PadawanError::UnexpectedMultistream);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch::multistream_select::encode;
+    use tokio::net::{TcpListener, TcpStream};
+
+    async fn connected() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (dialer, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        (dialer.unwrap(), accepted.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn select_falls_back_on_na() {
+        let (mut dialer, mut listener) = connected().await;
+        let (dial_read, mut dial_write) = dialer.split();
+        let mut dial_read = FramedRead::new(dial_read);
+        let (listen_read, mut listen_write) = listener.split();
+        let mut listen_read = FramedRead::new(listen_read);
+        let (selected, handled) = tokio::join!(
+            select(
+                &mut dial_read,
+                &mut dial_write,
+                &[ProtocolName::NOISE, ProtocolName::YAMUX]
+            ),
+            handle(&mut listen_read, &mut listen_write, &[ProtocolName::YAMUX]),
+        );
+        assert_eq!(selected.unwrap(), ProtocolName::YAMUX);
+        assert_eq!(handled.unwrap(), ProtocolName::YAMUX);
+    }
+
+    #[tokio::test]
+    async fn select_fails_when_all_rejected() {
+        let (mut dialer, mut listener) = connected().await;
+        let (dial_read, mut dial_write) = dialer.split();
+        let mut dial_read = FramedRead::new(dial_read);
+        let (listen_read, mut listen_write) = listener.split();
+        let mut listen_read = FramedRead::new(listen_read);
+        tokio::select!(
+            selected = select(&mut dial_read, &mut dial_write, &[ProtocolName::NOISE]) => {
+                assert!(matches!(selected, Err(PadawanError::NegotiationFailed)));
+            },
+            _ = handle(&mut listen_read, &mut listen_write, &[ProtocolName::YAMUX]) => {
+                unreachable!("listener cannot agree on an unsupported protocol");
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn select_arbitrary_protocol() {
+        let (mut dialer, mut listener) = connected().await;
+        let (dial_read, mut dial_write) = dialer.split();
+        let mut dial_read = FramedRead::new(dial_read);
+        let (listen_read, mut listen_write) = listener.split();
+        let mut listen_read = FramedRead::new(listen_read);
+        let kad: ProtocolName = "/dot/kad".parse().unwrap();
+        let proposed = [kad.clone()];
+        let supported = [ProtocolName::new("/ipfs/id/1.0.0").unwrap(), kad.clone()];
+        let (selected, handled) = tokio::join!(
+            select(&mut dial_read, &mut dial_write, &proposed),
+            handle(&mut listen_read, &mut listen_write, &supported),
+        );
+        assert_eq!(selected.unwrap(), kad);
+        assert_eq!(handled.unwrap(), kad);
+    }
+
+    #[tokio::test]
+    async fn handle_rejects_invalid_name() {
+        let (mut dialer, mut listener) = connected().await;
+        let (dial_read, mut dial_write) = dialer.split();
+        let mut dial_read = FramedRead::new(dial_read);
+        let (listen_read, mut listen_write) = listener.split();
+        let mut listen_read = FramedRead::new(listen_read);
+        let dial = async {
+            let invalid = encode(b"/\xff\n");
+            dial_write.write_all(&invalid).await?;
+            let response = Message::decode(&dial_read.next_message().await?)?;
+            assert_eq!(response, Message::NotAvailable);
+            select(&mut dial_read, &mut dial_write, &[ProtocolName::YAMUX]).await
+        };
+        let (selected, handled) = tokio::join!(
+            dial,
+            handle(&mut listen_read, &mut listen_write, &[ProtocolName::YAMUX]),
+        );
+        assert_eq!(selected.unwrap(), ProtocolName::YAMUX);
+        assert_eq!(handled.unwrap(), ProtocolName::YAMUX);
+    }
+
+    #[tokio::test]
+    async fn list_then_select() {
+        let (mut dialer, mut listener) = connected().await;
+        let (dial_read, mut dial_write) = dialer.split();
+        let mut dial_read = FramedRead::new(dial_read);
+        let (listen_read, mut listen_write) = listener.split();
+        let mut listen_read = FramedRead::new(listen_read);
+        let supported = [ProtocolName::NOISE, ProtocolName::YAMUX];
+        let dial = async {
+            let listed = list(&mut dial_read, &mut dial_write).await?;
+            select(&mut dial_read, &mut dial_write, &listed[1..]).await
+        };
+        let (selected, handled) = tokio::join!(
+            dial,
+            handle(&mut listen_read, &mut listen_write, &supported),
+        );
+        assert_eq!(selected.unwrap(), ProtocolName::YAMUX);
+        assert_eq!(handled.unwrap(), ProtocolName::YAMUX);
+    }
+
+    #[tokio::test]
+    async fn lazy_rejected() {
+        let (mut dialer, mut listener) = connected().await;
+        let (dial_read, mut dial_write) = dialer.split();
+        let mut dial_read = FramedRead::new(dial_read);
+        let (listen_read, mut listen_write) = listener.split();
+        let mut listen_read = FramedRead::new(listen_read);
+        let noise = &ProtocolName::NOISE;
+        let dial = async {
+            dial_lazy(&mut dial_write, noise).await?;
+            confirm(&mut dial_read, noise).await
+        };
+        let listen = async {
+            let hello = &ProtocolName::MULTISTREAM;
+            concurrent(&mut listen_read, &mut listen_write, hello).await?;
+            handle(&mut listen_read, &mut listen_write, &[ProtocolName::YAMUX]).await
+        };
+        tokio::select!(
+            confirmed = dial => {
+                assert!(matches!(confirmed, Err(PadawanError::NegotiationFailed)));
+            },
+            _ = listen => unreachable!("listener cannot agree on an unsupported protocol"),
+        );
     }
-    // Send
-    let response = incoming.encode();
-    let buffer = transport.buffer().write();
-    let n = response.as_slice().read(buffer).await?;
-    buffer.truncate(n);
-    let encrypted = transport.encrypt()?;
-    noise::wire::send(write, encrypted).await?;
-    Ok(())
 }
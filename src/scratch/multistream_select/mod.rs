@@ -4,16 +4,22 @@
 //!
 //! [multistream_select]: https://github.com/multiformats/multistream-select
 
+use std::borrow::Cow;
+use std::fmt;
+use std::str::FromStr;
+
 use unsigned_varint as varint;
 
 use crate::error::PadawanError;
 
+pub mod framed;
 pub mod mirror;
 
-const MULTISTREAM: &[u8] = b"/multistream/1.0.0\n";
-const NOISE: &[u8] = b"/noise\n";
-const YAMUX: &[u8] = b"/yamux/1.0.0\n";
 const NA: &[u8] = b"na\n";
+const LS: &[u8] = b"ls\n";
+
+/// Maximum length of a protocol name, including the trailing newline
+pub const MAX_PROTOCOL_LENGTH: usize = 140;
 
 /// Encode an arbitrary slice of bytes according to the `multistream_select` specification
 pub fn encode(bytes: &[u8]) -> Vec<u8> {
@@ -23,49 +29,185 @@ pub fn encode(bytes: &[u8]) -> Vec<u8> {
     encoded
 }
 
-/// Supported protocols
-#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
-pub enum Protocol {
-    Multistream,
-    Noise,
-    Yamux,
-    NotAvailable,
+/// The variants of the `multistream_select` negotiation for the dialer
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Version {
+    /// Await the confirmation of each message before proceeding
+    #[default]
+    V1,
+    /// Send the headers, the single protocol candidate, and the first payload
+    /// at once, and validate the confirmation upon the first read.
+    ///
+    /// Saves a round trip per negotiation, at the cost of failing outright
+    /// if the remote does not support the protocol.
+    V1Lazy,
 }
 
-impl Protocol {
-    /// The names of the protocol as bytes
-    pub fn name(&self) -> &'static [u8] {
-        match self {
-            Self::Multistream => MULTISTREAM,
-            Self::Noise => NOISE,
-            Self::Yamux => YAMUX,
-            Self::NotAvailable => NA,
+/// The name of a protocol negotiated through `multistream_select`,
+/// e.g. `/ipfs/id/1.0.0`.
+///
+/// The name is stored without the trailing newline that terminates it on the wire.
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub struct ProtocolName(Cow<'static, str>);
+
+impl ProtocolName {
+    pub const MPLEX: Self = Self(Cow::Borrowed("/mplex/6.7.0"));
+    pub const MULTISTREAM: Self = Self(Cow::Borrowed("/multistream/1.0.0"));
+    pub const NOISE: Self = Self(Cow::Borrowed("/noise"));
+    #[cfg(feature = "plaintext")]
+    pub const PLAINTEXT: Self = Self(Cow::Borrowed("/plaintext/2.0.0"));
+    pub const TLS: Self = Self(Cow::Borrowed("/tls/1.0.0"));
+    pub const YAMUX: Self = Self(Cow::Borrowed("/yamux/1.0.0"));
+
+    /// Create a new protocol name
+    ///
+    /// # Errors
+    ///
+    /// Fails if the name does not start with `/`, contains a newline,
+    /// or exceeds [`MAX_PROTOCOL_LENGTH`][] once terminated.
+    pub fn new(name: impl Into<Cow<'static, str>>) -> Result<Self, PadawanError> {
+        let name = name.into();
+        if !name.starts_with('/') || name.contains('\n') || name.len() >= MAX_PROTOCOL_LENGTH {
+            return Err(PadawanError::InvalidProtocolName(name.into_owned()));
         }
+        Ok(Self(name))
+    }
+
+    /// The name of the protocol
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Encode the protocol including the `varint` prefix and the trailing newline
+    pub fn encode(&self) -> Vec<u8> {
+        let mut name = Vec::with_capacity(self.0.len() + 1);
+        name.extend_from_slice(self.0.as_bytes());
+        name.push(b'\n');
+        encode(&name)
     }
+}
 
-    /// Decode a byte slice into a protocol
+impl fmt::Display for ProtocolName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for ProtocolName {
+    type Err = PadawanError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::new(s.to_owned())
+    }
+}
+
+/// Messages exchanged during a `multistream_select` negotiation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// A protocol proposal, or its confirmation
+    Protocol(ProtocolName),
+    /// The response to a proposal of an unsupported protocol
+    NotAvailable,
+    /// Request for the protocols supported by the remote peer
+    ListProtocols,
+    /// The response to a [`ListProtocols`][`Message::ListProtocols`] request
+    Protocols(Vec<ProtocolName>),
+}
+
+impl Message {
+    /// Decode a byte slice into a message
     ///
     /// # Errors
     ///
     /// Fails if there is a mismatch between the `varint` prefix
-    /// and the protocol length, or in case of `varint` decode
-    /// errors.
+    /// and the message length, if the message is not terminated by a
+    /// newline, or in case of `varint` decode errors.
     pub fn decode(encoded: &[u8]) -> Result<Self, PadawanError> {
-        let (len, protocol) = varint::decode::usize(encoded)?;
-        if len != protocol.len() {
+        let (len, message) = varint::decode::usize(encoded)?;
+        if len != message.len() {
             return Err(PadawanError::InvalidMultistreamEncoding);
         }
-        Ok(match protocol {
-            MULTISTREAM => Self::Multistream,
-            NOISE => Self::Noise,
-            YAMUX => Self::Yamux,
-            _ => Self::NotAvailable,
-        })
+        match message {
+            NA => Ok(Self::NotAvailable),
+            LS => Ok(Self::ListProtocols),
+            [b'/', name @ .., b'\n'] if !name.contains(&b'\n') => {
+                Ok(Self::Protocol(Self::decode_name(message)?))
+            }
+            _ => Self::decode_protocols(message),
+        }
+    }
+
+    /// Decode a newline-terminated protocol name
+    fn decode_name(name: &[u8]) -> Result<ProtocolName, PadawanError> {
+        let name = name
+            .strip_suffix(b"\n")
+            .ok_or(PadawanError::InvalidMultistreamEncoding)?;
+        let name = std::str::from_utf8(name).map_err(|_| {
+            PadawanError::InvalidProtocolName(String::from_utf8_lossy(name).into_owned())
+        })?;
+        name.parse()
     }
 
-    /// Encode the protocol
-    pub fn encode(self) -> Vec<u8> {
-        encode(self.name())
+    /// Decode the body of a [`Protocols`][`Message::Protocols`] response, i.e.
+    /// a sequence of length-prefixed protocol names terminated by a newline.
+    fn decode_protocols(mut remaining: &[u8]) -> Result<Self, PadawanError> {
+        let mut protocols = Vec::new();
+        while remaining != b"\n" {
+            let (len, rest) = varint::decode::usize(remaining)?;
+            if len > rest.len() {
+                return Err(PadawanError::InvalidMultistreamEncoding);
+            }
+            let (name, rest) = rest.split_at(len);
+            protocols.push(Self::decode_name(name)?);
+            remaining = rest;
+        }
+        Ok(Self::Protocols(protocols))
+    }
+
+    /// Decode a message received by the listener of a negotiation.
+    ///
+    /// Proposals of invalid protocol names are decoded as
+    /// [`NotAvailable`][`Message::NotAvailable`], so that they are rejected
+    /// like any unsupported protocol instead of aborting the negotiation.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`decode`][`Message::decode`] for any other invalid message.
+    pub fn decode_proposal(encoded: &[u8]) -> Result<Self, PadawanError> {
+        match Self::decode(encoded) {
+            Err(PadawanError::InvalidProtocolName(name)) => {
+                tracing::debug!("Rejecting invalid protocol name {:?}", name);
+                Ok(Self::NotAvailable)
+            }
+            decoded => decoded,
+        }
+    }
+
+    /// Encode the message
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Self::Protocol(protocol) => protocol.encode(),
+            Self::NotAvailable => encode(NA),
+            Self::ListProtocols => encode(LS),
+            Self::Protocols(protocols) => {
+                let mut varint = [0; 10];
+                let mut body = Vec::new();
+                for protocol in protocols {
+                    let name = protocol.as_str().as_bytes();
+                    body.extend_from_slice(varint::encode::usize(name.len() + 1, &mut varint));
+                    body.extend_from_slice(name);
+                    body.push(b'\n');
+                }
+                body.push(b'\n');
+                encode(&body)
+            }
+        }
+    }
+}
+
+impl From<ProtocolName> for Message {
+    fn from(protocol: ProtocolName) -> Self {
+        Self::Protocol(protocol)
     }
 }
 
@@ -75,25 +217,86 @@ mod tests {
 
     #[test]
     fn protocol_decode_supported() {
-        let protocol = Protocol::Yamux;
-        assert!(matches!(
-            Protocol::decode(&protocol.encode()),
-            Ok(Protocol::Yamux)
-        ));
+        let protocol = ProtocolName::YAMUX;
+        assert_eq!(
+            Message::decode(&protocol.encode()).unwrap(),
+            Message::Protocol(ProtocolName::YAMUX)
+        );
+    }
+
+    #[test]
+    fn protocol_decode_arbitrary() {
+        let encoded = encode(b"/ipfs/id/1.0.0\n");
+        assert_eq!(
+            Message::decode(&encoded).unwrap(),
+            Message::Protocol("/ipfs/id/1.0.0".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn protocol_decode_not_available() {
+        assert_eq!(
+            Message::decode(&Message::NotAvailable.encode()).unwrap(),
+            Message::NotAvailable
+        );
     }
 
     #[test]
-    fn protocol_decode_unsupported() {
-        let protocol = b"unsupported";
+    fn protocol_decode_unterminated() {
+        let protocol = b"/unterminated";
         assert!(matches!(
-            Protocol::decode(&encode(protocol)),
-            Ok(Protocol::NotAvailable)
+            Message::decode(&encode(protocol)),
+            Err(PadawanError::InvalidMultistreamEncoding)
         ));
     }
 
     #[test]
     fn protocol_decode_invalid() {
         let protocol = b"invalid";
-        assert!(Protocol::decode(protocol).is_err());
+        assert!(Message::decode(protocol).is_err());
+    }
+
+    #[test]
+    fn proposal_decode_invalid_name() {
+        let long = format!("/{}\n", "a".repeat(MAX_PROTOCOL_LENGTH));
+        for name in [long.as_bytes(), b"/\xff\n"] {
+            assert!(matches!(
+                Message::decode(&encode(name)),
+                Err(PadawanError::InvalidProtocolName(_))
+            ));
+            assert_eq!(
+                Message::decode_proposal(&encode(name)).unwrap(),
+                Message::NotAvailable
+            );
+        }
+        assert!(Message::decode_proposal(&encode(b"/unterminated")).is_err());
+    }
+
+    #[test]
+    fn protocols_roundtrip() {
+        for protocols in [
+            vec![],
+            vec![ProtocolName::NOISE],
+            vec![ProtocolName::NOISE, "/ipfs/id/1.0.0".parse().unwrap()],
+        ] {
+            let message = Message::Protocols(protocols);
+            assert_eq!(Message::decode(&message.encode()).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn list_protocols_decode() {
+        assert_eq!(
+            Message::decode(&encode(LS)).unwrap(),
+            Message::ListProtocols
+        );
+    }
+
+    #[test]
+    fn protocol_name_validation() {
+        assert!(ProtocolName::new("/dot/kad").is_ok());
+        assert!(ProtocolName::new("dot/kad").is_err());
+        assert!(ProtocolName::new("/dot/kad\n").is_err());
+        assert!(ProtocolName::new(format!("/{}", "a".repeat(MAX_PROTOCOL_LENGTH))).is_err());
     }
 }
@@ -0,0 +1,373 @@
+//! The cipher states of an established noise session.
+//!
+//! Each direction of the session is encrypted with its own key and nonce,
+//! so the two [`CipherState`][]s can be used independently of each other.
+use aes_gcm::Aes256Gcm;
+use bytes::BytesMut;
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce, Tag};
+
+use super::config::Cipher;
+use super::TAG_SIZE;
+use crate::error::PadawanError;
+
+/// The size of a cipher key
+pub const KEY_SIZE: usize = 32;
+
+/// The nonce reserved for rekeying, that cannot be used for messages
+const MAX_NONCE: u64 = u64::MAX;
+
+/// When to replace the key of a [`CipherState`][], with the `REKEY` function
+/// of the noise specification.
+///
+/// Rekeying is not signalled on the wire, so both peers must use the same policy.
+/// By default the key is never replaced.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RekeyPolicy {
+    messages: Option<u64>,
+    bytes: Option<u64>,
+}
+
+impl RekeyPolicy {
+    /// Rekey after the given number of messages
+    pub fn after_messages(mut self, messages: u64) -> Self {
+        self.messages = Some(messages);
+        self
+    }
+
+    /// Rekey once the messages amount to the given number of plaintext bytes
+    pub fn after_bytes(mut self, bytes: u64) -> Self {
+        self.bytes = Some(bytes);
+        self
+    }
+
+    fn is_due(&self, messages: u64, bytes: u64) -> bool {
+        matches!(self.messages, Some(limit) if messages >= limit)
+            || matches!(self.bytes, Some(limit) if bytes >= limit)
+    }
+}
+
+/// The AEAD construction of the negotiated cipher
+#[derive(Clone)]
+enum Aead {
+    ChaChaPoly(ChaCha20Poly1305),
+    AesGcm(Box<Aes256Gcm>),
+}
+
+impl Aead {
+    fn new(key: &[u8; KEY_SIZE], cipher: Cipher) -> Self {
+        match cipher {
+            Cipher::ChaChaPoly => Self::ChaChaPoly(ChaCha20Poly1305::new(key.into())),
+            Cipher::AesGcm => Self::AesGcm(Box::new(Aes256Gcm::new(key.into()))),
+        }
+    }
+
+    fn cipher(&self) -> Cipher {
+        match self {
+            Self::ChaChaPoly(_) => Cipher::ChaChaPoly,
+            Self::AesGcm(_) => Cipher::AesGcm,
+        }
+    }
+
+    /// The nonce of the given counter, encoded after 4 zero bytes
+    /// in the byte order of the cipher
+    fn nonce(&self, counter: u64) -> Nonce {
+        let counter = match self {
+            Self::ChaChaPoly(_) => counter.to_le_bytes(),
+            Self::AesGcm(_) => counter.to_be_bytes(),
+        };
+        let mut nonce = Nonce::default();
+        nonce[4..].copy_from_slice(&counter);
+        nonce
+    }
+
+    fn seal(&self, counter: u64, ad: &[u8], message: &mut [u8]) -> Result<Tag, PadawanError> {
+        let nonce = self.nonce(counter);
+        match self {
+            Self::ChaChaPoly(aead) => aead.encrypt_in_place_detached(&nonce, ad, message),
+            Self::AesGcm(aead) => aead.encrypt_in_place_detached(&nonce, ad, message),
+        }
+        .map_err(|_| snow::Error::Input.into())
+    }
+
+    fn open(
+        &self,
+        counter: u64,
+        ad: &[u8],
+        message: &mut [u8],
+        tag: &[u8],
+    ) -> Result<(), PadawanError> {
+        let (nonce, tag) = (self.nonce(counter), Tag::from_slice(tag));
+        match self {
+            Self::ChaChaPoly(aead) => aead.decrypt_in_place_detached(&nonce, ad, message, tag),
+            Self::AesGcm(aead) => aead.decrypt_in_place_detached(&nonce, ad, message, tag),
+        }
+        .map_err(|_| PadawanError::DecryptFailed)
+    }
+}
+
+/// Encrypt or decrypt the messages of a single direction of the session
+#[derive(Clone)]
+pub struct CipherState {
+    aead: Aead,
+    nonce: u64,
+    policy: RekeyPolicy,
+    /// The number of messages since the last rekey
+    messages: u64,
+    /// The number of plaintext bytes since the last rekey
+    bytes: u64,
+}
+
+impl CipherState {
+    /// Create a new `ChaChaPoly` cipher state from a key derived in the handshake
+    pub fn new(key: &[u8; KEY_SIZE]) -> Self {
+        Self::with_cipher(key, Cipher::ChaChaPoly)
+    }
+
+    /// Create a new cipher state for the given `cipher` from a key derived in the handshake
+    pub fn with_cipher(key: &[u8; KEY_SIZE], cipher: Cipher) -> Self {
+        Self {
+            aead: Aead::new(key, cipher),
+            nonce: 0,
+            policy: Default::default(),
+            messages: 0,
+            bytes: 0,
+        }
+    }
+
+    /// Rekey according to the given `policy`
+    pub fn with_rekey_policy(mut self, policy: RekeyPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// The nonce of the next message
+    pub fn nonce(&self) -> u64 {
+        self.nonce
+    }
+
+    /// Replace the key with the encryption of zeros under the reserved nonce,
+    /// as per the `REKEY` function of the noise specification.
+    ///
+    /// The nonce is not reset, so rekeying does not defer nonce exhaustion.
+    pub fn rekey(&mut self) -> Result<(), PadawanError> {
+        let mut key = [0; KEY_SIZE];
+        self.aead.seal(MAX_NONCE, &[], &mut key)?;
+        self.aead = Aead::new(&key, self.aead.cipher());
+        self.messages = 0;
+        self.bytes = 0;
+        Ok(())
+    }
+
+    /// Encrypt the `plaintext` into `out`, followed by the authentication tag.
+    ///
+    /// Returns the number of bytes written.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the nonces are exhausted, or `out` cannot fit the message.
+    pub fn encrypt(&mut self, plaintext: &[u8], out: &mut [u8]) -> Result<usize, PadawanError> {
+        self.encrypt_with_ad(&[], plaintext, out)
+    }
+
+    /// Encrypt the `plaintext` into `out` like [`encrypt`][`CipherState::encrypt`],
+    /// authenticating the associated data `ad` along with it.
+    pub fn encrypt_with_ad(
+        &mut self,
+        ad: &[u8],
+        plaintext: &[u8],
+        out: &mut [u8],
+    ) -> Result<usize, PadawanError> {
+        let len = plaintext.len();
+        let out = out.get_mut(..len + TAG_SIZE).ok_or(snow::Error::Input)?;
+        let (message, tag) = out.split_at_mut(len);
+        message.copy_from_slice(plaintext);
+        tag.copy_from_slice(&self.seal(ad, message)?);
+        Ok(len + TAG_SIZE)
+    }
+
+    /// Decrypt the `ciphertext`, followed by its authentication tag, into `out`.
+    ///
+    /// Returns the number of bytes written.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the nonces are exhausted, `out` cannot fit the message,
+    /// or the message cannot be authenticated.
+    pub fn decrypt(&mut self, ciphertext: &[u8], out: &mut [u8]) -> Result<usize, PadawanError> {
+        self.decrypt_with_ad(&[], ciphertext, out)
+    }
+
+    /// Decrypt the `ciphertext` into `out` like [`decrypt`][`CipherState::decrypt`],
+    /// authenticating the associated data `ad` along with it.
+    pub fn decrypt_with_ad(
+        &mut self,
+        ad: &[u8],
+        ciphertext: &[u8],
+        out: &mut [u8],
+    ) -> Result<usize, PadawanError> {
+        let len = ciphertext
+            .len()
+            .checked_sub(TAG_SIZE)
+            .ok_or(PadawanError::DecryptFailed)?;
+        let (message, tag) = ciphertext.split_at(len);
+        let out = out.get_mut(..len).ok_or(snow::Error::Input)?;
+        out.copy_from_slice(message);
+        self.open(ad, out, tag)?;
+        Ok(len)
+    }
+
+    /// Encrypt the bytes of the `buffer` following `offset` in place,
+    /// and append the authentication tag.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the nonces are exhausted.
+    pub fn encrypt_in_place(
+        &mut self,
+        buffer: &mut BytesMut,
+        offset: usize,
+    ) -> Result<(), PadawanError> {
+        let message = buffer.get_mut(offset..).ok_or(snow::Error::Input)?;
+        let tag = self.seal(&[], message)?;
+        buffer.extend_from_slice(&tag);
+        Ok(())
+    }
+
+    /// Decrypt the `buffer` in place, and remove its authentication tag.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the nonces are exhausted, or the message cannot be authenticated.
+    pub fn decrypt_in_place(&mut self, buffer: &mut BytesMut) -> Result<(), PadawanError> {
+        let len = buffer
+            .len()
+            .checked_sub(TAG_SIZE)
+            .ok_or(PadawanError::DecryptFailed)?;
+        let (message, tag) = buffer.split_at_mut(len);
+        self.open(&[], message, tag)?;
+        buffer.truncate(len);
+        Ok(())
+    }
+
+    /// Encrypt the `message` in place, and return its authentication tag
+    fn seal(&mut self, ad: &[u8], message: &mut [u8]) -> Result<Tag, PadawanError> {
+        let tag = self.aead.seal(self.next_nonce()?, ad, message)?;
+        self.advance(message.len())?;
+        Ok(tag)
+    }
+
+    /// Authenticate and decrypt the `message` in place
+    fn open(&mut self, ad: &[u8], message: &mut [u8], tag: &[u8]) -> Result<(), PadawanError> {
+        self.aead.open(self.next_nonce()?, ad, message, tag)?;
+        self.advance(message.len())
+    }
+
+    fn next_nonce(&self) -> Result<u64, PadawanError> {
+        if self.nonce == MAX_NONCE {
+            return Err(PadawanError::NonceExhausted);
+        }
+        Ok(self.nonce)
+    }
+
+    /// Move on to the nonce of the next message, and rekey if due
+    fn advance(&mut self, len: usize) -> Result<(), PadawanError> {
+        self.nonce += 1;
+        self.messages += 1;
+        self.bytes += len as u64;
+        if self.policy.is_due(self.messages, self.bytes) {
+            self.rekey()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let key = [7; KEY_SIZE];
+        let (mut sender, mut receiver) = (CipherState::new(&key), CipherState::new(&key));
+        for message in [&b""[..], b"hello", &[42; 1000]] {
+            let mut encrypted = vec![0; 2048];
+            let n = sender.encrypt(message, &mut encrypted).unwrap();
+            assert_eq!(n, message.len() + TAG_SIZE);
+            let mut decrypted = vec![0; 2048];
+            let n = receiver.decrypt(&encrypted[..n], &mut decrypted).unwrap();
+            assert_eq!(&decrypted[..n], message);
+        }
+        assert_eq!(sender.nonce(), 3);
+        assert_eq!(receiver.nonce(), 3);
+    }
+
+    #[test]
+    fn reject_tampered() {
+        let key = [7; KEY_SIZE];
+        let (mut sender, mut receiver) = (CipherState::new(&key), CipherState::new(&key));
+        let mut encrypted = [0; 64];
+        let n = sender.encrypt(b"hello", &mut encrypted).unwrap();
+        encrypted[0] ^= 1;
+        let mut decrypted = [0; 64];
+        assert!(matches!(
+            receiver.decrypt(&encrypted[..n], &mut decrypted),
+            Err(PadawanError::DecryptFailed)
+        ));
+        assert_eq!(receiver.nonce(), 0);
+    }
+
+    #[test]
+    fn roundtrip_in_place() {
+        let key = [7; KEY_SIZE];
+        let (mut sender, mut receiver) = (CipherState::new(&key), CipherState::new(&key));
+        let mut buffer = BytesMut::from(&b"headerhello"[..]);
+        sender.encrypt_in_place(&mut buffer, 6).unwrap();
+        assert_eq!(buffer.len(), 11 + TAG_SIZE);
+        assert_eq!(&buffer[..6], b"header");
+
+        let mut message = buffer.split_off(6);
+        receiver.decrypt_in_place(&mut message).unwrap();
+        assert_eq!(&message[..], b"hello");
+    }
+
+    #[test]
+    fn rekey_by_policy() {
+        let key = [7; KEY_SIZE];
+        for policy in [
+            RekeyPolicy::default().after_messages(2),
+            RekeyPolicy::default().after_bytes(10),
+        ] {
+            let mut sender = CipherState::new(&key).with_rekey_policy(policy);
+            let mut receiver = CipherState::new(&key).with_rekey_policy(policy);
+            let mut stale = CipherState::new(&key);
+            let (mut encrypted, mut decrypted) = ([0; 64], [0; 64]);
+            for i in 0..5 {
+                let n = sender.encrypt(b"hello", &mut encrypted).unwrap();
+                receiver.decrypt(&encrypted[..n], &mut decrypted).unwrap();
+                let result = stale.decrypt(&encrypted[..n], &mut decrypted);
+                // The first rekey takes place after the second message
+                assert_eq!(result.is_ok(), i < 2);
+            }
+        }
+    }
+
+    #[test]
+    fn exhaust_nonces() {
+        let key = [7; KEY_SIZE];
+        let (mut sender, mut receiver) = (CipherState::new(&key), CipherState::new(&key));
+        sender.nonce = MAX_NONCE - 1;
+        receiver.nonce = MAX_NONCE - 1;
+        let (mut encrypted, mut decrypted) = ([0; 64], [0; 64]);
+        let n = sender.encrypt(b"hello", &mut encrypted).unwrap();
+        receiver.decrypt(&encrypted[..n], &mut decrypted).unwrap();
+        assert!(matches!(
+            sender.encrypt(b"hello", &mut encrypted),
+            Err(PadawanError::NonceExhausted)
+        ));
+        assert!(matches!(
+            receiver.decrypt(&encrypted[..n], &mut decrypted),
+            Err(PadawanError::NonceExhausted)
+        ));
+    }
+}
@@ -0,0 +1,239 @@
+//! The noise protocol used by the handshake.
+//!
+//! `libp2p` peers only speak `Noise_XX_25519_ChaChaPoly_SHA256`, which is the
+//! default. Other cipher suites and patterns are only understood by peers
+//! configured alike, and are meant for interop experiments and benchmarks.
+use std::fmt;
+use std::str::FromStr;
+
+use super::cipher::RekeyPolicy;
+use crate::error::PadawanError;
+
+/// The handshake pattern of the noise protocol
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakePattern {
+    /// Both peers transmit their static keys during the handshake
+    #[default]
+    Xx,
+    /// The initiator knows the static key of the responder up front,
+    /// and the handshake completes in two messages.
+    Ik,
+    /// Noise Pipes: an [`IK`][`HandshakePattern::Ik`] handshake, that falls back to
+    /// `XXfallback` if the responder cannot decrypt the initial message, e.g. since
+    /// the initiator knows an outdated static key of the responder.
+    XxFallback,
+}
+
+impl HandshakePattern {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Xx => "XX",
+            Self::Ik => "IK",
+            Self::XxFallback => "XXfallback",
+        }
+    }
+}
+
+/// The cipher of the noise protocol
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Cipher {
+    #[default]
+    ChaChaPoly,
+    AesGcm,
+}
+
+impl Cipher {
+    fn name(self) -> &'static str {
+        match self {
+            Self::ChaChaPoly => "ChaChaPoly",
+            Self::AesGcm => "AESGCM",
+        }
+    }
+}
+
+/// The hash function of the noise protocol
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Hash {
+    #[default]
+    Sha256,
+    Blake2s,
+    Blake2b,
+}
+
+impl Hash {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Sha256 => "SHA256",
+            Self::Blake2s => "BLAKE2s",
+            Self::Blake2b => "BLAKE2b",
+        }
+    }
+}
+
+/// The noise protocol of a handshake, along with the static key of the
+/// remote peer if known up front, and the rekey policy of the session.
+///
+/// It is displayed and parsed by its protocol name, e.g. `Noise_IK_25519_AESGCM_BLAKE2s`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct NoiseConfig {
+    pattern: HandshakePattern,
+    cipher: Cipher,
+    hash: Hash,
+    remote_static: Option<Vec<u8>>,
+    rekey_policy: RekeyPolicy,
+}
+
+impl NoiseConfig {
+    /// Set the handshake pattern
+    pub fn with_pattern(mut self, pattern: HandshakePattern) -> Self {
+        self.pattern = pattern;
+        self
+    }
+
+    /// Set the cipher
+    pub fn with_cipher(mut self, cipher: Cipher) -> Self {
+        self.cipher = cipher;
+        self
+    }
+
+    /// Set the hash function
+    pub fn with_hash(mut self, hash: Hash) -> Self {
+        self.hash = hash;
+        self
+    }
+
+    /// Set the static noise key of the remote peer, required by the initiator of an
+    /// [`IK`][`HandshakePattern::Ik`] or [`XXfallback`][`HandshakePattern::XxFallback`] handshake.
+    pub fn with_remote_static(mut self, key: &[u8]) -> Self {
+        self.remote_static = Some(key.to_vec());
+        self
+    }
+
+    /// Rekey both directions of the session according to the given `policy`,
+    /// which the remote peer must share.
+    pub fn with_rekey_policy(mut self, policy: RekeyPolicy) -> Self {
+        self.rekey_policy = policy;
+        self
+    }
+
+    /// The handshake pattern of the protocol
+    pub fn pattern(&self) -> HandshakePattern {
+        self.pattern
+    }
+
+    /// The cipher function of the protocol
+    pub fn cipher(&self) -> Cipher {
+        self.cipher
+    }
+
+    /// The hash function of the protocol
+    pub fn hash(&self) -> Hash {
+        self.hash
+    }
+
+    /// The static noise key of the remote peer, if known up front
+    pub fn remote_static(&self) -> Option<&[u8]> {
+        self.remote_static.as_deref()
+    }
+
+    /// The policy for rekeying the session
+    pub fn rekey_policy(&self) -> RekeyPolicy {
+        self.rekey_policy
+    }
+
+    /// The parameters of the protocol for building a [`snow`][] handshake
+    pub fn params(&self) -> Result<snow::params::NoiseParams, PadawanError> {
+        Ok(self.to_string().parse()?)
+    }
+}
+
+impl fmt::Display for NoiseConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Noise_{}_25519_{}_{}",
+            self.pattern.name(),
+            self.cipher.name(),
+            self.hash.name()
+        )
+    }
+}
+
+impl FromStr for NoiseConfig {
+    type Err = PadawanError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        let unsupported =
+            |reason: &str| PadawanError::UnsupportedNoiseProtocol(format!("{name}: {reason}"));
+        let parts: Vec<&str> = name.split('_').collect();
+        let (pattern, dh, cipher, hash) = match parts[..] {
+            ["Noise", pattern, dh, cipher, hash] => (pattern, dh, cipher, hash),
+            _ => return Err(unsupported("expected Noise_<pattern>_<dh>_<cipher>_<hash>")),
+        };
+        let pattern = match pattern {
+            "XX" => HandshakePattern::Xx,
+            "IK" => HandshakePattern::Ik,
+            "XXfallback" => HandshakePattern::XxFallback,
+            _ => return Err(unsupported("expected the XX, IK or XXfallback pattern")),
+        };
+        if dh != "25519" {
+            return Err(unsupported("expected the 25519 key exchange"));
+        }
+        let cipher = match cipher {
+            "ChaChaPoly" => Cipher::ChaChaPoly,
+            "AESGCM" => Cipher::AesGcm,
+            _ => return Err(unsupported("expected the ChaChaPoly or AESGCM cipher")),
+        };
+        let hash = match hash {
+            "SHA256" => Hash::Sha256,
+            "BLAKE2s" => Hash::Blake2s,
+            "BLAKE2b" => Hash::Blake2b,
+            _ => return Err(unsupported("expected the SHA256, BLAKE2s or BLAKE2b hash")),
+        };
+        Ok(Self {
+            pattern,
+            cipher,
+            hash,
+            remote_static: None,
+            rekey_policy: Default::default(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_protocol_name() {
+        let config = NoiseConfig::default();
+        assert_eq!(config.to_string(), "Noise_XX_25519_ChaChaPoly_SHA256");
+        assert_eq!(config.to_string().parse::<NoiseConfig>().unwrap(), config);
+
+        let config: NoiseConfig = "Noise_IK_25519_AESGCM_BLAKE2b".parse().unwrap();
+        assert_eq!(config.pattern(), HandshakePattern::Ik);
+        assert_eq!(config.cipher(), Cipher::AesGcm);
+        assert_eq!(config.hash(), Hash::Blake2b);
+        assert!(config.params().is_ok());
+
+        let config: NoiseConfig = "Noise_XXfallback_25519_ChaChaPoly_SHA256".parse().unwrap();
+        assert_eq!(config.pattern(), HandshakePattern::XxFallback);
+        assert_eq!(
+            config.to_string(),
+            "Noise_XXfallback_25519_ChaChaPoly_SHA256"
+        );
+
+        for name in [
+            "Noise_XXfallback+psk0_25519_ChaChaPoly_SHA256",
+            "Noise_NN_25519_ChaChaPoly_SHA256",
+            "Noise_XX_448_ChaChaPoly_SHA256",
+            "Noise_XX_25519_ChaChaPoly_SHA512",
+            "Noise_XX_25519_ChaChaPoly",
+        ] {
+            assert!(matches!(
+                name.parse::<NoiseConfig>(),
+                Err(PadawanError::UnsupportedNoiseProtocol(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,172 @@
+//! Long-lived static keys of the local peer for the noise handshake.
+//!
+//! Signing the static key with the identity of the local peer is the most
+//! expensive part of the handshake payload. A [`SignedStaticKey`][] carries the
+//! signature along with the key, so that it is computed once and reused by
+//! every handshake until the key is rotated.
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use libp2p::identity;
+
+use super::libp2p::Identity;
+use crate::error::PadawanError;
+
+/// A static noise key, signed with the identity of the local peer
+pub struct SignedStaticKey {
+    keypair: snow::Keypair,
+    identity_key: Vec<u8>,
+    identity_sig: Vec<u8>,
+    created: Instant,
+}
+
+impl SignedStaticKey {
+    /// Sign the given static noise `keypair` with the `identity` of the local peer
+    ///
+    /// # Errors
+    ///
+    /// Fails if the identity cannot sign messages.
+    pub fn new(identity: &identity::Keypair, keypair: snow::Keypair) -> Result<Self, PadawanError> {
+        let message = Identity::new(&keypair.public).into_message();
+        Ok(Self {
+            identity_sig: identity.sign(&message)?,
+            identity_key: identity.public().to_protobuf_encoding(),
+            keypair,
+            created: Instant::now(),
+        })
+    }
+
+    /// Generate a new static noise key, signed with the `identity` of the local peer
+    ///
+    /// # Errors
+    ///
+    /// Fails if the identity cannot sign messages.
+    pub fn generate(identity: &identity::Keypair) -> Result<Self, PadawanError> {
+        Self::new(identity, super::generate_keypair()?)
+    }
+
+    /// The public static noise key
+    pub fn public(&self) -> &[u8] {
+        &self.keypair.public
+    }
+
+    /// A copy of the static noise keypair, for building a handshake
+    pub fn keypair(&self) -> snow::Keypair {
+        snow::Keypair {
+            private: self.keypair.private.clone(),
+            public: self.keypair.public.clone(),
+        }
+    }
+
+    /// The protobuf encoding of the public identity key that signed the static key
+    pub fn identity_key(&self) -> &[u8] {
+        &self.identity_key
+    }
+
+    /// The signature of the static key
+    pub fn identity_sig(&self) -> &[u8] {
+        &self.identity_sig
+    }
+
+    /// The time elapsed since the key was signed
+    pub fn age(&self) -> Duration {
+        self.created.elapsed()
+    }
+}
+
+/// The static noise key of the local peer, shared across its connections.
+///
+/// The key is generated on first use, and replaced by a new one once
+/// the rotation period, if any, has elapsed.
+#[derive(Clone)]
+pub struct StaticKeys {
+    identity: identity::Keypair,
+    current: Arc<Mutex<Option<Arc<SignedStaticKey>>>>,
+    rotation: Option<Duration>,
+}
+
+impl StaticKeys {
+    /// Create the static keys of the local peer with the given `identity`
+    pub fn new(identity: identity::Keypair) -> Self {
+        Self {
+            identity,
+            current: Default::default(),
+            rotation: None,
+        }
+    }
+
+    /// Rotate the static key once it is older than the given `period`
+    pub fn with_rotation(mut self, period: Duration) -> Self {
+        self.rotation = Some(period);
+        self
+    }
+
+    /// The static key to be used by a new handshake
+    ///
+    /// # Errors
+    ///
+    /// Fails if a new key is due, but cannot be generated or signed.
+    pub fn current(&self) -> Result<Arc<SignedStaticKey>, PadawanError> {
+        let mut current = self.current.lock().unwrap_or_else(|err| err.into_inner());
+        match &*current {
+            Some(key) if !self.is_expired(key) => Ok(key.clone()),
+            _ => {
+                let key = Arc::new(SignedStaticKey::generate(&self.identity)?);
+                tracing::debug!("Generated static noise key {}", hex::encode(key.public()));
+                *current = Some(key.clone());
+                Ok(key)
+            }
+        }
+    }
+
+    /// Replace the static key with a new one, regardless of the rotation period.
+    ///
+    /// Handshakes in progress complete with the previous key.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the new key cannot be generated or signed.
+    pub fn rotate(&self) -> Result<(), PadawanError> {
+        let key = Arc::new(SignedStaticKey::generate(&self.identity)?);
+        *self.current.lock().unwrap_or_else(|err| err.into_inner()) = Some(key);
+        Ok(())
+    }
+
+    fn is_expired(&self, key: &SignedStaticKey) -> bool {
+        matches!(self.rotation, Some(period) if key.age() >= period)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch::noise::libp2p::NoiseHandshakePayload;
+
+    #[test]
+    fn sign_static_key() {
+        let identity = identity::Keypair::generate_ed25519();
+        let key = SignedStaticKey::generate(&identity).unwrap();
+        let payload = NoiseHandshakePayload {
+            identity_key: Some(key.identity_key().to_vec()),
+            identity_sig: Some(key.identity_sig().to_vec()),
+            extensions: None,
+        };
+        let peer_id = payload
+            .verify_identity(Identity::new(key.public()))
+            .unwrap();
+        assert_eq!(peer_id, identity.public().to_peer_id());
+    }
+
+    #[test]
+    fn reuse_until_rotation() {
+        let keys = StaticKeys::new(identity::Keypair::generate_ed25519());
+        let key = keys.current().unwrap();
+        assert!(Arc::ptr_eq(&key, &keys.current().unwrap()));
+        keys.rotate().unwrap();
+        assert_ne!(keys.current().unwrap().public(), key.public());
+
+        let keys = keys.with_rotation(Duration::ZERO);
+        let key = keys.current().unwrap();
+        assert_ne!(keys.current().unwrap().public(), key.public());
+    }
+}
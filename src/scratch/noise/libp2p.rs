@@ -2,13 +2,19 @@
 //! of the noise protocol.
 #![allow(clippy::derive_partial_eq_without_eq)]
 
+use std::sync::Arc;
+
+use bytes::BytesMut;
 use libp2p::identity;
 use prost::Message;
-use tokio::net::tcp;
+use tokio::io::{AsyncRead, AsyncWrite};
 
+use super::config::{HandshakePattern, NoiseConfig};
+use super::keys::SignedStaticKey;
 use super::wire;
 use super::Handshake;
 use crate::error::PadawanError;
+use crate::scratch::multistream_select::ProtocolName;
 
 // The protobuf types representing the handshake payload
 //
@@ -16,7 +22,10 @@ use crate::error::PadawanError;
 include!(concat!(env!("OUT_DIR"), "/payload.rs"));
 
 impl NoiseHandshakePayload {
-    pub fn verify_identity(&self, identity: Identity) -> Result<(), PadawanError> {
+    /// Verify the signature of the remote peer over its static noise key
+    ///
+    /// Returns the [`PeerId`][`libp2p::PeerId`] of the remote peer.
+    pub fn verify_identity(&self, identity: Identity) -> Result<libp2p::PeerId, PadawanError> {
         let remote_key = identity::PublicKey::from_protobuf_encoding(self.identity_key())?;
         tracing::debug!("remote key {:?}", remote_key);
 
@@ -27,11 +36,9 @@ impl NoiseHandshakePayload {
         if !remote_key.verify(&identity.into_message(), sig) {
             return Err(PadawanError::IdVerification);
         }
-        tracing::info!(
-            "Verified remote identity: {:?}",
-            libp2p::PeerId::from_public_key(&remote_key)
-        );
-        Ok(())
+        let peer_id = libp2p::PeerId::from_public_key(&remote_key);
+        tracing::info!("Verified remote identity: {:?}", peer_id);
+        Ok(peer_id)
     }
 }
 
@@ -55,11 +62,27 @@ impl<'a> Identity<'a> {
 }
 
 /// Represents the `libp2p` implementation of the noise handshake
-pub struct NoiseHandshake(Handshake);
+pub struct NoiseHandshake {
+    inner: Handshake,
+    /// Stream muxers advertised to the remote peer, in order of preference
+    muxers: Vec<ProtocolName>,
+    /// Stream muxers advertised by the remote peer
+    remote_muxers: Vec<ProtocolName>,
+    /// The long-lived static key of the handshake, if any, along with its signature
+    static_key: Option<Arc<SignedStaticKey>>,
+    /// The number of handshake messages exchanged so far
+    messages: usize,
+}
 
 impl From<Handshake> for NoiseHandshake {
     fn from(protocol_state: Handshake) -> Self {
-        Self(protocol_state)
+        Self {
+            inner: protocol_state,
+            muxers: Vec::new(),
+            remote_muxers: Vec::new(),
+            static_key: None,
+            messages: 0,
+        }
     }
 }
 
@@ -74,61 +97,324 @@ impl NoiseHandshake {
         Ok(Self::from(Handshake::build_responder()?))
     }
 
+    /// Build an initiator handshake state with a long-lived static key,
+    /// whose signature is reused instead of signing it again
+    pub fn dialer_with_key(static_key: Arc<SignedStaticKey>) -> Result<Self, PadawanError> {
+        Self::dialer_with_config(static_key, Default::default())
+    }
+
+    /// Build a responder handshake state with a long-lived static key,
+    /// whose signature is reused instead of signing it again
+    pub fn listener_with_key(static_key: Arc<SignedStaticKey>) -> Result<Self, PadawanError> {
+        Self::listener_with_config(static_key, Default::default())
+    }
+
+    /// Build an initiator handshake state with a long-lived static key,
+    /// speaking the noise protocol of the given `config`
+    pub fn dialer_with_config(
+        static_key: Arc<SignedStaticKey>,
+        config: NoiseConfig,
+    ) -> Result<Self, PadawanError> {
+        let handshake = Handshake::build_initiator_with_config(static_key.keypair(), config)?;
+        Ok(Self::from(handshake).with_static_key(static_key))
+    }
+
+    /// Build a responder handshake state with a long-lived static key,
+    /// speaking the noise protocol of the given `config`
+    pub fn listener_with_config(
+        static_key: Arc<SignedStaticKey>,
+        config: NoiseConfig,
+    ) -> Result<Self, PadawanError> {
+        let handshake = Handshake::build_responder_with_config(static_key.keypair(), config)?;
+        Ok(Self::from(handshake).with_static_key(static_key))
+    }
+
+    fn with_static_key(mut self, static_key: Arc<SignedStaticKey>) -> Self {
+        self.static_key = Some(static_key);
+        self
+    }
+
+    /// Advertise the given stream muxers in the identity payload,
+    /// as per the early muxer negotiation of the `libp2p` noise specification.
+    pub fn with_muxers(mut self, muxers: &[ProtocolName]) -> Self {
+        self.muxers = muxers.to_vec();
+        self
+    }
+
+    /// The stream muxers advertised by the remote peer
+    pub fn remote_muxers(&self) -> &[ProtocolName] {
+        &self.remote_muxers
+    }
+
+    /// The stream muxer agreed during the handshake, if any.
+    ///
+    /// This is the first muxer of the initiator that is also supported by the responder.
+    pub fn negotiated_muxer(&self) -> Option<&ProtocolName> {
+        let (initiator, responder) = if self.inner.is_initiator() {
+            (&self.muxers, &self.remote_muxers)
+        } else {
+            (&self.remote_muxers, &self.muxers)
+        };
+        initiator.iter().find(|muxer| responder.contains(muxer))
+    }
+
     /// Get the inner stateful buffer of the noise-handshake state
     pub fn into_inner(self) -> Handshake {
-        self.0
+        self.inner
+    }
+
+    /// Whether the local peer sends the next message of the handshake
+    pub fn is_my_turn(&self) -> bool {
+        self.inner.is_my_turn()
+    }
+
+    /// Whether all the messages of the handshake have been exchanged
+    pub fn is_finished(&self) -> bool {
+        self.inner.is_finished()
+    }
+
+    /// Create the next message of the local peer: the empty initial message
+    /// of an `XX` dialer, or the identity payload otherwise.
+    pub fn write_message(&mut self, keypair: &identity::Keypair) -> Result<&[u8], PadawanError> {
+        if self.is_hello() {
+            self.write_hello()
+        } else {
+            self.write_identity(keypair)
+        }
+    }
+
+    /// Process the next message of the remote peer
+    ///
+    /// Returns the verified [`PeerId`][`libp2p::PeerId`] of the remote peer,
+    /// unless the message is the initial message of an `XX` dialer, or the
+    /// initial `IK` message that a listener fell back to `XXfallback` from.
+    pub fn read_message(&mut self, message: &[u8]) -> Result<Option<libp2p::PeerId>, PadawanError> {
+        if self.is_hello() {
+            self.read_hello(message).map(|_| None)
+        } else {
+            self.read_payload(message)
+        }
+    }
+
+    /// Whether the next message is the initial message of an `XX` handshake,
+    /// that precedes any static key and carries no payload
+    fn is_hello(&self) -> bool {
+        self.inner.config().pattern() == HandshakePattern::Xx && self.messages == 0
+    }
+
+    /// Create the initial message of the dialer
+    pub fn write_hello(&mut self) -> Result<&[u8], PadawanError> {
+        self.inner.buffer().write().clear();
+        self.messages += 1;
+        Ok(self.inner.encrypt()?)
+    }
+
+    /// Process the initial message of the dialer
+    pub fn read_hello(&mut self, message: &[u8]) -> Result<(), PadawanError> {
+        if !self.decrypt(message)?.is_empty() {
+            // Only the initial message of another pattern carries a payload
+            return Err(self.mismatch());
+        }
+        Ok(())
+    }
+
+    /// Process and verify the identity payload of the remote peer
+    ///
+    /// Returns the verified [`PeerId`][`libp2p::PeerId`] of the remote peer.
+    pub fn read_identity(&mut self, message: &[u8]) -> Result<libp2p::PeerId, PadawanError> {
+        self.read_payload(message)?.ok_or_else(|| self.mismatch())
+    }
+
+    /// Process the identity payload of the remote peer, unless the listener
+    /// falls back to `XXfallback` and discards the message.
+    fn read_payload(&mut self, message: &[u8]) -> Result<Option<libp2p::PeerId>, PadawanError> {
+        let fallback = self.inner.is_fallback();
+        self.decrypt(message)?;
+        if !fallback && self.inner.is_fallback() && !self.inner.is_initiator() {
+            return Ok(None);
+        }
+        let payload = NoiseHandshakePayload::decode(&self.inner.buffer().read()[..])?;
+        if let Some(extensions) = &payload.extensions {
+            self.remote_muxers = extensions
+                .stream_muxers
+                .iter()
+                .filter_map(|muxer| muxer.parse().ok())
+                .collect();
+        }
+        let remote_key = self
+            .inner
+            .remote_static()
+            .ok_or(PadawanError::MissingRemoteNoiseKey)?;
+        payload.verify_identity(Identity::new(remote_key)).map(Some)
+    }
+
+    /// Create the identity payload message of the local peer.
+    ///
+    /// The static key is signed with the given `keypair`, unless the handshake
+    /// was built with a [`SignedStaticKey`][], whose signature is used instead.
+    pub fn write_identity(&mut self, keypair: &identity::Keypair) -> Result<&[u8], PadawanError> {
+        let (identity_key, identity_sig) = match &self.static_key {
+            Some(key) => (key.identity_key().to_vec(), key.identity_sig().to_vec()),
+            None => {
+                let msg = Identity::new(self.inner.local_static()).into_message();
+                (keypair.public().to_protobuf_encoding(), keypair.sign(&msg)?)
+            }
+        };
+        let payload = NoiseHandshakePayload {
+            identity_key: Some(identity_key),
+            identity_sig: Some(identity_sig),
+            extensions: (!self.muxers.is_empty()).then(|| NoiseExtensions {
+                webtransport_certhashes: Vec::new(),
+                stream_muxers: self.muxers.iter().map(ToString::to_string).collect(),
+            }),
+        };
+        let buffer = self.inner.buffer().write();
+        buffer.clear();
+        payload.encode(buffer)?;
+        self.messages += 1;
+        Ok(self.inner.encrypt()?)
+    }
+
+    /// Decrypt a handshake message of the remote peer.
+    ///
+    /// A message that cannot be decrypted most likely comes from a peer that
+    /// speaks a different noise protocol, and is reported as such.
+    fn decrypt(&mut self, message: &[u8]) -> Result<&mut BytesMut, PadawanError> {
+        let buffer = self.inner.buffer().encrypted();
+        buffer.clear();
+        buffer.extend_from_slice(message);
+        self.messages += 1;
+        match self.inner.decrypt() {
+            Ok(_) => Ok(self.inner.buffer().read()),
+            Err(
+                PadawanError::Snow(snow::Error::Decrypt | snow::Error::Input)
+                | PadawanError::DecryptFailed,
+            ) => Err(self.mismatch()),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn mismatch(&self) -> PadawanError {
+        PadawanError::NoiseProtocolMismatch(self.inner.config().to_string())
     }
 
     /// Dialer initial communication
-    pub async fn hello<'a>(&mut self, write: &mut tcp::WriteHalf<'a>) -> Result<(), PadawanError> {
-        let encrypted = self.0.encrypt()?;
-        let n = wire::send(write, encrypted).await?;
+    pub async fn hello<W: AsyncWrite + Unpin>(
+        &mut self,
+        write: &mut W,
+    ) -> Result<(), PadawanError> {
+        let n = wire::send(write, self.write_hello()?).await?;
         tracing::trace!("Noise hello: {:?} bytes", n);
         Ok(())
     }
 
     /// Listener initial communication
-    pub async fn recv_hello<'a>(
+    pub async fn recv_hello<R: AsyncRead + Unpin>(
         &mut self,
-        read: &mut tcp::ReadHalf<'a>,
+        read: &mut R,
     ) -> Result<(), PadawanError> {
-        wire::recv(read, self.0.buffer().encrypted()).await?;
-        self.0.decrypt()?;
-        Ok(())
+        let mut message = BytesMut::new();
+        wire::recv(read, &mut message).await?;
+        self.read_hello(&message)
     }
 
     /// Receive and verify identity from the remote peer
-    pub async fn recv_identity<'a>(
+    pub async fn recv_identity<R: AsyncRead + Unpin>(
         &mut self,
-        read: &mut tcp::ReadHalf<'a>,
-    ) -> Result<(), PadawanError> {
-        wire::recv(read, self.0.buffer().encrypted()).await?;
-        let decrypted = self.0.decrypt()?;
-        let payload = NoiseHandshakePayload::decode(decrypted.as_slice())?;
-        let remote_key = self
-            .0
-            .remote_static()
-            .ok_or(PadawanError::MissingRemoteNoiseKey)?;
-        payload.verify_identity(Identity::new(remote_key))?;
-        Ok(())
+        read: &mut R,
+    ) -> Result<libp2p::PeerId, PadawanError> {
+        let mut message = BytesMut::new();
+        wire::recv(read, &mut message).await?;
+        self.read_identity(&message)
     }
 
     /// Construct and send identity payload for a local peer
-    pub async fn send_identity<'a>(
+    pub async fn send_identity<W: AsyncWrite + Unpin>(
         &mut self,
-        write: &mut tcp::WriteHalf<'a>,
+        write: &mut W,
         keypair: &identity::Keypair,
     ) -> Result<(), PadawanError> {
-        let msg = Identity::new(self.0.local_static()).into_message();
-        let payload = NoiseHandshakePayload {
-            identity_key: Some(keypair.public().to_protobuf_encoding()),
-            identity_sig: Some(keypair.sign(&msg)?),
-            extensions: None,
-        };
-        payload.encode(self.0.buffer().write())?;
-        let encrypted = self.0.encrypt()?;
-        let n = wire::send(write, encrypted).await?;
+        let n = wire::send(write, self.write_identity(keypair)?).await?;
         tracing::trace!("Sent noise identity: {:?} bytes", n);
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch::noise::{self, Transport};
+
+    /// The hex-encoded messages of a handshake transcript in `testdata`
+    fn transcript(contents: &str) -> Vec<Vec<u8>> {
+        contents
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .map(|line| hex::decode(line).unwrap())
+            .collect()
+    }
+
+    fn peer(
+        identity: u8,
+        key: u8,
+        ephemeral: u8,
+        initiator: bool,
+        muxers: &[ProtocolName],
+    ) -> (NoiseHandshake, identity::Keypair) {
+        let keypair = noise::keypair_from_private(&[key; 32]).unwrap();
+        let handshake = if initiator {
+            Handshake::build_initiator_for_testing(keypair, &[ephemeral; 32])
+        } else {
+            Handshake::build_responder_for_testing(keypair, &[ephemeral; 32])
+        };
+        let handshake = NoiseHandshake::from(handshake.unwrap()).with_muxers(muxers);
+        (
+            handshake,
+            crate::identity::from_secret(&[identity; 32]).unwrap(),
+        )
+    }
+
+    #[test]
+    fn replay_handshake_snapshot() {
+        let messages = transcript(include_str!("../../../testdata/noise-xx-snapshot.txt"));
+        let (mut dialer, dialer_id) = peer(1, 3, 5, true, &[ProtocolName::YAMUX]);
+        let (mut listener, listener_id) = peer(2, 4, 6, false, &[ProtocolName::YAMUX]);
+
+        assert_eq!(dialer.write_hello().unwrap(), messages[0]);
+        listener.read_hello(&messages[0]).unwrap();
+        assert_eq!(listener.write_identity(&listener_id).unwrap(), messages[1]);
+        let remote = dialer.read_identity(&messages[1]).unwrap();
+        assert_eq!(remote, listener_id.public().to_peer_id());
+        assert_eq!(dialer.write_identity(&dialer_id).unwrap(), messages[2]);
+        let remote = listener.read_identity(&messages[2]).unwrap();
+        assert_eq!(remote, dialer_id.public().to_peer_id());
+        assert_eq!(dialer.negotiated_muxer(), Some(&ProtocolName::YAMUX));
+
+        let mut transport = Transport::try_from(dialer.into_inner()).unwrap();
+        assert_eq!(&transport.encrypt_frame(b"ping").unwrap()[2..], messages[3]);
+    }
+
+    #[test]
+    fn replay_independent_vector() {
+        let messages = transcript(include_str!("../../../testdata/noise-xx-vector.txt"));
+        let muxers = [ProtocolName::YAMUX, ProtocolName::MPLEX];
+        let (mut dialer, dialer_id) = peer(0x11, 0x13, 0x15, true, &muxers);
+        let (mut listener, listener_id) = peer(0x12, 0x14, 0x16, false, &muxers[1..]);
+
+        assert_eq!(dialer.write_hello().unwrap(), messages[0]);
+        listener.read_hello(&messages[0]).unwrap();
+        assert_eq!(listener.write_identity(&listener_id).unwrap(), messages[1]);
+        let remote = dialer.read_identity(&messages[1]).unwrap();
+        assert_eq!(remote, listener_id.public().to_peer_id());
+        assert_eq!(dialer.write_identity(&dialer_id).unwrap(), messages[2]);
+        let remote = listener.read_identity(&messages[2]).unwrap();
+        assert_eq!(remote, dialer_id.public().to_peer_id());
+        assert_eq!(dialer.negotiated_muxer(), Some(&ProtocolName::MPLEX));
+        assert_eq!(listener.negotiated_muxer(), Some(&ProtocolName::MPLEX));
+
+        let mut transport = Transport::try_from(dialer.into_inner()).unwrap();
+        assert_eq!(&transport.encrypt_frame(b"ping").unwrap()[2..], messages[3]);
+        let mut transport = Transport::try_from(listener.into_inner()).unwrap();
+        assert_eq!(&transport.encrypt_frame(b"pong").unwrap()[2..], messages[4]);
+    }
+}
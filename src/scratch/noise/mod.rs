@@ -1,17 +1,53 @@
 //! Implementation of the `noise-libp2p` protocol as specified
 //! in https://github.com/libp2p/specs/tree/master/noise
-use snow::{Builder, HandshakeState, TransportState};
+use bytes::BytesMut;
+use snow::error::{InitStage, StateProblem};
+use snow::params::DHChoice;
+use snow::resolvers::{CryptoResolver, DefaultResolver};
+use snow::{Builder, HandshakeState};
 
 use crate::error::PadawanError;
+use crate::scratch::multistream_select::framed::Decoder;
+use config::{HandshakePattern, NoiseConfig};
 
+pub mod cipher;
+pub mod config;
+pub mod keys;
 pub mod libp2p;
+pub mod pipes;
+pub mod pool;
+pub mod stream;
 pub mod wire;
 
 /// Reserved space for the size increase caused by encryption
 const ENCRYPTION_INFLATION_SIZE: usize = 1024;
 
-/// The supported noise handshake pattern
-static PATTERN: &str = "Noise_XX_25519_ChaChaPoly_SHA256";
+/// The size of the authentication tag appended to each encrypted message
+const TAG_SIZE: usize = 16;
+
+/// Generate a new static keypair for the noise handshake
+pub fn generate_keypair() -> Result<snow::Keypair, PadawanError> {
+    Ok(Builder::new(NoiseConfig::default().params()?).generate_keypair()?)
+}
+
+/// Create the static keypair for the noise handshake from its private key
+///
+/// # Errors
+///
+/// Fails if the private key is not a valid X25519 key.
+pub fn keypair_from_private(private: &[u8]) -> Result<snow::Keypair, PadawanError> {
+    let mut dh = DefaultResolver
+        .resolve_dh(&DHChoice::Curve25519)
+        .ok_or(snow::Error::Init(InitStage::GetDhImpl))?;
+    if private.len() != dh.priv_len() {
+        return Err(snow::Error::Input.into());
+    }
+    dh.set(private);
+    Ok(snow::Keypair {
+        private: private.to_vec(),
+        public: dh.pubkey().to_vec(),
+    })
+}
 
 /// Common behaviour exposed by [`snow`][] state abstractions.
 pub trait NoiseState {
@@ -43,38 +79,148 @@ impl NoiseState for HandshakeState {
     }
 }
 
-impl NoiseState for TransportState {
+/// The state of a handshake in progress, processed by [`snow`][] or, for the
+/// [`XXfallback`][`HandshakePattern::XxFallback`] pattern that it cannot build,
+/// by [`PipesState`][`pipes::PipesState`].
+pub enum PatternState {
+    Snow(Box<HandshakeState>),
+    Pipes(Box<pipes::PipesState>),
+}
+
+impl PatternState {
+    /// Whether the local peer sends the first message of the handshake
+    pub fn is_initiator(&self) -> bool {
+        match self {
+            Self::Snow(state) => state.is_initiator(),
+            Self::Pipes(state) => state.is_initiator(),
+        }
+    }
+
+    /// Whether the local peer sends the next message of the handshake
+    pub fn is_my_turn(&self) -> bool {
+        match self {
+            Self::Snow(state) => state.is_my_turn(),
+            Self::Pipes(state) => state.is_my_turn(),
+        }
+    }
+
+    /// Whether all the messages of the handshake have been exchanged
+    pub fn is_finished(&self) -> bool {
+        match self {
+            Self::Snow(state) => state.is_handshake_finished(),
+            Self::Pipes(state) => state.is_finished(),
+        }
+    }
+
+    /// Whether the handshake fell back to `XXfallback`
+    pub fn is_fallback(&self) -> bool {
+        matches!(self, Self::Pipes(state) if state.is_fallback())
+    }
+
+    /// Derive the keys of the initiator and the responder for the transport phase
+    fn split(&mut self) -> Result<([u8; cipher::KEY_SIZE], [u8; cipher::KEY_SIZE]), PadawanError> {
+        match self {
+            Self::Snow(state) if state.is_handshake_finished() => {
+                Ok(state.dangerously_get_raw_split())
+            }
+            Self::Snow(_) => Err(snow::Error::State(StateProblem::HandshakeNotFinished).into()),
+            Self::Pipes(state) => state.split(),
+        }
+    }
+}
+
+impl NoiseState for PatternState {
     fn remote_static(&self) -> Option<&[u8]> {
-        self.get_remote_static()
+        match self {
+            Self::Snow(state) => state.get_remote_static(),
+            Self::Pipes(state) => state.remote_static(),
+        }
     }
 
     fn decrypt(&mut self, encrypted: &[u8], plaintext: &mut [u8]) -> Result<usize, PadawanError> {
-        Ok(self.read_message(encrypted, plaintext)?)
+        match self {
+            Self::Snow(state) => state.decrypt(encrypted, plaintext),
+            Self::Pipes(state) => state.read_message(encrypted, plaintext),
+        }
     }
 
     fn encrypt(&mut self, plaintext: &[u8], encrypted: &mut [u8]) -> Result<usize, PadawanError> {
-        Ok(self.write_message(plaintext, encrypted)?)
+        match self {
+            Self::Snow(state) => state.encrypt(plaintext, encrypted),
+            Self::Pipes(state) => state.write_message(plaintext, encrypted),
+        }
+    }
+}
+
+/// The independent cipher states of the two directions of an established session
+pub struct CipherStates {
+    send: cipher::CipherState,
+    receive: cipher::CipherState,
+    remote_static: Option<Vec<u8>>,
+}
+
+impl CipherStates {
+    /// Split a finished handshake into the cipher states of the given `config`
+    fn new(state: &mut PatternState, config: &NoiseConfig) -> Result<Self, PadawanError> {
+        let (initiator, responder) = state.split()?;
+        let (send, receive) = if state.is_initiator() {
+            (initiator, responder)
+        } else {
+            (responder, initiator)
+        };
+        let cipher_state = |key| {
+            cipher::CipherState::with_cipher(key, config.cipher())
+                .with_rekey_policy(config.rekey_policy())
+        };
+        Ok(Self {
+            send: cipher_state(&send),
+            receive: cipher_state(&receive),
+            remote_static: state.remote_static().map(<[u8]>::to_vec),
+        })
+    }
+}
+
+impl NoiseState for CipherStates {
+    fn remote_static(&self) -> Option<&[u8]> {
+        self.remote_static.as_deref()
+    }
+
+    fn decrypt(&mut self, encrypted: &[u8], plaintext: &mut [u8]) -> Result<usize, PadawanError> {
+        self.receive.decrypt(encrypted, plaintext)
+    }
+
+    fn encrypt(&mut self, plaintext: &[u8], encrypted: &mut [u8]) -> Result<usize, PadawanError> {
+        self.send.encrypt(plaintext, encrypted)
     }
 }
 
 /// Encapsulate buffers for read, write, and encrypted data.
+///
+/// The buffers retain their capacity across messages, so that they are
+/// allocated once per connection. Decrypted data that carry `multistream_select`
+/// messages are accumulated separately, since messages may span or share frames.
 #[derive(Debug, Default, Clone)]
 pub struct Buffer {
-    read: Vec<u8>,
-    write: Vec<u8>,
-    encrypted: Vec<u8>,
+    read: BytesMut,
+    write: BytesMut,
+    encrypted: BytesMut,
+    messages: Decoder,
 }
 
 impl Buffer {
-    pub fn read(&mut self) -> &mut Vec<u8> {
+    pub fn messages(&mut self) -> &mut Decoder {
+        &mut self.messages
+    }
+
+    pub fn read(&mut self) -> &mut BytesMut {
         &mut self.read
     }
 
-    pub fn write(&mut self) -> &mut Vec<u8> {
+    pub fn write(&mut self) -> &mut BytesMut {
         &mut self.write
     }
 
-    pub fn encrypted(&mut self) -> &mut Vec<u8> {
+    pub fn encrypted(&mut self) -> &mut BytesMut {
         &mut self.encrypted
     }
 }
@@ -85,6 +231,7 @@ pub struct StatefulBuf<T: NoiseState> {
     state: T,
     buffer: Buffer,
     keypair: snow::Keypair,
+    config: NoiseConfig,
 }
 
 impl<T: NoiseState> StatefulBuf<T> {
@@ -101,7 +248,7 @@ impl<T: NoiseState> StatefulBuf<T> {
     /// Decrypt encrypted data from the internal buffer.
     ///
     /// Returns a mutable reference to the decrypted data.
-    pub fn decrypt(&mut self) -> Result<&mut Vec<u8>, PadawanError> {
+    pub fn decrypt(&mut self) -> Result<&mut BytesMut, PadawanError> {
         self.buffer.read.resize(self.buffer.encrypted.len(), 0);
         let n = self
             .state
@@ -113,7 +260,7 @@ impl<T: NoiseState> StatefulBuf<T> {
     /// Encrypt the data of the internal write buffer.
     ///
     /// Returns a mutable reference to the encrypted data.
-    pub fn encrypt(&mut self) -> Result<&mut Vec<u8>, PadawanError> {
+    pub fn encrypt(&mut self) -> Result<&mut BytesMut, PadawanError> {
         self.buffer
             .encrypted
             .resize(self.buffer.write.len() + ENCRYPTION_INFLATION_SIZE, 0);
@@ -128,56 +275,236 @@ impl<T: NoiseState> StatefulBuf<T> {
     pub fn buffer(&mut self) -> &mut Buffer {
         &mut self.buffer
     }
+
+    /// The noise protocol of the session
+    pub fn config(&self) -> &NoiseConfig {
+        &self.config
+    }
 }
 
 /// The stateful buffer to be used in the handshake phase
-pub type Handshake = StatefulBuf<HandshakeState>;
+pub type Handshake = StatefulBuf<PatternState>;
 
 impl Handshake {
     /// Build a handshake for the side that will respond to the first message
     pub fn build_responder() -> Result<Self, PadawanError> {
-        let builder = Builder::new(PATTERN.parse()?);
-        let keypair = builder.generate_keypair()?;
-        let state = builder
-            .local_private_key(&keypair.private)
-            .build_responder()?;
-        Ok(Self {
-            state,
-            buffer: Default::default(),
-            keypair,
-        })
+        Self::build_responder_with_keypair(generate_keypair()?)
     }
 
     /// Build a handshake for the side that will send the first message
     pub fn build_initiator() -> Result<Self, PadawanError> {
-        let builder = Builder::new(PATTERN.parse()?);
-        let keypair = builder.generate_keypair()?;
-        let state = builder
-            .local_private_key(&keypair.private)
-            .build_initiator()?;
+        Self::build_initiator_with_keypair(generate_keypair()?)
+    }
+
+    /// Build a handshake for the side that will respond to the first message,
+    /// authenticated with the given static keypair
+    pub fn build_responder_with_keypair(keypair: snow::Keypair) -> Result<Self, PadawanError> {
+        Self::build_responder_with_config(keypair, Default::default())
+    }
+
+    /// Build a handshake for the side that will send the first message,
+    /// authenticated with the given static keypair
+    pub fn build_initiator_with_keypair(keypair: snow::Keypair) -> Result<Self, PadawanError> {
+        Self::build_initiator_with_config(keypair, Default::default())
+    }
+
+    /// Build a handshake for the side that will respond to the first message,
+    /// speaking the noise protocol of the given `config`
+    pub fn build_responder_with_config(
+        keypair: snow::Keypair,
+        config: NoiseConfig,
+    ) -> Result<Self, PadawanError> {
+        Self::build(keypair, config, None, false)
+    }
+
+    /// Build a handshake for the side that will send the first message,
+    /// speaking the noise protocol of the given `config`
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`PadawanError::MissingRemoteNoiseKey`][] if the pattern
+    /// requires the static key of the remote peer, but none is configured.
+    pub fn build_initiator_with_config(
+        keypair: snow::Keypair,
+        config: NoiseConfig,
+    ) -> Result<Self, PadawanError> {
+        Self::build(keypair, config, None, true)
+    }
+
+    /// Build a responder handshake with a fixed ephemeral private key, so that
+    /// its messages can be reproduced byte-for-byte.
+    ///
+    /// This defeats the forward secrecy of the session, so it is only built for tests.
+    #[cfg(test)]
+    pub(crate) fn build_responder_for_testing(
+        keypair: snow::Keypair,
+        ephemeral: &[u8],
+    ) -> Result<Self, PadawanError> {
+        Self::build(keypair, Default::default(), Some(ephemeral), false)
+    }
+
+    /// Build an initiator handshake with a fixed ephemeral private key, so that
+    /// its messages can be reproduced byte-for-byte.
+    ///
+    /// This defeats the forward secrecy of the session, so it is only built for tests.
+    #[cfg(test)]
+    pub(crate) fn build_initiator_for_testing(
+        keypair: snow::Keypair,
+        ephemeral: &[u8],
+    ) -> Result<Self, PadawanError> {
+        Self::build(keypair, Default::default(), Some(ephemeral), true)
+    }
+
+    fn build(
+        keypair: snow::Keypair,
+        config: NoiseConfig,
+        ephemeral: Option<&[u8]>,
+        initiator: bool,
+    ) -> Result<Self, PadawanError> {
+        if config.pattern() == HandshakePattern::XxFallback {
+            let state = if initiator {
+                pipes::PipesState::initiator(&keypair, config.clone())?
+            } else {
+                pipes::PipesState::responder(&keypair, config.clone())?
+            };
+            return Ok(Self {
+                state: PatternState::Pipes(Box::new(state)),
+                buffer: Default::default(),
+                keypair,
+                config,
+            });
+        }
+        let params = config.params()?;
+        let mut builder = Builder::new(params).local_private_key(&keypair.private);
+        if let Some(ephemeral) = ephemeral {
+            builder = builder.fixed_ephemeral_key_for_testing_only(ephemeral);
+        }
+        if initiator && config.pattern() == HandshakePattern::Ik {
+            let remote = config
+                .remote_static()
+                .ok_or(PadawanError::MissingRemoteNoiseKey)?;
+            builder = builder.remote_public_key(remote);
+        }
+        let state = if initiator {
+            builder.build_initiator()?
+        } else {
+            builder.build_responder()?
+        };
         Ok(Self {
-            state,
+            state: PatternState::Snow(Box::new(state)),
             buffer: Default::default(),
             keypair,
+            config,
         })
     }
+
+    /// Whether the local peer sends the first message of the handshake
+    pub fn is_initiator(&self) -> bool {
+        self.state.is_initiator()
+    }
+
+    /// Whether the local peer sends the next message of the handshake
+    pub fn is_my_turn(&self) -> bool {
+        self.state.is_my_turn()
+    }
+
+    /// Whether all the messages of the handshake have been exchanged
+    pub fn is_finished(&self) -> bool {
+        self.state.is_finished()
+    }
+
+    /// Whether the handshake fell back from `IK` to `XXfallback`
+    pub fn is_fallback(&self) -> bool {
+        self.state.is_fallback()
+    }
 }
 
 /// The stateful buffer to be used in the transport phase
-pub type Transport = StatefulBuf<TransportState>;
+pub type Transport = StatefulBuf<CipherStates>;
 
 impl TryFrom<Handshake> for Transport {
     type Error = PadawanError;
 
-    fn try_from(handshake: Handshake) -> Result<Self, Self::Error> {
+    fn try_from(mut handshake: Handshake) -> Result<Self, Self::Error> {
         Ok(Self {
-            state: handshake.state.try_into()?,
+            state: CipherStates::new(&mut handshake.state, &handshake.config)?,
             buffer: handshake.buffer,
             keypair: handshake.keypair,
+            config: handshake.config,
         })
     }
 }
 
+impl Transport {
+    /// Encrypt the `message` in place as a noise frame, using the internal
+    /// buffer for encrypted data.
+    ///
+    /// Returns a reference to the frame, including its length prefix.
+    pub fn encrypt_frame(&mut self, message: &[u8]) -> Result<&[u8], PadawanError> {
+        let frame = &mut self.buffer.encrypted;
+        frame.clear();
+        wire::encrypt_frame(&mut self.state.send, message, frame)?;
+        Ok(frame)
+    }
+
+    /// Decrypt the payload of a noise frame from the internal buffer for
+    /// encrypted data in place, and queue it for decoding as [`messages`][`Buffer::messages`].
+    pub fn decrypt_frame(&mut self) -> Result<(), PadawanError> {
+        let payload = &mut self.buffer.encrypted;
+        self.state.receive.decrypt_in_place(payload)?;
+        self.buffer.messages.extend(payload);
+        payload.clear();
+        Ok(())
+    }
+
+    /// Split the transport into its receiving and sending halves,
+    /// that can be used independently, e.g. from separate tasks.
+    pub fn split(self) -> (TransportReceiver, TransportSender) {
+        let receiver = TransportReceiver {
+            cipher: self.state.receive,
+            messages: self.buffer.messages,
+        };
+        let sender = TransportSender {
+            cipher: self.state.send,
+        };
+        (receiver, sender)
+    }
+}
+
+/// The receiving half of a [`Transport`][]
+pub struct TransportReceiver {
+    cipher: cipher::CipherState,
+    messages: Decoder,
+}
+
+impl TransportReceiver {
+    /// Decrypt the payload of a noise frame in place
+    pub fn decrypt(&mut self, payload: &mut BytesMut) -> Result<(), PadawanError> {
+        self.cipher.decrypt_in_place(payload)
+    }
+
+    /// Decrypted data received during the handshake but not yet consumed
+    pub fn messages(&mut self) -> &mut Decoder {
+        &mut self.messages
+    }
+}
+
+/// The sending half of a [`Transport`][]
+pub struct TransportSender {
+    cipher: cipher::CipherState,
+}
+
+impl TransportSender {
+    /// Append a noise frame carrying the encrypted `plaintext` to the `out` buffer
+    pub fn encrypt_frame(
+        &mut self,
+        plaintext: &[u8],
+        out: &mut BytesMut,
+    ) -> Result<(), PadawanError> {
+        wire::encrypt_frame(&mut self.cipher, plaintext, out)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -186,4 +513,200 @@ mod tests {
     fn build_initiator() {
         assert!(Handshake::build_initiator().is_ok());
     }
+
+    #[test]
+    fn derive_public_key() {
+        let keypair = generate_keypair().unwrap();
+        let derived = keypair_from_private(&keypair.private).unwrap();
+        assert_eq!(derived.public, keypair.public);
+        assert!(keypair_from_private(&[0; 16]).is_err());
+    }
+
+    fn snow_state(state: PatternState) -> HandshakeState {
+        match state {
+            PatternState::Snow(state) => *state,
+            PatternState::Pipes(_) => panic!("expected a snow handshake"),
+        }
+    }
+
+    /// Exchange the messages of a handshake until both peers are finished
+    fn complete(initiator: &mut Handshake, responder: &mut dyn NoiseState) {
+        let mut message = [0; 1024];
+        let mut payload = [0; 1024];
+        while !initiator.is_finished() {
+            let (writer, reader): (&mut dyn NoiseState, &mut dyn NoiseState) =
+                if initiator.is_my_turn() {
+                    (&mut initiator.state, responder)
+                } else {
+                    (responder, &mut initiator.state)
+                };
+            let n = writer.encrypt(&[], &mut message).unwrap();
+            reader.decrypt(&message[..n], &mut payload).unwrap();
+        }
+    }
+
+    /// Complete a handshake of the given protocol between a local initiator
+    /// and a `snow` responder
+    fn handshake(config: NoiseConfig) -> (Handshake, HandshakeState) {
+        let keypair = generate_keypair().unwrap();
+        let config = config.with_remote_static(&keypair.public);
+        let mut responder = snow_state(
+            Handshake::build_responder_with_config(keypair, config.clone())
+                .unwrap()
+                .state,
+        );
+        let mut initiator =
+            Handshake::build_initiator_with_config(generate_keypair().unwrap(), config).unwrap();
+        complete(&mut initiator, &mut responder);
+        (initiator, responder)
+    }
+
+    #[test]
+    fn transport_interoperates_with_snow() {
+        let (initiator, responder) = handshake(Default::default());
+        let (mut receiver, mut sender) = Transport::try_from(initiator).unwrap().split();
+        let mut remote = responder.into_transport_mode().unwrap();
+        let mut buffer = [0; 1024];
+
+        let mut frame = BytesMut::new();
+        sender.encrypt_frame(b"ping", &mut frame).unwrap();
+        let n = remote.read_message(&frame[2..], &mut buffer).unwrap();
+        assert_eq!(&buffer[..n], b"ping");
+
+        let mut encrypted = [0; 1024];
+        let n = remote.write_message(b"pong", &mut encrypted).unwrap();
+        let mut payload = BytesMut::from(&encrypted[..n]);
+        receiver.decrypt(&mut payload).unwrap();
+        assert_eq!(&payload[..], b"pong");
+    }
+
+    #[test]
+    fn configured_protocols_interoperate_with_snow() {
+        use config::{Cipher, Hash};
+
+        for pattern in [HandshakePattern::Xx, HandshakePattern::Ik] {
+            for cipher in [Cipher::ChaChaPoly, Cipher::AesGcm] {
+                for hash in [Hash::Sha256, Hash::Blake2s, Hash::Blake2b] {
+                    let config = NoiseConfig::default()
+                        .with_pattern(pattern)
+                        .with_cipher(cipher)
+                        .with_hash(hash);
+                    let (initiator, responder) = handshake(config);
+                    let mut transport = Transport::try_from(initiator).unwrap();
+                    let mut remote = responder.into_transport_mode().unwrap();
+                    let mut buffer = [0; 1024];
+                    for _ in 0..2 {
+                        let frame = transport.encrypt_frame(b"ping").unwrap();
+                        let n = remote.read_message(&frame[2..], &mut buffer).unwrap();
+                        assert_eq!(&buffer[..n], b"ping");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rekey_interoperates_with_snow() {
+        let policy = cipher::RekeyPolicy::default().after_messages(1);
+        let (initiator, responder) = handshake(NoiseConfig::default().with_rekey_policy(policy));
+        let mut transport = Transport::try_from(initiator).unwrap();
+        let mut remote = responder.into_transport_mode().unwrap();
+        let mut buffer = [0; 1024];
+        for _ in 0..3 {
+            let frame = transport.encrypt_frame(b"ping").unwrap();
+            let n = remote.read_message(&frame[2..], &mut buffer).unwrap();
+            assert_eq!(&buffer[..n], b"ping");
+            remote.rekey_incoming();
+        }
+    }
+
+    #[test]
+    fn initiator_requires_remote_static_for_ik() {
+        let config = NoiseConfig::default().with_pattern(HandshakePattern::Ik);
+        let keypair = generate_keypair().unwrap();
+        assert!(matches!(
+            Handshake::build_initiator_with_config(keypair, config),
+            Err(PadawanError::MissingRemoteNoiseKey)
+        ));
+    }
+
+    #[test]
+    fn pipes_interoperate_with_snow_ik() {
+        let keypair = generate_keypair().unwrap();
+        let config = NoiseConfig::default().with_remote_static(&keypair.public);
+        let mut responder = snow_state(
+            Handshake::build_responder_with_config(
+                keypair,
+                config.clone().with_pattern(HandshakePattern::Ik),
+            )
+            .unwrap()
+            .state,
+        );
+        let mut initiator = Handshake::build_initiator_with_config(
+            generate_keypair().unwrap(),
+            config.with_pattern(HandshakePattern::XxFallback),
+        )
+        .unwrap();
+        complete(&mut initiator, &mut responder);
+        assert!(!initiator.is_fallback());
+
+        let mut transport = Transport::try_from(initiator).unwrap();
+        let mut remote = responder.into_transport_mode().unwrap();
+        let mut buffer = [0; 1024];
+        let frame = transport.encrypt_frame(b"ping").unwrap();
+        let n = remote.read_message(&frame[2..], &mut buffer).unwrap();
+        assert_eq!(&buffer[..n], b"ping");
+    }
+
+    #[test]
+    fn fall_back_on_outdated_remote_static() {
+        use config::{Cipher, Hash};
+
+        for cipher in [Cipher::ChaChaPoly, Cipher::AesGcm] {
+            for hash in [Hash::Sha256, Hash::Blake2b] {
+                let config = NoiseConfig::default()
+                    .with_pattern(HandshakePattern::XxFallback)
+                    .with_cipher(cipher)
+                    .with_hash(hash);
+                let (keypair, outdated) =
+                    (generate_keypair().unwrap(), generate_keypair().unwrap());
+                let mut responder =
+                    Handshake::build_responder_with_config(keypair, config.clone()).unwrap();
+                let mut initiator = Handshake::build_initiator_with_config(
+                    generate_keypair().unwrap(),
+                    config.with_remote_static(&outdated.public),
+                )
+                .unwrap();
+                complete(&mut initiator, &mut responder.state);
+                assert!(initiator.is_fallback() && responder.is_fallback());
+                assert!(responder.is_finished());
+                assert_eq!(initiator.remote_static(), Some(responder.local_static()));
+                assert_eq!(responder.remote_static(), Some(initiator.local_static()));
+
+                let (mut receiver, mut sender) = Transport::try_from(initiator).unwrap().split();
+                let (mut remote_receiver, mut remote_sender) =
+                    Transport::try_from(responder).unwrap().split();
+                for (sender, receiver) in [
+                    (&mut sender, &mut remote_receiver),
+                    (&mut remote_sender, &mut receiver),
+                ] {
+                    let mut frame = BytesMut::new();
+                    sender.encrypt_frame(b"ping", &mut frame).unwrap();
+                    let mut payload = frame.split_off(2);
+                    receiver.decrypt(&mut payload).unwrap();
+                    assert_eq!(&payload[..], b"ping");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn initiator_requires_remote_static_for_xx_fallback() {
+        let config = NoiseConfig::default().with_pattern(HandshakePattern::XxFallback);
+        let keypair = generate_keypair().unwrap();
+        assert!(matches!(
+            Handshake::build_initiator_with_config(keypair, config),
+            Err(PadawanError::MissingRemoteNoiseKey)
+        ));
+    }
 }
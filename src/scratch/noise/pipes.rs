@@ -0,0 +1,426 @@
+//! Noise Pipes: an `IK` handshake that falls back to `XXfallback` if the responder
+//! cannot decrypt the initial message, e.g. since the initiator knows an outdated
+//! static key of the responder.
+//!
+//! [`snow`][] cannot build either side of `XXfallback`, since it neither accepts the
+//! ephemeral key of the remote peer nor exposes its own, so both patterns are
+//! processed here with the primitives of its default resolver and the [`CipherState`][].
+//!
+//! The fallback is not signalled on the wire. Each peer attempts the message it
+//! expects, and falls back once it cannot decrypt it. The initiator of `IK` remains
+//! the initiator of `XXfallback`, e.g. to split the keys of the transport.
+use snow::error::{InitStage, StateProblem};
+use snow::params::{DHChoice, HashChoice};
+use snow::resolvers::{CryptoResolver, DefaultResolver};
+use snow::types::Hash;
+
+use super::cipher::{CipherState, KEY_SIZE};
+use super::config::{Cipher, HandshakePattern, NoiseConfig};
+use super::TAG_SIZE;
+use crate::error::PadawanError;
+
+/// The size of X25519 keys
+const DH_SIZE: usize = 32;
+
+/// The maximum output size of the hash functions
+const MAX_HASH_SIZE: usize = 64;
+
+/// The tokens of the handshake messages, as named by the noise specification
+#[derive(Debug, Clone, Copy)]
+enum Token {
+    E,
+    S,
+    Ee,
+    Es,
+    Se,
+    Ss,
+}
+
+use Token::*;
+
+/// The messages of `IK`, following the pre-message `<- s`:
+/// `-> e, es, s, ss` and `<- e, ee, se`
+const IK: &[&[Token]] = &[&[E, Es, S, Ss], &[E, Ee, Se]];
+
+/// The messages of `XXfallback`, following the pre-message `-> e`:
+/// `<- e, ee, s, es` and `-> s, se`
+const XX_FALLBACK: &[&[Token]] = &[&[E, Ee, S, Es], &[S, Se]];
+
+/// A keypair of the handshake, that is cloned along with its state unlike [`snow::Keypair`][]
+#[derive(Clone)]
+struct Keypair {
+    private: Vec<u8>,
+    public: Vec<u8>,
+}
+
+impl From<&snow::Keypair> for Keypair {
+    fn from(keypair: &snow::Keypair) -> Self {
+        Self {
+            private: keypair.private.clone(),
+            public: keypair.public.clone(),
+        }
+    }
+}
+
+/// The chaining key and the hash of the handshake, along with the key derived so far
+#[derive(Clone)]
+struct SymmetricState {
+    hash: HashChoice,
+    cipher: Cipher,
+    ck: Vec<u8>,
+    h: Vec<u8>,
+    key: Option<CipherState>,
+}
+
+impl SymmetricState {
+    /// Initialize the state of the given protocol, with an empty prologue
+    fn new(config: &NoiseConfig) -> Result<Self, PadawanError> {
+        let hash = config.params()?.hash;
+        let name = config.to_string();
+        let mut hasher = hasher(hash)?;
+        let mut h = vec![0; hasher.hash_len()];
+        if name.len() <= h.len() {
+            h[..name.len()].copy_from_slice(name.as_bytes());
+        } else {
+            hasher.input(name.as_bytes());
+            hasher.result(&mut h);
+        }
+        let mut state = Self {
+            hash,
+            cipher: config.cipher(),
+            ck: h.clone(),
+            h,
+            key: None,
+        };
+        state.mix_hash(&[])?;
+        Ok(state)
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) -> Result<(), PadawanError> {
+        let mut hasher = hasher(self.hash)?;
+        hasher.input(&self.h);
+        hasher.input(data);
+        hasher.result(&mut self.h);
+        Ok(())
+    }
+
+    fn mix_key(&mut self, material: &[u8]) -> Result<(), PadawanError> {
+        let (ck, key) = self.hkdf(material)?;
+        self.ck = ck;
+        self.key = Some(CipherState::with_cipher(&cipher_key(&key), self.cipher));
+        Ok(())
+    }
+
+    /// Derive two outputs from the chaining key and the given key `material`
+    fn hkdf(&self, material: &[u8]) -> Result<(Vec<u8>, Vec<u8>), PadawanError> {
+        let mut hasher = hasher(self.hash)?;
+        let len = hasher.hash_len();
+        let (mut first, mut second) = ([0; MAX_HASH_SIZE], [0; MAX_HASH_SIZE]);
+        hasher.hkdf(&self.ck, material, 2, &mut first, &mut second, &mut []);
+        Ok((first[..len].to_vec(), second[..len].to_vec()))
+    }
+
+    /// Append the encryption of the `plaintext` to the `message`, once a key is derived
+    fn encrypt_and_hash(
+        &mut self,
+        plaintext: &[u8],
+        message: &mut Vec<u8>,
+    ) -> Result<(), PadawanError> {
+        let start = message.len();
+        match &mut self.key {
+            Some(key) => {
+                message.resize(start + plaintext.len() + TAG_SIZE, 0);
+                key.encrypt_with_ad(&self.h, plaintext, &mut message[start..])?;
+            }
+            None => message.extend_from_slice(plaintext),
+        }
+        self.mix_hash(&message[start..])
+    }
+
+    /// Decrypt the `ciphertext`, once a key is derived
+    fn decrypt_and_hash(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, PadawanError> {
+        let plaintext = match &mut self.key {
+            Some(key) => {
+                let mut plaintext = vec![0; ciphertext.len().saturating_sub(TAG_SIZE)];
+                key.decrypt_with_ad(&self.h, ciphertext, &mut plaintext)?;
+                plaintext
+            }
+            None => ciphertext.to_vec(),
+        };
+        self.mix_hash(ciphertext)?;
+        Ok(plaintext)
+    }
+
+    /// The size of an encrypted static key
+    fn static_size(&self) -> usize {
+        match self.key {
+            Some(_) => DH_SIZE + TAG_SIZE,
+            None => DH_SIZE,
+        }
+    }
+}
+
+/// The state of a Noise Pipes handshake
+#[derive(Clone)]
+pub struct PipesState {
+    config: NoiseConfig,
+    symmetric: SymmetricState,
+    initiator: bool,
+    s: Keypair,
+    e: Option<Keypair>,
+    rs: Option<Vec<u8>>,
+    re: Option<Vec<u8>>,
+    /// Whether the handshake fell back to `XXfallback`
+    fallback: bool,
+    /// The number of messages of the current pattern exchanged so far
+    messages: usize,
+}
+
+impl PipesState {
+    /// Build the state of the initiator, that knows the static key of the responder
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`PadawanError::MissingRemoteNoiseKey`][] if the `config`
+    /// has no static key of the remote peer.
+    pub fn initiator(keypair: &snow::Keypair, config: NoiseConfig) -> Result<Self, PadawanError> {
+        let remote = config
+            .remote_static()
+            .ok_or(PadawanError::MissingRemoteNoiseKey)?
+            .to_vec();
+        Self::new(keypair, config, Some(remote))
+    }
+
+    /// Build the state of the responder
+    pub fn responder(keypair: &snow::Keypair, config: NoiseConfig) -> Result<Self, PadawanError> {
+        Self::new(keypair, config, None)
+    }
+
+    fn new(
+        keypair: &snow::Keypair,
+        config: NoiseConfig,
+        rs: Option<Vec<u8>>,
+    ) -> Result<Self, PadawanError> {
+        let mut symmetric =
+            SymmetricState::new(&config.clone().with_pattern(HandshakePattern::Ik))?;
+        // The pre-message of the static key of the responder
+        symmetric.mix_hash(rs.as_deref().unwrap_or(&keypair.public))?;
+        Ok(Self {
+            config,
+            symmetric,
+            initiator: rs.is_some(),
+            s: keypair.into(),
+            e: None,
+            rs,
+            re: None,
+            fallback: false,
+            messages: 0,
+        })
+    }
+
+    /// Whether the local peer initiated the handshake
+    pub fn is_initiator(&self) -> bool {
+        self.initiator
+    }
+
+    /// Whether the handshake fell back to `XXfallback`
+    pub fn is_fallback(&self) -> bool {
+        self.fallback
+    }
+
+    /// Whether the local peer sends the next message of the handshake.
+    ///
+    /// The initiator sends the first message of `IK`,
+    /// and the responder the first message of `XXfallback`.
+    pub fn is_my_turn(&self) -> bool {
+        let sends_first = self.initiator != self.fallback;
+        !self.is_finished() && (self.messages % 2 == 1) != sends_first
+    }
+
+    /// Whether all the messages of the handshake have been exchanged
+    pub fn is_finished(&self) -> bool {
+        self.messages == self.pattern().len()
+    }
+
+    /// The static key of the remote peer, once received
+    pub fn remote_static(&self) -> Option<&[u8]> {
+        self.rs.as_deref()
+    }
+
+    /// Write the next message of the local peer, carrying the given `payload`, into `out`.
+    ///
+    /// Returns the size of the message.
+    pub fn write_message(&mut self, payload: &[u8], out: &mut [u8]) -> Result<usize, PadawanError> {
+        if !self.is_my_turn() {
+            return Err(snow::Error::State(StateProblem::NotTurnToWrite).into());
+        }
+        let mut message = Vec::new();
+        for token in self.pattern()[self.messages] {
+            match token {
+                E => {
+                    let e = Keypair::from(&super::generate_keypair()?);
+                    message.extend_from_slice(&e.public);
+                    self.symmetric.mix_hash(&e.public)?;
+                    self.e = Some(e);
+                }
+                S => self
+                    .symmetric
+                    .encrypt_and_hash(&self.s.public, &mut message)?,
+                dh => self.mix_dh(*dh)?,
+            }
+        }
+        self.symmetric.encrypt_and_hash(payload, &mut message)?;
+        self.messages += 1;
+        out.get_mut(..message.len())
+            .ok_or(snow::Error::Input)?
+            .copy_from_slice(&message);
+        Ok(message.len())
+    }
+
+    /// Read the next message of the remote peer, and write its payload into `out`.
+    ///
+    /// The responder falls back if it cannot decrypt the initial `IK` message,
+    /// whose payload is then lost. The initiator falls back if it cannot decrypt
+    /// the response as the final `IK` message.
+    ///
+    /// Returns the size of the payload.
+    pub fn read_message(&mut self, message: &[u8], out: &mut [u8]) -> Result<usize, PadawanError> {
+        if self.is_finished() || self.is_my_turn() {
+            return Err(snow::Error::State(StateProblem::NotTurnToRead).into());
+        }
+        if self.fallback {
+            return self.read_tokens(message, out);
+        }
+        let mut attempt = self.clone();
+        match attempt.read_tokens(message, out) {
+            Ok(n) => {
+                *self = attempt;
+                Ok(n)
+            }
+            Err(err) if self.initiator => {
+                tracing::debug!("Falling back to XXfallback after {}", err);
+                let e = self.e.as_ref().ok_or_else(missing_keys)?.public.clone();
+                self.fall_back(e)?;
+                self.read_tokens(message, out)
+            }
+            Err(err) => {
+                tracing::debug!("Falling back to XXfallback after {}", err);
+                let e = message.get(..DH_SIZE).ok_or(snow::Error::Input)?.to_vec();
+                self.fall_back(e)?;
+                Ok(0)
+            }
+        }
+    }
+
+    /// Derive the keys of the initiator and the responder for the transport phase
+    pub fn split(&self) -> Result<([u8; KEY_SIZE], [u8; KEY_SIZE]), PadawanError> {
+        if !self.is_finished() {
+            return Err(snow::Error::State(StateProblem::HandshakeNotFinished).into());
+        }
+        let (initiator, responder) = self.symmetric.hkdf(&[])?;
+        Ok((cipher_key(&initiator), cipher_key(&responder)))
+    }
+
+    fn pattern(&self) -> &'static [&'static [Token]] {
+        if self.fallback {
+            XX_FALLBACK
+        } else {
+            IK
+        }
+    }
+
+    /// Restart the handshake as `XXfallback`, following the pre-message
+    /// of the ephemeral key `e` of the initiator
+    fn fall_back(&mut self, e: Vec<u8>) -> Result<(), PadawanError> {
+        self.symmetric = SymmetricState::new(&self.config)?;
+        self.symmetric.mix_hash(&e)?;
+        if !self.initiator {
+            self.re = Some(e);
+        }
+        self.rs = None;
+        self.fallback = true;
+        self.messages = 0;
+        Ok(())
+    }
+
+    fn read_tokens(&mut self, message: &[u8], out: &mut [u8]) -> Result<usize, PadawanError> {
+        let mut rest = message;
+        for token in self.pattern()[self.messages] {
+            match token {
+                E => {
+                    let (e, tail) = split_at(rest, DH_SIZE)?;
+                    self.symmetric.mix_hash(e)?;
+                    self.re = Some(e.to_vec());
+                    rest = tail;
+                }
+                S => {
+                    let (s, tail) = split_at(rest, self.symmetric.static_size())?;
+                    self.rs = Some(self.symmetric.decrypt_and_hash(s)?);
+                    rest = tail;
+                }
+                dh => self.mix_dh(*dh)?,
+            }
+        }
+        let payload = self.symmetric.decrypt_and_hash(rest)?;
+        self.messages += 1;
+        out.get_mut(..payload.len())
+            .ok_or(snow::Error::Input)?
+            .copy_from_slice(&payload);
+        Ok(payload.len())
+    }
+
+    /// Mix the result of the key exchange of the given token into the chaining key
+    fn mix_dh(&mut self, token: Token) -> Result<(), PadawanError> {
+        let e = self.e.as_ref().map(|e| &e.private[..]);
+        let s = Some(&self.s.private[..]);
+        let (local, remote) = match (token, self.initiator) {
+            (Ee, _) => (e, &self.re),
+            (Es, true) | (Se, false) => (e, &self.rs),
+            (Es, false) | (Se, true) => (s, &self.re),
+            (Ss, _) => (s, &self.rs),
+            (E | S, _) => unreachable!("only called for key exchange tokens"),
+        };
+        let local = local.ok_or_else(missing_keys)?;
+        let remote = remote.as_deref().ok_or_else(missing_keys)?;
+        let shared = dh(local, remote)?;
+        self.symmetric.mix_key(&shared)
+    }
+}
+
+fn hasher(hash: HashChoice) -> Result<Box<dyn Hash>, PadawanError> {
+    DefaultResolver
+        .resolve_hash(&hash)
+        .ok_or_else(|| snow::Error::Init(InitStage::GetHashImpl).into())
+}
+
+/// The X25519 key exchange between a local private key and a remote public key
+fn dh(private: &[u8], public: &[u8]) -> Result<[u8; DH_SIZE], PadawanError> {
+    let mut dh = DefaultResolver
+        .resolve_dh(&DHChoice::Curve25519)
+        .ok_or(snow::Error::Init(InitStage::GetDhImpl))?;
+    if public.len() != DH_SIZE {
+        return Err(snow::Error::Input.into());
+    }
+    dh.set(private);
+    let mut shared = [0; DH_SIZE];
+    dh.dh(public, &mut shared)?;
+    Ok(shared)
+}
+
+/// The cipher key of a hash output, truncated as per the noise specification
+fn cipher_key(output: &[u8]) -> [u8; KEY_SIZE] {
+    let mut key = [0; KEY_SIZE];
+    key.copy_from_slice(&output[..KEY_SIZE]);
+    key
+}
+
+fn split_at(message: &[u8], n: usize) -> Result<(&[u8], &[u8]), PadawanError> {
+    if message.len() < n {
+        return Err(snow::Error::Input.into());
+    }
+    Ok(message.split_at(n))
+}
+
+fn missing_keys() -> snow::Error {
+    snow::Error::State(StateProblem::MissingKeyMaterial)
+}
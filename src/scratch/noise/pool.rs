@@ -0,0 +1,87 @@
+//! A pool of reusable buffers, shared across connections.
+//!
+//! Each secure stream holds a buffer per direction for as long as it is open.
+//! Returning them to a pool on close lets subsequent connections reuse the
+//! allocations instead of growing new buffers frame by frame.
+use std::sync::{Arc, Mutex};
+
+use bytes::BytesMut;
+
+use super::wire;
+
+/// The capacity of the buffers handed out by the pool, fitting a complete frame
+pub const BUFFER_CAPACITY: usize = wire::MAX_FRAME_SIZE + 2;
+
+/// The default number of idle buffers retained by a pool
+const DEFAULT_MAX_IDLE: usize = 256;
+
+/// A cheaply cloneable handle to a pool of buffers
+#[derive(Debug, Clone)]
+pub struct BufferPool {
+    idle: Arc<Mutex<Vec<BytesMut>>>,
+    max_idle: usize,
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_IDLE)
+    }
+}
+
+impl BufferPool {
+    /// Create a new pool retaining up to `max_idle` buffers
+    pub fn new(max_idle: usize) -> Self {
+        Self {
+            idle: Default::default(),
+            max_idle,
+        }
+    }
+
+    /// Take an empty buffer from the pool, or allocate a new one if none is idle
+    pub fn acquire(&self) -> BytesMut {
+        let buffer = self.idle.lock().ok().and_then(|mut idle| idle.pop());
+        let mut buffer = buffer.unwrap_or_default();
+        // No-op for pooled buffers, unless their allocation is still shared
+        buffer.reserve(BUFFER_CAPACITY);
+        buffer
+    }
+
+    /// Return a buffer to the pool
+    pub fn release(&self, mut buffer: BytesMut) {
+        if buffer.capacity() == 0 {
+            return;
+        }
+        buffer.clear();
+        if let Ok(mut idle) = self.idle.lock() {
+            if idle.len() < self.max_idle {
+                idle.push(buffer);
+            }
+        }
+    }
+
+    /// The number of idle buffers in the pool
+    pub fn idle(&self) -> usize {
+        self.idle.lock().map_or(0, |idle| idle.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuse_released_buffers() {
+        let pool = BufferPool::new(1);
+        let buffer = pool.acquire();
+        assert!(buffer.capacity() >= BUFFER_CAPACITY);
+        let allocation = buffer.as_ptr();
+
+        pool.release(buffer);
+        // Beyond the idle limit, or never allocated
+        pool.release(BytesMut::with_capacity(BUFFER_CAPACITY));
+        pool.release(BytesMut::new());
+        assert_eq!(pool.idle(), 1);
+        assert_eq!(pool.acquire().as_ptr(), allocation);
+        assert_eq!(pool.idle(), 0);
+    }
+}
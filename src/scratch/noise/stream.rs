@@ -0,0 +1,513 @@
+//! A secure channel over the noise transport, usable as any other stream.
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::{Buf, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+use super::pool::BufferPool;
+use super::{wire, Transport, TransportReceiver, TransportSender};
+use crate::error::PadawanError;
+
+/// The minimum number of bytes requested from the underlying stream on each read
+const READ_CHUNK_SIZE: usize = 8192;
+
+/// Encrypt and decrypt the data exchanged over an underlying stream
+/// once the noise handshake is complete.
+///
+/// Writes larger than [`MAX_PLAINTEXT_SIZE`][`wire::MAX_PLAINTEXT_SIZE`]
+/// are split into multiple noise frames, and decrypted frames that
+/// are only partially consumed are buffered for subsequent reads.
+///
+/// Frames are encrypted and decrypted in place, within buffers that are
+/// reused for the lifetime of the stream, and optionally taken from a [`BufferPool`][].
+///
+/// Once a frame fails to decrypt every subsequent read fails with the same kind
+/// of error, and once the nonces are exhausted the underlying stream is shut
+/// down. In either case the first failure carries the [`PadawanError`][] as the
+/// source of the IO error.
+pub struct NoiseStream<S> {
+    inner: S,
+    reader: ReadState,
+    writer: WriteState,
+}
+
+impl<S> NoiseStream<S> {
+    /// Wrap the given stream with the transport of a completed noise handshake.
+    ///
+    /// Any decrypted data left in the transport buffer are yielded first.
+    pub fn new(inner: S, transport: Transport) -> Self {
+        let (receiver, sender) = transport.split();
+        Self {
+            inner,
+            reader: ReadState::new(receiver),
+            writer: WriteState::new(sender),
+        }
+    }
+
+    /// Prepend encrypted bytes already read from the underlying stream
+    pub fn with_received(mut self, received: BytesMut) -> Self {
+        let buffered = std::mem::replace(&mut self.reader.received, received);
+        self.reader.received.unsplit(buffered);
+        self
+    }
+
+    /// Take the buffers of the stream from the given pool, and return
+    /// them to it once the stream, or either of its halves, is dropped
+    pub fn with_pool(mut self, pool: BufferPool) -> Self {
+        self.reader.received = pooled(&pool, &self.reader.received);
+        self.reader.pool = Some(pool.clone());
+        self.writer.pending = pooled(&pool, &self.writer.pending);
+        self.writer.pool = Some(pool);
+        self
+    }
+
+    /// Get a mutable reference to the underlying stream
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Get the underlying stream
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// Split the secure stream into halves that can be used independently,
+    /// e.g. from a reader and a writer task.
+    ///
+    /// The underlying stream is split with the given function, e.g.
+    /// [`TcpStream::into_split`][`tokio::net::TcpStream::into_split`] or [`tokio::io::split`][].
+    pub fn into_split_with<R, W>(
+        self,
+        split: impl FnOnce(S) -> (R, W),
+    ) -> (NoiseReadHalf<R>, NoiseWriteHalf<W>) {
+        let (read, write) = split(self.inner);
+        let reader = NoiseReadHalf {
+            inner: read,
+            state: self.reader,
+        };
+        let writer = NoiseWriteHalf {
+            inner: write,
+            state: self.writer,
+        };
+        (reader, writer)
+    }
+}
+
+/// The reading half of a [`NoiseStream`][]
+pub struct NoiseReadHalf<R> {
+    inner: R,
+    state: ReadState,
+}
+
+/// The writing half of a [`NoiseStream`][]
+pub struct NoiseWriteHalf<W> {
+    inner: W,
+    state: WriteState,
+}
+
+/// The state of the decrypting direction of the stream
+struct ReadState {
+    receiver: TransportReceiver,
+    /// Bytes received but not yet decrypted
+    received: BytesMut,
+    /// Decrypted bytes not yet consumed
+    plaintext: BytesMut,
+    pool: Option<BufferPool>,
+    /// The kind of the decryption failure, after which nothing more can be read
+    failed: Option<io::ErrorKind>,
+}
+
+impl ReadState {
+    fn new(mut receiver: TransportReceiver) -> Self {
+        let plaintext = receiver.messages().take_remaining();
+        Self {
+            receiver,
+            received: BytesMut::new(),
+            plaintext,
+            pool: None,
+            failed: None,
+        }
+    }
+
+    /// Decrypt the next complete frame in place, once all plaintext is consumed.
+    ///
+    /// Returns `false` if more bytes are needed.
+    fn decrypt_frame(&mut self) -> Result<bool, PadawanError> {
+        let mut frame = match wire::decode_frame(&mut self.received)? {
+            Some(frame) => frame,
+            None => return Ok(false),
+        };
+        self.receiver.decrypt(&mut frame)?;
+        self.plaintext = frame;
+        Ok(true)
+    }
+
+    fn poll_read<R: AsyncRead + Unpin>(
+        &mut self,
+        inner: &mut R,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        while self.plaintext.is_empty() {
+            if let Some(kind) = self.failed {
+                return Poll::Ready(Err(kind.into()));
+            }
+            // Release the consumed frame, so that its allocation is reclaimed
+            // by the received bytes instead of growing them
+            self.plaintext = BytesMut::new();
+            match self.decrypt_frame() {
+                Ok(true) => continue,
+                Ok(false) => {}
+                Err(err) => {
+                    // The nonces of the two peers are out of sync from now on
+                    let err = io::Error::from(err);
+                    self.failed = Some(err.kind());
+                    self.received.clear();
+                    return Poll::Ready(Err(err));
+                }
+            }
+            // Read straight into the spare capacity of the buffer
+            if self.received.capacity() - self.received.len() < READ_CHUNK_SIZE {
+                self.received.reserve(READ_CHUNK_SIZE);
+            }
+            let read = inner.read_buf(&mut self.received);
+            futures::pin_mut!(read);
+            match futures::ready!(read.poll(cx))? {
+                0 if self.received.is_empty() => return Poll::Ready(Ok(())),
+                0 => return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into())),
+                _ => {}
+            }
+        }
+        let n = self.plaintext.len().min(buf.remaining());
+        buf.put_slice(&self.plaintext[..n]);
+        self.plaintext.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// The state of the encrypting direction of the stream
+struct WriteState {
+    sender: TransportSender,
+    /// Encoded frames not yet written to the underlying stream
+    pending: BytesMut,
+    pool: Option<BufferPool>,
+}
+
+impl WriteState {
+    fn new(sender: TransportSender) -> Self {
+        Self {
+            sender,
+            pending: BytesMut::new(),
+            pool: None,
+        }
+    }
+
+    /// Write all pending frames to the underlying stream
+    fn poll_pending<W: AsyncWrite + Unpin>(
+        &mut self,
+        inner: &mut W,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            let n = match Pin::new(&mut *inner).poll_write(cx, &self.pending) {
+                Poll::Ready(Ok(n)) => n,
+                other => return other.map_ok(|_| ()),
+            };
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pending.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_write<W: AsyncWrite + Unpin>(
+        &mut self,
+        inner: &mut W,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.poll_pending(inner, cx)?.is_pending() {
+            return Poll::Pending;
+        }
+        let n = buf.len().min(wire::MAX_PLAINTEXT_SIZE);
+        match self.sender.encrypt_frame(&buf[..n], &mut self.pending) {
+            Ok(()) => {}
+            Err(PadawanError::NonceExhausted) => {
+                // No more frames can be sent, so close the stream after the previous ones
+                if Pin::new(&mut *inner).poll_shutdown(cx)?.is_pending() {
+                    return Poll::Pending;
+                }
+                return Poll::Ready(Err(PadawanError::NonceExhausted.into()));
+            }
+            Err(err) => return Poll::Ready(Err(err.into())),
+        }
+        // Best effort to send the frame right away, it is otherwise sent upon flush
+        let _ = self.poll_pending(inner, cx)?;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush<W: AsyncWrite + Unpin>(
+        &mut self,
+        inner: &mut W,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.poll_pending(inner, cx)?.is_pending() {
+            return Poll::Pending;
+        }
+        Pin::new(inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown<W: AsyncWrite + Unpin>(
+        &mut self,
+        inner: &mut W,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.poll_pending(inner, cx)?.is_pending() {
+            return Poll::Pending;
+        }
+        Pin::new(inner).poll_shutdown(cx)
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for NoiseStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.reader.poll_read(&mut this.inner, cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for NoiseStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        this.writer.poll_write(&mut this.inner, cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.writer.poll_flush(&mut this.inner, cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.writer.poll_shutdown(&mut this.inner, cx)
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for NoiseReadHalf<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.state.poll_read(&mut this.inner, cx, buf)
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for NoiseWriteHalf<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        this.state.poll_write(&mut this.inner, cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.state.poll_flush(&mut this.inner, cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.state.poll_shutdown(&mut this.inner, cx)
+    }
+}
+
+impl Drop for ReadState {
+    fn drop(&mut self) {
+        if let Some(pool) = &self.pool {
+            pool.release(std::mem::take(&mut self.received));
+        }
+    }
+}
+
+impl Drop for WriteState {
+    fn drop(&mut self) {
+        if let Some(pool) = &self.pool {
+            pool.release(std::mem::take(&mut self.pending));
+        }
+    }
+}
+
+/// Take a buffer from the `pool`, carrying over any `buffered` bytes
+fn pooled(pool: &BufferPool, buffered: &[u8]) -> BytesMut {
+    let mut buffer = pool.acquire();
+    buffer.extend_from_slice(buffered);
+    buffer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch::connection::Connection;
+    use crate::scratch::noise::{cipher::RekeyPolicy, config::NoiseConfig};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    async fn secure_pair() -> (NoiseStream<DuplexStream>, NoiseStream<DuplexStream>) {
+        secure_pair_with(Default::default()).await
+    }
+
+    async fn secure_pair_with(
+        config: NoiseConfig,
+    ) -> (NoiseStream<DuplexStream>, NoiseStream<DuplexStream>) {
+        let (dialer, listener) = tokio::io::duplex(4096);
+        let mut dialer = Connection::from(dialer).with_noise_config(config.clone());
+        let mut listener = Connection::from(listener).with_noise_config(config);
+        let (dialed, listened) = tokio::join!(dialer.dial(), listener.listen());
+        dialed.unwrap();
+        listened.unwrap();
+        (
+            dialer.into_stream().unwrap(),
+            listener.into_stream().unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn echo() {
+        let (mut dialer, mut listener) = secure_pair().await;
+        dialer.write_all(b"ping").await.unwrap();
+        dialer.flush().await.unwrap();
+
+        let mut received = [0; 4];
+        listener.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"ping");
+        listener.write_all(b"pong").await.unwrap();
+        listener.flush().await.unwrap();
+        dialer.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"pong");
+    }
+
+    #[tokio::test]
+    async fn rekey_periodically() {
+        let policy = RekeyPolicy::default().after_messages(2);
+        let config = NoiseConfig::default().with_rekey_policy(policy);
+        let (mut dialer, mut listener) = secure_pair_with(config).await;
+        let mut received = [0; 4];
+        for _ in 0..5 {
+            dialer.write_all(b"ping").await.unwrap();
+            dialer.flush().await.unwrap();
+            listener.read_exact(&mut received).await.unwrap();
+            assert_eq!(&received, b"ping");
+        }
+    }
+
+    #[tokio::test]
+    async fn stop_reading_on_decrypt_failure() {
+        let (mut dialer, mut listener) = secure_pair().await;
+        let mut forged = vec![0, 20];
+        forged.extend_from_slice(&[42; 20]);
+        dialer.get_mut().write_all(&forged).await.unwrap();
+        dialer.write_all(b"ping").await.unwrap();
+        dialer.flush().await.unwrap();
+
+        let mut received = [0; 4];
+        let err = listener.read(&mut received).await.unwrap_err();
+        assert!(matches!(
+            err.get_ref().and_then(|err| err.downcast_ref()),
+            Some(PadawanError::DecryptFailed)
+        ));
+        let err = listener.read(&mut received).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn split_large_writes() {
+        let (mut dialer, mut listener) = secure_pair().await;
+        let sent: Vec<u8> = (0..3 * wire::MAX_PLAINTEXT_SIZE + 7)
+            .map(|i| i as u8)
+            .collect();
+        let expected = sent.clone();
+        let writer = tokio::spawn(async move {
+            dialer.write_all(&sent).await.unwrap();
+            dialer.shutdown().await.unwrap();
+        });
+
+        let mut received = Vec::new();
+        // Read in small chunks to consume decrypted frames partially
+        let mut chunk = [0; 1000];
+        loop {
+            let n = listener.read(&mut chunk).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            received.extend_from_slice(&chunk[..n]);
+        }
+        writer.await.unwrap();
+        assert_eq!(received, expected);
+    }
+
+    #[tokio::test]
+    async fn return_buffers_to_pool() {
+        let pool = BufferPool::default();
+        let (dialer, listener) = secure_pair().await;
+        let mut dialer = dialer.with_pool(pool.clone());
+        let (mut read, write) = listener
+            .with_pool(pool.clone())
+            .into_split_with(tokio::io::split);
+        dialer.write_all(b"ping").await.unwrap();
+        dialer.flush().await.unwrap();
+        let mut received = [0; 4];
+        read.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"ping");
+
+        drop((dialer, write));
+        assert_eq!(pool.idle(), 3);
+        drop(read);
+        assert_eq!(pool.idle(), 4);
+    }
+
+    #[tokio::test]
+    async fn full_duplex_halves() {
+        let (dialer, listener) = secure_pair().await;
+        let (mut dialer_read, mut dialer_write) = dialer.into_split_with(tokio::io::split);
+        let (mut listener_read, mut listener_write) = listener.into_split_with(tokio::io::split);
+        let message = vec![7_u8; 2 * wire::MAX_PLAINTEXT_SIZE];
+
+        let sent = message.clone();
+        let writers = tokio::spawn(async move {
+            let (dialed, listened) = tokio::join!(
+                dialer_write.write_all(&sent),
+                listener_write.write_all(&sent)
+            );
+            dialed.unwrap();
+            listened.unwrap();
+            dialer_write.flush().await.unwrap();
+            listener_write.flush().await.unwrap();
+        });
+        let mut from_listener = vec![0; message.len()];
+        let mut from_dialer = vec![0; message.len()];
+        let (dialer_read, listener_read) = tokio::join!(
+            dialer_read.read_exact(&mut from_listener),
+            listener_read.read_exact(&mut from_dialer)
+        );
+        dialer_read.unwrap();
+        listener_read.unwrap();
+        writers.await.unwrap();
+        assert_eq!(from_listener, message);
+        assert_eq!(from_dialer, message);
+    }
+}
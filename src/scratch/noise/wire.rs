@@ -1,36 +1,232 @@
 //! Implement the noise-frame specification for read
 //! and write operations
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::tcp;
+use std::io;
 
+use bytes::{Buf, BufMut, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::cipher::CipherState;
 use crate::error::PadawanError;
 
-const MAX_FRAME_SIZE: usize = 65536;
-const MAX_PAYLOAD_SIZE: usize = MAX_FRAME_SIZE - super::ENCRYPTION_INFLATION_SIZE;
+/// The maximum size of a noise frame, excluding its length prefix.
+///
+/// This is the largest length that the two-byte prefix can express,
+/// so that received frames cannot exceed it.
+pub const MAX_FRAME_SIZE: usize = u16::MAX as usize;
 
-/// Read a noise frame from the remote peer and put the payload
-/// into the given `buffer`
-pub async fn recv<'a>(
-    read: &mut tcp::ReadHalf<'a>,
-    buffer: &mut Vec<u8>,
+/// The maximum size of the plaintext carried by a single frame,
+/// accounting for the authentication tag appended on encryption
+pub const MAX_PLAINTEXT_SIZE: usize = MAX_FRAME_SIZE - super::TAG_SIZE;
+
+/// Append a noise frame carrying the given `payload` to the `out` buffer
+pub fn encode_frame(payload: &[u8], out: &mut BytesMut) -> Result<(), PadawanError> {
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(PadawanError::NoiseFrameSizeExceeded);
+    }
+    out.reserve(payload.len() + 2);
+    out.put_u16(payload.len() as u16);
+    out.extend_from_slice(payload);
+    Ok(())
+}
+
+/// Append a noise frame carrying the given `plaintext` to the `out` buffer,
+/// encrypting it in place with the `cipher`.
+pub fn encrypt_frame(
+    cipher: &mut CipherState,
+    plaintext: &[u8],
+    out: &mut BytesMut,
 ) -> Result<(), PadawanError> {
-    let n = read.read_u16().await?;
-    if n as usize > MAX_FRAME_SIZE {
+    if plaintext.len() > MAX_PLAINTEXT_SIZE {
         return Err(PadawanError::NoiseFrameSizeExceeded);
     }
-    buffer.resize(n as usize, 0);
-    read.read_exact(buffer).await?;
+    let start = out.len();
+    out.reserve(plaintext.len() + super::TAG_SIZE + 2);
+    out.put_u16((plaintext.len() + super::TAG_SIZE) as u16);
+    out.extend_from_slice(plaintext);
+    let result = cipher.encrypt_in_place(out, start + 2);
+    if result.is_err() {
+        // Never leave the plaintext behind to be sent
+        out.truncate(start);
+    }
+    result
+}
+
+/// Split the next complete noise frame from the front of the `buffer`.
+///
+/// Returns the payload of the frame, or `None` if more bytes are needed.
+/// The payload shares the allocation of the `buffer`, which is reclaimed
+/// once the payload is dropped.
+pub fn decode_frame(buffer: &mut BytesMut) -> Result<Option<BytesMut>, PadawanError> {
+    let n = match buffer.get(..2) {
+        Some(prefix) => u16::from_be_bytes([prefix[0], prefix[1]]) as usize,
+        None => return Ok(None),
+    };
+    if buffer.len() < n + 2 {
+        buffer.reserve(n + 2 - buffer.len());
+        return Ok(None);
+    }
+    let mut payload = buffer.split_to(n + 2);
+    payload.advance(2);
+    Ok(Some(payload))
+}
+
+/// Read a noise frame from the remote peer and put the payload
+/// into the given `buffer`, reusing its allocation
+pub async fn recv<R: AsyncRead + Unpin>(
+    read: &mut R,
+    buffer: &mut BytesMut,
+) -> Result<(), PadawanError> {
+    let n = read.read_u16().await? as usize;
+    buffer.clear();
+    buffer.reserve(n);
+    let mut frame = read.take(n as u64);
+    while buffer.len() < n {
+        if frame.read_buf(buffer).await? == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+    }
     Ok(())
 }
 
-/// Create a noise frame from the given `payload` and send it to the remote peer
-pub async fn send<'a>(
-    write: &mut tcp::WriteHalf<'a>,
+/// Create a noise frame from the given `payload` and send it to the remote peer.
+///
+/// The length prefix and the payload are written together, and the whole
+/// frame is written even if the stream accepts only part of it at a time.
+///
+/// Returns the size of the payload.
+pub async fn send<W: AsyncWrite + Unpin>(
+    write: &mut W,
     payload: &[u8],
 ) -> Result<usize, PadawanError> {
-    if payload.len() > MAX_PAYLOAD_SIZE {
-        return Err(PadawanError::NoiseFrameSizeExceeded);
+    let mut frame = BytesMut::with_capacity(payload.len() + 2);
+    encode_frame(payload, &mut frame)?;
+    write.write_all(&frame).await?;
+    Ok(payload.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use crate::scratch::noise::cipher::KEY_SIZE;
+
+    /// Accept at most `limit` bytes per write, and yield before every other write
+    #[derive(Default)]
+    struct Throttled {
+        limit: usize,
+        yielded: bool,
+        writes: usize,
+        written: Vec<u8>,
+    }
+
+    impl Throttled {
+        fn new(limit: usize) -> Self {
+            Self {
+                limit,
+                ..Default::default()
+            }
+        }
+    }
+
+    impl AsyncWrite for Throttled {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.yielded = !self.yielded;
+            if self.yielded {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            let n = buf.len().min(self.limit);
+            self.writes += 1;
+            self.written.extend_from_slice(&buf[..n]);
+            Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn send_single_write() {
+        let mut write = Throttled::new(usize::MAX);
+        assert_eq!(send(&mut write, b"hello").await.unwrap(), 5);
+        assert_eq!(write.writes, 1);
+        assert_eq!(write.written, b"\x00\x05hello");
+    }
+
+    #[tokio::test]
+    async fn send_through_short_writes() {
+        let payload: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        let mut write = Throttled::new(7);
+        assert_eq!(send(&mut write, &payload).await.unwrap(), payload.len());
+        // The 1002 bytes of the frame, 7 at a time
+        assert_eq!(write.writes, 144);
+
+        let mut received = BytesMut::new();
+        recv(&mut write.written.as_slice(), &mut received)
+            .await
+            .unwrap();
+        assert_eq!(&received[..], payload);
+    }
+
+    #[tokio::test]
+    async fn max_frame_size() {
+        let mut write = Throttled::new(usize::MAX);
+        let payload = vec![42; MAX_FRAME_SIZE];
+        send(&mut write, &payload).await.unwrap();
+        let mut received = BytesMut::new();
+        recv(&mut write.written.as_slice(), &mut received)
+            .await
+            .unwrap();
+        assert_eq!(received.len(), MAX_FRAME_SIZE);
+
+        let oversized = vec![42; MAX_FRAME_SIZE + 1];
+        assert!(matches!(
+            send(&mut write, &oversized).await,
+            Err(PadawanError::NoiseFrameSizeExceeded)
+        ));
+        assert!(matches!(
+            encode_frame(&oversized, &mut BytesMut::new()),
+            Err(PadawanError::NoiseFrameSizeExceeded)
+        ));
+    }
+
+    #[test]
+    fn decode_coalesced_frames() {
+        let mut buffer = BytesMut::new();
+        encode_frame(b"first", &mut buffer).unwrap();
+        encode_frame(b"second", &mut buffer).unwrap();
+        buffer.extend_from_slice(&[0, 10, 1]);
+
+        assert_eq!(&decode_frame(&mut buffer).unwrap().unwrap()[..], b"first");
+        assert_eq!(&decode_frame(&mut buffer).unwrap().unwrap()[..], b"second");
+        assert!(decode_frame(&mut buffer).unwrap().is_none());
+        assert_eq!(&buffer[..], &[0, 10, 1]);
+    }
+
+    #[test]
+    fn encrypt_frame_in_place() {
+        let key = [7; KEY_SIZE];
+        let (mut sender, mut receiver) = (CipherState::new(&key), CipherState::new(&key));
+        let mut buffer = BytesMut::new();
+        encrypt_frame(&mut sender, b"hello", &mut buffer).unwrap();
+
+        let mut payload = decode_frame(&mut buffer).unwrap().unwrap();
+        receiver.decrypt_in_place(&mut payload).unwrap();
+        assert_eq!(&payload[..], b"hello");
+        assert!(matches!(
+            encrypt_frame(&mut sender, &[0; MAX_PLAINTEXT_SIZE + 1], &mut buffer),
+            Err(PadawanError::NoiseFrameSizeExceeded)
+        ));
     }
-    write.write_u16(payload.len() as u16).await?;
-    Ok(write.write(payload).await?)
 }
@@ -0,0 +1,89 @@
+//! The `libp2p` [plaintext][plaintext-spec] security protocol.
+//!
+//! The peers exchange their identities without proving them, and nothing is
+//! encrypted afterwards. It is only meant for debugging the protocols on top of
+//! the connection, and is only available with the `plaintext` feature.
+//!
+//! [plaintext-spec]: https://github.com/libp2p/specs/blob/master/plaintext/README.md
+#![allow(clippy::derive_partial_eq_without_eq)]
+
+use bytes::BytesMut;
+use libp2p::{identity, PeerId};
+use prost::Message;
+
+use crate::error::PadawanError;
+use crate::scratch::multistream_select::framed;
+
+// The protobuf types of the exchanged messages
+//
+// See `/src/scratch/plaintext/proto/exchange.proto`
+include!(concat!(env!("OUT_DIR"), "/plaintext.rs"));
+
+impl Exchange {
+    /// Create the exchange message of the local peer
+    pub fn new(public_key: &identity::PublicKey) -> Self {
+        Self {
+            id: Some(public_key.to_peer_id().to_bytes()),
+            pubkey: Some(public_key.to_protobuf_encoding()),
+        }
+    }
+
+    /// Verify that the announced [`PeerId`][] is derived from the announced public key
+    pub fn verify(&self) -> Result<PeerId, PadawanError> {
+        let id = PeerId::from_bytes(self.id()).map_err(|_| PadawanError::IdVerification)?;
+        let public_key = identity::PublicKey::from_protobuf_encoding(self.pubkey())?;
+        if id != public_key.to_peer_id() {
+            return Err(PadawanError::IdVerification);
+        }
+        Ok(id)
+    }
+}
+
+/// Append the length-prefixed exchange message of the local peer to the `out` buffer
+pub fn encode_exchange(
+    public_key: &identity::PublicKey,
+    out: &mut BytesMut,
+) -> Result<(), PadawanError> {
+    Ok(Exchange::new(public_key).encode_length_delimited(out)?)
+}
+
+/// Split the exchange message of the remote peer from the front of the `received` bytes.
+///
+/// Returns the verified [`PeerId`][] of the remote peer, or `None` if more bytes are needed.
+pub fn decode_exchange(received: &mut BytesMut) -> Result<Option<PeerId>, PadawanError> {
+    let message = match framed::decode_message(received, framed::MAX_MESSAGE_SIZE)? {
+        Some(message) => message,
+        None => return Ok(None),
+    };
+    let exchange = Exchange::decode_length_delimited(message.as_slice())?;
+    exchange.verify().map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exchange() {
+        let public_key = identity::Keypair::generate_ed25519().public();
+        let mut buffer = BytesMut::new();
+        encode_exchange(&public_key, &mut buffer).unwrap();
+        let mut partial = buffer.split_to(buffer.len() - 1);
+        assert!(decode_exchange(&mut partial).unwrap().is_none());
+        partial.unsplit(buffer);
+        assert_eq!(
+            decode_exchange(&mut partial).unwrap(),
+            Some(public_key.to_peer_id())
+        );
+        assert!(partial.is_empty());
+
+        let mut forged = Exchange::new(&public_key);
+        forged.id = Some(PeerId::random().to_bytes());
+        let mut buffer = BytesMut::new();
+        forged.encode_length_delimited(&mut buffer).unwrap();
+        assert!(matches!(
+            decode_exchange(&mut buffer),
+            Err(PadawanError::IdVerification)
+        ));
+    }
+}
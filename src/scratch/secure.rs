@@ -0,0 +1,81 @@
+//! The secure channel of an established connection, whichever security protocol
+//! was agreed, usable as any other stream, e.g. beneath a stream muxer.
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+#[cfg(feature = "plaintext")]
+use bytes::{Buf, BytesMut};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use super::noise::stream::NoiseStream;
+use super::tls::stream::TlsStream;
+
+/// A secure stream over the underlying stream `S`
+pub enum SecureStream<S> {
+    Noise(Box<NoiseStream<S>>),
+    Tls(Box<TlsStream<S>>),
+    /// The data are exchanged unencrypted, starting with any bytes
+    /// received beyond the handshake
+    #[cfg(feature = "plaintext")]
+    Plaintext {
+        inner: S,
+        received: BytesMut,
+    },
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for SecureStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Noise(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(feature = "plaintext")]
+            Self::Plaintext { inner, received } => {
+                if received.is_empty() {
+                    return Pin::new(inner).poll_read(cx, buf);
+                }
+                let n = received.len().min(buf.remaining());
+                buf.put_slice(&received[..n]);
+                received.advance(n);
+                Poll::Ready(Ok(()))
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for SecureStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Noise(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(feature = "plaintext")]
+            Self::Plaintext { inner, .. } => Pin::new(inner).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Noise(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(feature = "plaintext")]
+            Self::Plaintext { inner, .. } => Pin::new(inner).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Noise(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(feature = "plaintext")]
+            Self::Plaintext { inner, .. } => Pin::new(inner).poll_shutdown(cx),
+        }
+    }
+}
@@ -0,0 +1,371 @@
+//! The session of a stream muxer, shared by [`yamux`][`super::yamux`] and
+//! [`mplex`][`super::mplex`].
+//!
+//! A [`Session`][] multiplexes the substreams of a [`Muxer`][] over a single
+//! secure stream, e.g. a [`SecureStream`][`crate::scratch::secure::SecureStream`].
+//! The session is driven by [`run`][`Session::run`], which reads and writes the
+//! underlying stream and serves the requests of the substreams and of any clone
+//! of its [`Control`][], while the muxer handles the frames of its protocol.
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::{Buf, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Interval;
+
+use crate::error::PadawanError;
+
+/// The number of bytes requested from the underlying stream on each read
+const READ_CHUNK_SIZE: usize = 8192;
+
+/// The frame handling of a stream muxer
+pub trait Muxer: Sized {
+    /// The identifier of a stream within the session
+    type Id: Copy + Eq + Hash + fmt::Debug;
+    /// A frame sent on behalf of a substream
+    type Frame;
+    /// The state of a stream shared between the session and its substream
+    type Stream: Stream;
+    /// The handle of a stream, as opened and accepted
+    type Substream;
+    /// The requests to the session specific to the muxer
+    type Request;
+
+    /// The name of the muxer, as logged
+    const NAME: &'static str;
+
+    /// Process the complete frames received so far
+    fn on_input(session: &mut SessionState<Self>) -> Result<(), PadawanError>;
+
+    /// Append a `frame` to the `out` buffer
+    fn encode(frame: &Self::Frame, out: &mut BytesMut);
+
+    /// The frame resetting a stream
+    fn reset(id: Self::Id) -> Self::Frame;
+
+    /// Open a new stream to the remote peer
+    fn open_stream(session: &mut SessionState<Self>) -> Result<Self::Substream, PadawanError>;
+
+    /// Serve a request specific to the muxer
+    fn on_request(session: &mut SessionState<Self>, request: Self::Request);
+
+    /// Start closing the session, once closed through its [`Control`][]
+    fn on_close(session: &mut SessionState<Self>);
+
+    /// Handle an `err` of processing the input, and return whether it is a
+    /// protocol violation of the remote peer, which is reported to it before
+    /// the session closes. The session fails right away otherwise.
+    fn on_violation(_session: &mut SessionState<Self>, _err: &PadawanError) -> bool {
+        false
+    }
+
+    /// Account for `n` bytes written to the underlying stream
+    fn on_sent(_session: &mut SessionState<Self>, _n: usize) {}
+
+    /// The period of [`on_tick`][`Muxer::on_tick`], if any
+    fn tick_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Perform the periodic tasks of the muxer, starting right away
+    fn on_tick(_session: &mut SessionState<Self>) {}
+
+    /// Release the resources of the muxer, once the session terminates
+    fn on_terminate(_session: &mut SessionState<Self>) {}
+}
+
+/// The state of a stream that the session resets once it terminates
+pub trait Stream {
+    /// Mark the stream as reset, and wake its pending reader and writer
+    fn reset(&mut self);
+}
+
+/// Requests to the session from its [`Control`][] and substreams
+pub(crate) enum Command<M: Muxer> {
+    Open(oneshot::Sender<Result<M::Substream, PadawanError>>),
+    Frame(M::Frame),
+    /// The stream was dropped, and is reset unless closed in both directions
+    Drop {
+        id: M::Id,
+        reset: bool,
+    },
+    Muxer(M::Request),
+    Close,
+}
+
+/// A handle for opening and accepting streams, and closing the session
+pub struct Control<M: Muxer> {
+    commands: mpsc::UnboundedSender<Command<M>>,
+    inbound: Arc<tokio::sync::Mutex<mpsc::UnboundedReceiver<M::Substream>>>,
+}
+
+impl<M: Muxer> Clone for Control<M> {
+    fn clone(&self) -> Self {
+        Self {
+            commands: self.commands.clone(),
+            inbound: self.inbound.clone(),
+        }
+    }
+}
+
+impl<M: Muxer> fmt::Debug for Control<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Control")
+            .field("muxer", &M::NAME)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<M: Muxer> Control<M> {
+    /// Open a new stream to the remote peer
+    pub async fn open_stream(&self) -> Result<M::Substream, PadawanError> {
+        let (reply, opened) = oneshot::channel();
+        self.request(Command::Open(reply))?;
+        opened.await.map_err(|_| PadawanError::SessionClosed)?
+    }
+
+    /// Wait for the next stream opened by the remote peer.
+    ///
+    /// Returns `None` once the session terminates.
+    pub async fn accept(&self) -> Option<M::Substream> {
+        self.inbound.lock().await.recv().await
+    }
+
+    /// Close the session, which terminates once all pending frames are sent
+    pub fn close(&self) -> Result<(), PadawanError> {
+        self.request(Command::Close)
+    }
+
+    pub(crate) fn request(&self, command: Command<M>) -> Result<(), PadawanError> {
+        self.commands
+            .send(command)
+            .map_err(|_| PadawanError::SessionClosed)
+    }
+}
+
+/// A session of the muxer `M` over the stream `S`
+pub struct Session<S, M: Muxer> {
+    io: S,
+    pub(crate) state: SessionState<M>,
+}
+
+impl<S, M: Muxer> Session<S, M> {
+    pub(crate) fn with_muxer(io: S, muxer: M) -> Self {
+        Self {
+            io,
+            state: SessionState::new(muxer),
+        }
+    }
+
+    /// Get a handle to the session
+    pub fn control(&self) -> Control<M> {
+        self.state.control.clone()
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin, M: Muxer> Session<S, M> {
+    /// Drive the session until the remote peer closes the underlying stream,
+    /// or the session is closed through its [`Control`][].
+    ///
+    /// Once terminated, all streams are reset, while those closed by the remote
+    /// peer still yield their unread data before the end of the stream.
+    ///
+    /// # Errors
+    ///
+    /// Fails on IO errors, or if the remote peer violates the protocol.
+    pub async fn run(self) -> Result<(), PadawanError> {
+        let (mut reader, mut writer) = tokio::io::split(self.io);
+        let mut state = self.state;
+        let result = state.drive(&mut reader, &mut writer).await;
+        state.terminate();
+        result
+    }
+}
+
+/// The state of the session, apart from the underlying stream
+pub struct SessionState<M: Muxer> {
+    pub(crate) muxer: M,
+    pub(crate) streams: HashMap<M::Id, Arc<Mutex<M::Stream>>>,
+    commands: mpsc::UnboundedReceiver<Command<M>>,
+    /// The handle passed to the streams, and cloned for users
+    control: Control<M>,
+    inbound: mpsc::UnboundedSender<M::Substream>,
+    /// Bytes received but not yet decoded
+    pub(crate) received: BytesMut,
+    /// Frames not yet written to the underlying stream
+    outgoing: BytesMut,
+    /// Whether the local peer is closing the session
+    pub(crate) closing: bool,
+    /// The protocol violation of the remote peer that closes the session
+    failure: Option<PadawanError>,
+}
+
+impl<M: Muxer> SessionState<M> {
+    fn new(muxer: M) -> Self {
+        let (commands_tx, commands) = mpsc::unbounded_channel();
+        let (inbound, inbound_rx) = mpsc::unbounded_channel();
+        let control = Control {
+            commands: commands_tx,
+            inbound: Arc::new(tokio::sync::Mutex::new(inbound_rx)),
+        };
+        Self {
+            muxer,
+            streams: HashMap::new(),
+            commands,
+            control,
+            inbound,
+            received: BytesMut::new(),
+            outgoing: BytesMut::new(),
+            closing: false,
+            failure: None,
+        }
+    }
+
+    async fn drive<R, W>(&mut self, reader: &mut R, writer: &mut W) -> Result<(), PadawanError>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut chunk = [0_u8; READ_CHUNK_SIZE];
+        let mut flushed = true;
+        // The first tick completes right away
+        let mut timer = self.muxer.tick_interval().map(tokio::time::interval);
+        loop {
+            if self.closing && self.outgoing.is_empty() && flushed {
+                let shutdown = writer.shutdown().await;
+                if let Some(err) = self.failure.take() {
+                    return Err(err);
+                }
+                return Ok(shutdown?);
+            }
+            tokio::select! {
+                read = reader.read(&mut chunk), if !self.closing => {
+                    let n = read?;
+                    if n == 0 {
+                        tracing::debug!("Remote peer closed the {} session", M::NAME);
+                        return Ok(());
+                    }
+                    self.received.extend_from_slice(&chunk[..n]);
+                    if let Err(err) = M::on_input(self) {
+                        if !M::on_violation(self, &err) {
+                            return Err(err);
+                        }
+                        // Let the remote peer know once the pending frames are sent
+                        tracing::debug!("Closing the {} session: {}", M::NAME, err);
+                        self.closing = true;
+                        self.failure = Some(err);
+                    }
+                }
+                written = transmit(writer, &self.outgoing), if !self.outgoing.is_empty() || !flushed => {
+                    let n = written?;
+                    if self.outgoing.is_empty() {
+                        flushed = true;
+                    } else if n == 0 {
+                        return Err(io::Error::from(io::ErrorKind::WriteZero).into());
+                    } else {
+                        self.outgoing.advance(n);
+                        M::on_sent(self, n);
+                        flushed = false;
+                    }
+                }
+                Some(command) = self.commands.recv(), if self.failure.is_none() => {
+                    self.on_command(command)
+                }
+                _ = tick(&mut timer), if !self.closing => M::on_tick(self),
+            }
+        }
+    }
+
+    fn on_command(&mut self, command: Command<M>) {
+        match command {
+            Command::Open(reply) => {
+                let _ = reply.send(M::open_stream(self));
+            }
+            Command::Frame(frame) => self.send(&frame),
+            Command::Drop { id, reset } => {
+                if self.streams.remove(&id).is_some() && reset {
+                    self.send(&M::reset(id));
+                }
+            }
+            Command::Muxer(request) => M::on_request(self, request),
+            Command::Close => {
+                if !self.closing {
+                    self.closing = true;
+                    M::on_close(self);
+                }
+            }
+        }
+    }
+
+    /// Queue a frame for sending
+    pub(crate) fn send(&mut self, frame: &M::Frame) {
+        M::encode(frame, &mut self.outgoing);
+    }
+
+    /// Hand a stream opened by the remote peer over to [`Control::accept`][]
+    pub(crate) fn accept(&mut self, substream: M::Substream) {
+        // The receiver is owned by the session as well, so the stream is never lost
+        let _ = self.inbound.send(substream);
+    }
+
+    /// The channel of the requests of a new substream
+    pub(crate) fn commands(&self) -> mpsc::UnboundedSender<Command<M>> {
+        self.control.commands.clone()
+    }
+
+    /// Reset all the streams, so that pending reads and writes fail, except for
+    /// reading the remaining data of the streams closed by the remote peer
+    pub(crate) fn terminate(&mut self) {
+        for state in self.streams.values() {
+            state.lock().expect("stream state lock").reset();
+        }
+        self.streams.clear();
+        M::on_terminate(self);
+        tracing::debug!("{} session terminated", M::NAME);
+    }
+}
+
+/// Write some of the `outgoing` bytes, or flush the `writer` once they are all written
+async fn transmit<W: AsyncWrite + Unpin>(writer: &mut W, outgoing: &[u8]) -> io::Result<usize> {
+    if outgoing.is_empty() {
+        writer.flush().await.map(|_| 0)
+    } else {
+        writer.write(outgoing).await
+    }
+}
+
+/// Wait for the next tick of the `timer`, or forever without one
+async fn tick(timer: &mut Option<Interval>) {
+    match timer {
+        Some(timer) => {
+            timer.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Run a pair of connected sessions, and return their controls
+#[cfg(test)]
+pub(crate) fn spawn_pair<S, M>(
+    dialer: Session<S, M>,
+    listener: Session<S, M>,
+) -> (Control<M>, Control<M>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    M: Muxer + Send + 'static,
+    M::Id: Send,
+    M::Frame: Send,
+    M::Stream: Send,
+    M::Substream: Send,
+    M::Request: Send,
+{
+    let controls = (dialer.control(), listener.control());
+    tokio::spawn(dialer.run());
+    tokio::spawn(listener.run());
+    controls
+}
@@ -0,0 +1,181 @@
+//! The self-signed certificates of the `libp2p` TLS handshake.
+//!
+//! The certificate key is unrelated to the identity of the peer. Instead, the
+//! certificate carries the public identity key, and its signature over the
+//! certificate key, in the `libp2p` public key extension.
+//!
+//! See https://github.com/libp2p/specs/blob/master/tls/tls.md
+use libp2p::{identity, PeerId};
+use ring::signature;
+use rustls::SignatureScheme;
+use x509_parser::oid_registry::{self, Oid};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::error::PadawanError;
+
+/// The OID of the `libp2p` public key extension
+const EXTENSION_OID: [u64; 9] = [1, 3, 6, 1, 4, 1, 53594, 1, 1];
+
+/// The prefix of the message signed with the identity key
+const SIGNING_PREFIX: &[u8] = b"libp2p-tls-handshake:";
+
+/// The algorithm of the certificate keys of the local peer
+static CERTIFICATE_ALGORITHM: &rcgen::SignatureAlgorithm = &rcgen::PKCS_ECDSA_P256_SHA256;
+
+/// Generate a new self-signed certificate, and its private key, for the given `identity`
+///
+/// # Errors
+///
+/// Fails if the certificate key cannot be generated, or the identity cannot sign messages.
+pub fn generate(
+    identity: &identity::Keypair,
+) -> Result<(rustls::Certificate, rustls::PrivateKey), PadawanError> {
+    let keypair = rcgen::KeyPair::generate(CERTIFICATE_ALGORITHM)?;
+    let key = rustls::PrivateKey(keypair.serialize_der());
+
+    let mut message = SIGNING_PREFIX.to_vec();
+    message.extend_from_slice(&keypair.public_key_der());
+    // SignedKey ::= SEQUENCE { publicKey OCTET STRING, signature OCTET STRING }
+    let signed_key = yasna::encode_der(&(
+        identity.public().to_protobuf_encoding(),
+        identity.sign(&message)?,
+    ));
+    let mut extension = rcgen::CustomExtension::from_oid_content(&EXTENSION_OID, signed_key);
+    extension.set_criticality(true);
+
+    let mut params = rcgen::CertificateParams::new(vec![]);
+    params.distinguished_name = rcgen::DistinguishedName::new();
+    params.custom_extensions.push(extension);
+    params.alg = CERTIFICATE_ALGORITHM;
+    params.key_pair = Some(keypair);
+    let certificate = rcgen::Certificate::from_params(params)?;
+    Ok((rustls::Certificate(certificate.serialize_der()?), key))
+}
+
+/// A verified certificate of a remote peer
+pub struct Certificate<'a> {
+    x509: X509Certificate<'a>,
+    public_key: identity::PublicKey,
+}
+
+impl<'a> Certificate<'a> {
+    /// Parse and verify the certificate presented by a remote peer.
+    ///
+    /// The certificate must be currently valid, self-signed, and carry a valid
+    /// signature of its key by the identity of the peer. Critical extensions
+    /// other than the `libp2p` one are rejected.
+    pub fn parse(certificate: &'a rustls::Certificate) -> Result<Self, PadawanError> {
+        let invalid = |reason: &str| PadawanError::InvalidCertificate(reason.to_string());
+        let (_, x509) = X509Certificate::from_der(&certificate.0)
+            .map_err(|_| invalid("malformed certificate"))?;
+        let oid = Oid::from(&EXTENSION_OID).expect("valid OID");
+        let mut signed_key = None;
+        for extension in x509.extensions() {
+            if extension.oid == oid {
+                if signed_key.is_some() {
+                    return Err(invalid("duplicate libp2p extension"));
+                }
+                signed_key = Some(extension.value);
+            } else if extension.critical {
+                return Err(invalid("unsupported critical extension"));
+            }
+        }
+        let signed_key = signed_key.ok_or_else(|| invalid("missing libp2p extension"))?;
+        let (public_key, signature): (Vec<u8>, Vec<u8>) =
+            yasna::decode_der(signed_key).map_err(|_| invalid("malformed libp2p extension"))?;
+        let public_key = identity::PublicKey::from_protobuf_encoding(&public_key)?;
+
+        if !x509.validity().is_valid() {
+            return Err(invalid("expired or not yet valid"));
+        }
+        let mut message = SIGNING_PREFIX.to_vec();
+        message.extend_from_slice(x509.public_key().raw);
+        if !public_key.verify(&message, &signature) {
+            return Err(PadawanError::IdVerification);
+        }
+        let certificate = Self { x509, public_key };
+        let scheme = certificate.signature_scheme()?;
+        certificate.verify_signature(
+            scheme,
+            certificate.x509.tbs_certificate.as_ref(),
+            &certificate.x509.signature_value.data,
+        )?;
+        Ok(certificate)
+    }
+
+    /// The [`PeerId`][] of the remote peer
+    pub fn peer_id(&self) -> PeerId {
+        self.public_key.to_peer_id()
+    }
+
+    /// Verify the `signature` of the `message` with the certificate key
+    pub fn verify_signature(
+        &self,
+        scheme: SignatureScheme,
+        message: &[u8],
+        signature: &[u8],
+    ) -> Result<(), PadawanError> {
+        let algorithm: &dyn signature::VerificationAlgorithm = match scheme {
+            SignatureScheme::ECDSA_NISTP256_SHA256 => &signature::ECDSA_P256_SHA256_ASN1,
+            SignatureScheme::ECDSA_NISTP384_SHA384 => &signature::ECDSA_P384_SHA384_ASN1,
+            SignatureScheme::ED25519 => &signature::ED25519,
+            SignatureScheme::RSA_PKCS1_SHA256 => &signature::RSA_PKCS1_2048_8192_SHA256,
+            SignatureScheme::RSA_PKCS1_SHA384 => &signature::RSA_PKCS1_2048_8192_SHA384,
+            SignatureScheme::RSA_PKCS1_SHA512 => &signature::RSA_PKCS1_2048_8192_SHA512,
+            SignatureScheme::RSA_PSS_SHA256 => &signature::RSA_PSS_2048_8192_SHA256,
+            SignatureScheme::RSA_PSS_SHA384 => &signature::RSA_PSS_2048_8192_SHA384,
+            SignatureScheme::RSA_PSS_SHA512 => &signature::RSA_PSS_2048_8192_SHA512,
+            scheme => {
+                let reason = format!("unsupported signature scheme {scheme:?}");
+                return Err(PadawanError::InvalidCertificate(reason));
+            }
+        };
+        let key = &self.x509.public_key().subject_public_key.data;
+        signature::UnparsedPublicKey::new(algorithm, key)
+            .verify(message, signature)
+            .map_err(|_| PadawanError::InvalidCertificate("invalid signature".to_string()))
+    }
+
+    /// The scheme of the self-signature of the certificate.
+    ///
+    /// Hash functions shorter than 256 bits are not accepted.
+    fn signature_scheme(&self) -> Result<SignatureScheme, PadawanError> {
+        let algorithm = &self.x509.signature_algorithm.algorithm;
+        let scheme = if *algorithm == oid_registry::OID_SIG_ECDSA_WITH_SHA256 {
+            SignatureScheme::ECDSA_NISTP256_SHA256
+        } else if *algorithm == oid_registry::OID_SIG_ECDSA_WITH_SHA384 {
+            SignatureScheme::ECDSA_NISTP384_SHA384
+        } else if *algorithm == oid_registry::OID_SIG_ED25519 {
+            SignatureScheme::ED25519
+        } else if *algorithm == oid_registry::OID_PKCS1_SHA256WITHRSA {
+            SignatureScheme::RSA_PKCS1_SHA256
+        } else if *algorithm == oid_registry::OID_PKCS1_SHA384WITHRSA {
+            SignatureScheme::RSA_PKCS1_SHA384
+        } else if *algorithm == oid_registry::OID_PKCS1_SHA512WITHRSA {
+            SignatureScheme::RSA_PKCS1_SHA512
+        } else {
+            let reason = format!("unsupported signature algorithm {algorithm}");
+            return Err(PadawanError::InvalidCertificate(reason));
+        };
+        Ok(scheme)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_generated_certificate() {
+        let identity = identity::Keypair::generate_ed25519();
+        let (certificate, _) = generate(&identity).unwrap();
+        let parsed = Certificate::parse(&certificate).unwrap();
+        assert_eq!(parsed.peer_id(), identity.public().to_peer_id());
+
+        // Any change invalidates the self-signature or the extension
+        let mut tampered = certificate.clone();
+        let last = tampered.0.len() - 1;
+        tampered.0[last] ^= 1;
+        assert!(Certificate::parse(&tampered).is_err());
+    }
+}
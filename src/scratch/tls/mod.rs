@@ -0,0 +1,188 @@
+//! Implementation of the [`libp2p` TLS handshake][libp2p-tls-spec].
+//!
+//! The TLS 1.3 protocol itself is provided by [`rustls`][], which is driven
+//! without IO by the bytes exchanged with the remote peer.
+//!
+//! [libp2p-tls-spec]: https://github.com/libp2p/specs/blob/master/tls/tls.md
+use std::io::{Read, Write};
+use std::sync::Arc;
+
+use bytes::{Buf, BufMut, BytesMut};
+use libp2p::{identity, PeerId};
+
+use crate::error::PadawanError;
+
+use super::multistream_select::framed::Decoder;
+use certificate::Certificate;
+use verifier::Libp2pCertVerifier;
+
+pub mod certificate;
+pub mod stream;
+pub mod verifier;
+
+/// The protocol advertised through ALPN
+const ALPN: &[u8] = b"libp2p";
+
+/// The server name sent by the client, which is not verified by the server
+const SERVER_NAME: &str = "l";
+
+/// The TLS 1.3 cipher suites, in order of preference
+static CIPHER_SUITES: &[rustls::SupportedCipherSuite] = &[
+    rustls::cipher_suite::TLS13_CHACHA20_POLY1305_SHA256,
+    rustls::cipher_suite::TLS13_AES_256_GCM_SHA384,
+    rustls::cipher_suite::TLS13_AES_128_GCM_SHA256,
+];
+
+/// A TLS session with the remote peer, both during the handshake and
+/// once established.
+pub struct Session {
+    conn: rustls::Connection,
+    /// The decrypted data received from the remote peer
+    messages: Decoder,
+    /// Whether the remote peer closed the session
+    closed: bool,
+}
+
+impl Session {
+    /// Start the session of the peer that sends the first handshake message
+    pub fn client(identity: &identity::Keypair) -> Result<Self, PadawanError> {
+        let (certificate, key) = certificate::generate(identity)?;
+        let mut config = rustls::ClientConfig::builder()
+            .with_cipher_suites(CIPHER_SUITES)
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .with_custom_certificate_verifier(Arc::new(Libp2pCertVerifier))
+            .with_single_cert(vec![certificate], key)?;
+        config.alpn_protocols = vec![ALPN.to_vec()];
+        let name = rustls::ServerName::try_from(SERVER_NAME).expect("valid server name");
+        let conn = rustls::ClientConnection::new(Arc::new(config), name)?;
+        Ok(Self::new(conn.into()))
+    }
+
+    /// Start the session of the peer that responds to the handshake
+    pub fn server(identity: &identity::Keypair) -> Result<Self, PadawanError> {
+        let (certificate, key) = certificate::generate(identity)?;
+        let mut config = rustls::ServerConfig::builder()
+            .with_cipher_suites(CIPHER_SUITES)
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .with_client_cert_verifier(Arc::new(Libp2pCertVerifier))
+            .with_single_cert(vec![certificate], key)?;
+        config.alpn_protocols = vec![ALPN.to_vec()];
+        let conn = rustls::ServerConnection::new(Arc::new(config))?;
+        Ok(Self::new(conn.into()))
+    }
+
+    fn new(conn: rustls::Connection) -> Self {
+        Self {
+            conn,
+            messages: Default::default(),
+            closed: false,
+        }
+    }
+
+    /// Whether the handshake is still in progress
+    pub fn is_handshaking(&self) -> bool {
+        self.conn.is_handshaking()
+    }
+
+    /// Whether the remote peer closed the session
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// The [`PeerId`][] of the remote peer, once its certificate is verified
+    pub fn remote_peer_id(&self) -> Result<PeerId, PadawanError> {
+        let certificate = match self.conn.peer_certificates() {
+            Some([certificate]) => certificate,
+            _ => return Err(PadawanError::IdVerification),
+        };
+        Ok(Certificate::parse(certificate)?.peer_id())
+    }
+
+    /// The decrypted data received from the remote peer
+    pub fn messages(&mut self) -> &mut Decoder {
+        &mut self.messages
+    }
+
+    /// Process the TLS records received from the remote peer, consuming
+    /// the `received` bytes.
+    ///
+    /// Any decrypted data are appended to the [`messages`][`Session::messages`].
+    /// On failure, an alert for the remote peer may be collected with
+    /// [`write_tls`][`Session::write_tls`].
+    pub fn read_tls(&mut self, received: &mut BytesMut) -> Result<(), PadawanError> {
+        while !received.is_empty() {
+            let n = self.conn.read_tls(&mut &received[..])?;
+            received.advance(n);
+            let state = self.conn.process_new_packets()?;
+            let mut plaintext = vec![0; state.plaintext_bytes_to_read()];
+            self.conn.reader().read_exact(&mut plaintext)?;
+            self.messages.extend(&plaintext);
+            self.closed = state.peer_has_closed();
+        }
+        Ok(())
+    }
+
+    /// Append the TLS records to be sent to the remote peer to the `out` buffer
+    pub fn write_tls(&mut self, out: &mut BytesMut) -> Result<(), PadawanError> {
+        let mut writer = out.writer();
+        while self.conn.wants_write() {
+            self.conn.write_tls(&mut writer)?;
+        }
+        Ok(())
+    }
+
+    /// Encrypt the `plaintext` and append the resulting records to the `out` buffer
+    pub fn send(&mut self, plaintext: &[u8], out: &mut BytesMut) -> Result<(), PadawanError> {
+        self.conn.writer().write_all(plaintext)?;
+        self.write_tls(out)
+    }
+
+    /// Notify the remote peer that no more data will be sent
+    pub fn close(&mut self, out: &mut BytesMut) -> Result<(), PadawanError> {
+        self.conn.send_close_notify();
+        self.write_tls(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handshake() {
+        let (client_key, server_key) = (
+            identity::Keypair::generate_ed25519(),
+            identity::Keypair::generate_ed25519(),
+        );
+        let mut client = Session::client(&client_key).unwrap();
+        let mut server = Session::server(&server_key).unwrap();
+        let mut wire = BytesMut::new();
+        while client.is_handshaking() || server.is_handshaking() {
+            client.write_tls(&mut wire).unwrap();
+            server.read_tls(&mut wire).unwrap();
+            server.write_tls(&mut wire).unwrap();
+            client.read_tls(&mut wire).unwrap();
+        }
+        assert_eq!(
+            client.remote_peer_id().unwrap(),
+            server_key.public().to_peer_id()
+        );
+        assert_eq!(
+            server.remote_peer_id().unwrap(),
+            client_key.public().to_peer_id()
+        );
+
+        client.send(b"\x06hello\n", &mut wire).unwrap();
+        server.read_tls(&mut wire).unwrap();
+        assert_eq!(
+            server.messages().next_message().unwrap().unwrap(),
+            b"\x06hello\n"
+        );
+
+        client.close(&mut wire).unwrap();
+        server.read_tls(&mut wire).unwrap();
+        assert!(server.is_closed());
+    }
+}
@@ -0,0 +1,189 @@
+//! A secure channel over an established TLS session, usable as any other stream.
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::{Buf, BytesMut};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use super::Session;
+
+/// The number of bytes requested from the underlying stream on each read
+const READ_CHUNK_SIZE: usize = 8192;
+
+/// The maximum plaintext size of a TLS record
+const MAX_PLAINTEXT_SIZE: usize = 16 * 1024;
+
+/// Encrypt and decrypt the data exchanged over an underlying stream
+/// once the TLS handshake is complete.
+///
+/// The stream yields no more data once the remote peer closes the session,
+/// and shutting it down notifies the remote peer likewise.
+pub struct TlsStream<S> {
+    inner: S,
+    session: Session,
+    /// Records received but not yet processed
+    received: BytesMut,
+    /// Decrypted bytes not yet consumed
+    plaintext: BytesMut,
+    /// Records not yet written to the underlying stream
+    pending: BytesMut,
+    /// Whether the close notification was queued
+    closing: bool,
+}
+
+impl<S> TlsStream<S> {
+    /// Wrap the given stream with an established TLS session.
+    ///
+    /// Any decrypted data left in the session are yielded first.
+    pub fn new(inner: S, mut session: Session) -> Self {
+        let plaintext = session.messages().take_remaining();
+        Self {
+            inner,
+            session,
+            received: BytesMut::new(),
+            plaintext,
+            pending: BytesMut::new(),
+            closing: false,
+        }
+    }
+
+    /// Prepend records already read from the underlying stream
+    pub fn with_received(mut self, received: BytesMut) -> Self {
+        let buffered = std::mem::replace(&mut self.received, received);
+        self.received.unsplit(buffered);
+        self
+    }
+
+    /// Get a mutable reference to the underlying stream
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Get the underlying stream
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: AsyncWrite + Unpin> TlsStream<S> {
+    /// Write all pending records to the underlying stream
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            let n = match Pin::new(&mut self.inner).poll_write(cx, &self.pending) {
+                Poll::Ready(Ok(n)) => n,
+                other => return other.map_ok(|_| ()),
+            };
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pending.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TlsStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.plaintext.is_empty() {
+            if !this.received.is_empty() {
+                this.session.read_tls(&mut this.received)?;
+                this.plaintext = this.session.messages().take_remaining();
+                continue;
+            }
+            if this.session.is_closed() {
+                return Poll::Ready(Ok(()));
+            }
+            let mut chunk = [0_u8; READ_CHUNK_SIZE];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            match Pin::new(&mut this.inner).poll_read(cx, &mut chunk) {
+                Poll::Ready(Ok(())) => {}
+                other => return other,
+            }
+            if chunk.filled().is_empty() {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            this.received.extend_from_slice(chunk.filled());
+        }
+        let n = this.plaintext.len().min(buf.remaining());
+        buf.put_slice(&this.plaintext[..n]);
+        this.plaintext.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TlsStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.poll_pending(cx)?.is_pending() {
+            return Poll::Pending;
+        }
+        let n = buf.len().min(MAX_PLAINTEXT_SIZE);
+        this.session.send(&buf[..n], &mut this.pending)?;
+        let _ = this.poll_pending(cx)?;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.poll_pending(cx)?.is_pending() {
+            return Poll::Pending;
+        }
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.closing {
+            this.closing = true;
+            this.session.close(&mut this.pending)?;
+        }
+        if this.poll_pending(cx)?.is_pending() {
+            return Poll::Pending;
+        }
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch::connection::Connection;
+    use crate::scratch::upgrade::Security;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn echo() {
+        let (dialer, listener) = tokio::io::duplex(4096);
+        let mut dialer = Connection::from(dialer).with_security(&[Security::Tls]);
+        let mut listener = Connection::from(listener).with_security(&[Security::Tls]);
+        let (dialed, listened) = tokio::join!(dialer.dial(), listener.listen());
+        dialed.unwrap();
+        listened.unwrap();
+        let mut dialer = dialer.into_tls_stream().unwrap();
+        let mut listener = listener.into_tls_stream().unwrap();
+
+        let message = vec![42; 3 * MAX_PLAINTEXT_SIZE];
+        let (_, read) = tokio::join!(
+            async {
+                dialer.write_all(&message).await.unwrap();
+                dialer.shutdown().await.unwrap();
+            },
+            async {
+                let mut received = Vec::new();
+                listener.read_to_end(&mut received).await.unwrap();
+                received
+            }
+        );
+        assert_eq!(read, message);
+    }
+}
@@ -0,0 +1,131 @@
+//! Verification of the certificates presented in the `libp2p` TLS handshake.
+//!
+//! Certificates are self-signed, so they are not chained to any authority.
+//! Instead, they are trusted by the identity key that they carry.
+use std::time::SystemTime;
+
+use rustls::client::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::server::{ClientCertVerified, ClientCertVerifier};
+use rustls::{DigitallySignedStruct, DistinguishedNames, Error, ServerName, SignatureScheme};
+
+use super::certificate::Certificate;
+
+/// The signature schemes accepted in the handshake, in order of preference
+const SIGNATURE_SCHEMES: &[SignatureScheme] = &[
+    SignatureScheme::ECDSA_NISTP256_SHA256,
+    SignatureScheme::ECDSA_NISTP384_SHA384,
+    SignatureScheme::ED25519,
+    SignatureScheme::RSA_PSS_SHA256,
+    SignatureScheme::RSA_PSS_SHA384,
+    SignatureScheme::RSA_PSS_SHA512,
+];
+
+/// Verify the certificates of remote peers, both as a client and as a server
+pub struct Libp2pCertVerifier;
+
+impl Libp2pCertVerifier {
+    /// Verify the single certificate of the remote peer
+    fn verify_cert(
+        end_entity: &rustls::Certificate,
+        intermediates: &[rustls::Certificate],
+    ) -> Result<(), Error> {
+        if !intermediates.is_empty() {
+            return Err(Error::General("expected a single certificate".to_string()));
+        }
+        Certificate::parse(end_entity)
+            .map(|_| ())
+            .map_err(|err| Error::InvalidCertificateData(err.to_string()))
+    }
+
+    fn verify_tls13(
+        message: &[u8],
+        cert: &rustls::Certificate,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        Certificate::parse(cert)
+            .and_then(|cert| cert.verify_signature(dss.scheme, message, dss.signature()))
+            .map(|_| HandshakeSignatureValid::assertion())
+            .map_err(|_| Error::InvalidCertificateSignature)
+    }
+}
+
+impl ServerCertVerifier for Libp2pCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        intermediates: &[rustls::Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, Error> {
+        Self::verify_cert(end_entity, intermediates).map(|_| ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        _message: &[u8],
+        _cert: &rustls::Certificate,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        Err(Error::PeerIncompatibleError(
+            "only TLS 1.3 is supported".to_string(),
+        ))
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::Certificate,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        Self::verify_tls13(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        SIGNATURE_SCHEMES.to_vec()
+    }
+
+    fn request_scts(&self) -> bool {
+        false
+    }
+}
+
+impl ClientCertVerifier for Libp2pCertVerifier {
+    fn client_auth_root_subjects(&self) -> Option<DistinguishedNames> {
+        Some(Vec::new())
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        intermediates: &[rustls::Certificate],
+        _now: SystemTime,
+    ) -> Result<ClientCertVerified, Error> {
+        Self::verify_cert(end_entity, intermediates).map(|_| ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        _message: &[u8],
+        _cert: &rustls::Certificate,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        Err(Error::PeerIncompatibleError(
+            "only TLS 1.3 is supported".to_string(),
+        ))
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::Certificate,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        Self::verify_tls13(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        SIGNATURE_SCHEMES.to_vec()
+    }
+}
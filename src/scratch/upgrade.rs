@@ -0,0 +1,1187 @@
+//! A sans-IO implementation of the connection upgrade.
+//!
+//! [`Upgrade`][] consumes the bytes received from the remote peer and
+//! produces the bytes to be sent back along with [`Event`][]s that
+//! describe the progress of the upgrade. It performs no IO by itself,
+//! so it can be driven by any event loop, or stepped deterministically in tests.
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+
+use bytes::BytesMut;
+use libp2p::{identity, PeerId};
+
+use crate::error::PadawanError;
+
+use super::connection::HandshakeState;
+use super::multistream_select::{framed, Message, ProtocolName, Version};
+use super::noise::{self, config::NoiseConfig, keys::StaticKeys, libp2p::NoiseHandshake};
+#[cfg(feature = "plaintext")]
+use super::plaintext;
+use super::tls;
+
+/// The role of the local peer in the upgrade
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Dialer,
+    Listener,
+}
+
+/// The security protocols of the upgrade
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Security {
+    #[default]
+    Noise,
+    Tls,
+    /// No encryption at all, for debugging only
+    #[cfg(feature = "plaintext")]
+    Plaintext,
+}
+
+impl Security {
+    /// The name of the protocol negotiated through `multistream_select`
+    pub fn protocol(self) -> ProtocolName {
+        match self {
+            Self::Noise => ProtocolName::NOISE,
+            Self::Tls => ProtocolName::TLS,
+            #[cfg(feature = "plaintext")]
+            Self::Plaintext => ProtocolName::PLAINTEXT,
+        }
+    }
+}
+
+impl fmt::Display for Security {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Noise => f.write_str("noise"),
+            Self::Tls => f.write_str("tls"),
+            #[cfg(feature = "plaintext")]
+            Self::Plaintext => f.write_str("plaintext"),
+        }
+    }
+}
+
+impl FromStr for Security {
+    type Err = PadawanError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "noise" => Ok(Self::Noise),
+            "tls" => Ok(Self::Tls),
+            #[cfg(feature = "plaintext")]
+            "plaintext" => Ok(Self::Plaintext),
+            _ => Err(PadawanError::UnsupportedSecurityProtocol(name.to_string())),
+        }
+    }
+}
+
+/// The stream muxers of the upgrade
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Muxer {
+    #[default]
+    Yamux,
+    /// Only spoken by older `libp2p` peers
+    Mplex,
+}
+
+impl Muxer {
+    /// The name of the protocol negotiated through `multistream_select`
+    pub fn protocol(self) -> ProtocolName {
+        match self {
+            Self::Yamux => ProtocolName::YAMUX,
+            Self::Mplex => ProtocolName::MPLEX,
+        }
+    }
+
+    fn from_protocol(protocol: &ProtocolName) -> Option<Self> {
+        [Self::Yamux, Self::Mplex]
+            .into_iter()
+            .find(|muxer| muxer.protocol() == *protocol)
+    }
+}
+
+impl fmt::Display for Muxer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Yamux => f.write_str("yamux"),
+            Self::Mplex => f.write_str("mplex"),
+        }
+    }
+}
+
+impl FromStr for Muxer {
+    type Err = PadawanError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "yamux" => Ok(Self::Yamux),
+            "mplex" => Ok(Self::Mplex),
+            _ => Err(PadawanError::UnsupportedMuxer(name.to_string())),
+        }
+    }
+}
+
+/// The secure channel of an established connection
+pub enum SecureTransport {
+    Noise(noise::Transport),
+    Tls(tls::Session),
+    /// The messages are exchanged unencrypted
+    #[cfg(feature = "plaintext")]
+    Plaintext,
+}
+
+impl SecureTransport {
+    /// Encrypt the `message` and append it to the `out` buffer
+    fn send(&mut self, message: &[u8], out: &mut BytesMut) -> Result<(), PadawanError> {
+        match self {
+            Self::Noise(transport) => out.extend_from_slice(transport.encrypt_frame(message)?),
+            Self::Tls(session) => session.send(message, out)?,
+            #[cfg(feature = "plaintext")]
+            Self::Plaintext => out.extend_from_slice(message),
+        }
+        Ok(())
+    }
+
+    /// Get the next complete message, decrypting the `received` bytes as needed
+    fn recv(&mut self, received: &mut BytesMut) -> Result<Option<Vec<u8>>, PadawanError> {
+        let transport = match self {
+            Self::Noise(transport) => transport,
+            Self::Tls(session) => {
+                session.read_tls(received)?;
+                return session.messages().next_message();
+            }
+            #[cfg(feature = "plaintext")]
+            Self::Plaintext => {
+                return framed::decode_message(received, framed::MAX_MESSAGE_SIZE);
+            }
+        };
+        loop {
+            if let Some(message) = transport.buffer().messages().next_message()? {
+                return Ok(Some(message));
+            }
+            let frame = match noise::wire::decode_frame(received)? {
+                Some(frame) => frame,
+                None => return Ok(None),
+            };
+            *transport.buffer().encrypted() = frame;
+            transport.decrypt_frame()?;
+        }
+    }
+}
+
+/// The phase of the upgrade
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Exchange of the `multistream_select` headers
+    Initialization,
+    /// Negotiation of the security protocol
+    Negotiation,
+    /// The noise handshake
+    Noise,
+    /// The TLS handshake
+    Tls,
+    /// The exchange of the plaintext identities
+    #[cfg(feature = "plaintext")]
+    Plaintext,
+    /// Negotiation of the multiplex protocol
+    Multiplex,
+}
+
+/// Events emitted during the upgrade
+#[derive(Debug)]
+pub enum Event {
+    /// A protocol was agreed with the remote peer
+    ProtocolAgreed(ProtocolName),
+    /// The remote peer proved its identity during the security handshake
+    RemoteIdentified(PeerId),
+    /// The upgrade completed successfully
+    Established,
+    /// The upgrade failed and cannot proceed any further
+    Failed { phase: Phase, reason: PadawanError },
+}
+
+/// The internal progress of the upgrade
+enum Step {
+    Initialization,
+    Negotiation,
+    Noise(Box<NoiseHandshake>),
+    Tls(Box<tls::Session>),
+    #[cfg(feature = "plaintext")]
+    Plaintext,
+    Multiplex(Box<SecureTransport>),
+    Established(Box<SecureTransport>),
+    /// The upgrade failed, or is between steps, during the given phase
+    Failed(Phase),
+}
+
+/// The state machine of the connection upgrade.
+///
+/// Any bytes produced after construction or after feeding input through
+/// [`handle_input`][`Upgrade::handle_input`] must be collected with
+/// [`poll_transmit`][`Upgrade::poll_transmit`] and sent to the remote peer.
+pub struct Upgrade {
+    role: Role,
+    version: Version,
+    keypair: identity::Keypair,
+    step: Step,
+    started: bool,
+    /// Bytes received but not yet processed
+    received: BytesMut,
+    /// Bytes to be sent to the remote peer
+    outgoing: BytesMut,
+    events: VecDeque<Event>,
+    /// Messages expected from the remote peer before any other, in order
+    expected: VecDeque<ProtocolName>,
+    /// The index of the protocol currently proposed by the dialer
+    candidate: usize,
+    /// The peer the remote is required to authenticate as
+    expected_peer: Option<PeerId>,
+    /// The authenticated identity of the remote peer
+    remote_peer: Option<PeerId>,
+    /// Whether to advertise the multiplex protocols in the noise handshake
+    early_muxer: bool,
+    /// The static key of the local peer for the noise handshake
+    static_keys: StaticKeys,
+    /// The noise protocol of the handshake
+    noise_config: NoiseConfig,
+    /// The security protocols proposed or accepted, in order of preference
+    security: Vec<Security>,
+    /// The stream muxers proposed or accepted, in order of preference
+    muxers: Vec<Muxer>,
+    /// The stream muxer agreed with the remote peer
+    muxer: Option<Muxer>,
+}
+
+impl Upgrade {
+    /// Create a new upgrade for the local peer with the given role
+    pub fn new(role: Role, keypair: identity::Keypair) -> Self {
+        Self {
+            role,
+            version: Default::default(),
+            step: Step::Initialization,
+            started: false,
+            received: BytesMut::new(),
+            outgoing: BytesMut::new(),
+            events: VecDeque::new(),
+            expected: VecDeque::new(),
+            candidate: 0,
+            expected_peer: None,
+            remote_peer: None,
+            early_muxer: true,
+            static_keys: StaticKeys::new(keypair.clone()),
+            noise_config: Default::default(),
+            security: vec![Security::Noise],
+            muxers: vec![Muxer::Yamux, Muxer::Mplex],
+            muxer: None,
+            keypair,
+        }
+    }
+
+    /// Set the `multistream_select` variant used when dialing.
+    ///
+    /// [`V1Lazy`][`Version::V1Lazy`] only applies to negotiations of a single
+    /// candidate. Since yamux then mplex are proposed by default, the stream
+    /// muxer is only negotiated lazily once restricted to one through
+    /// [`with_muxers`][`Self::with_muxers`], and never when agreed during the
+    /// noise handshake.
+    pub fn with_version(mut self, version: Version) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Require the remote peer to authenticate as the given [`PeerId`][]
+    /// during the security handshake.
+    pub fn with_expected_peer(mut self, peer_id: PeerId) -> Self {
+        self.expected_peer = Some(peer_id);
+        self
+    }
+
+    /// Enable or disable the negotiation of the multiplex protocol within
+    /// the noise handshake, which is enabled by default. It does not apply to TLS.
+    ///
+    /// If either peer does not advertise a common stream muxer, the multiplex
+    /// protocol is negotiated through `multistream_select` once the handshake completes.
+    pub fn with_early_muxer(mut self, enabled: bool) -> Self {
+        self.early_muxer = enabled;
+        self
+    }
+
+    /// Use the static key of the local peer shared with its other connections,
+    /// instead of generating one for this upgrade.
+    pub fn with_static_keys(mut self, static_keys: StaticKeys) -> Self {
+        self.static_keys = static_keys;
+        self
+    }
+
+    /// Speak the noise protocol of the given `config`, instead of the one of `libp2p`.
+    ///
+    /// The handshake fails with [`PadawanError::NoiseProtocolMismatch`][] if the
+    /// remote peer speaks a different protocol.
+    pub fn with_noise_config(mut self, config: NoiseConfig) -> Self {
+        self.noise_config = config;
+        self
+    }
+
+    /// Propose, or accept as a listener, the given security protocols in order
+    /// of preference, instead of noise alone.
+    pub fn with_security(mut self, protocols: &[Security]) -> Self {
+        self.security = protocols.to_vec();
+        self
+    }
+
+    /// Propose, or accept as a listener, the given stream muxers in order
+    /// of preference, instead of yamux then mplex.
+    pub fn with_muxers(mut self, muxers: &[Muxer]) -> Self {
+        self.muxers = muxers.to_vec();
+        self
+    }
+
+    /// Skip the exchange of the `multistream_select` headers, e.g. when
+    /// they were already exchanged over the same stream.
+    pub fn headers_exchanged(mut self) -> Self {
+        if let Step::Initialization = self.step {
+            self.step = Step::Negotiation;
+        }
+        self
+    }
+
+    /// The coarse state of the upgrade
+    pub fn state(&self) -> HandshakeState {
+        match self.step {
+            Step::Initialization => HandshakeState::Initialization,
+            Step::Negotiation => HandshakeState::Negotiation,
+            Step::Noise(_) => HandshakeState::Noise,
+            Step::Tls(_) => HandshakeState::Tls,
+            #[cfg(feature = "plaintext")]
+            Step::Plaintext => HandshakeState::Plaintext,
+            Step::Multiplex(_) => HandshakeState::Multiplex,
+            Step::Established(_) => HandshakeState::Established,
+            Step::Failed(_) => HandshakeState::Failed,
+        }
+    }
+
+    /// The identity of the remote peer, once verified in the security handshake
+    pub fn remote_peer_id(&self) -> Option<&PeerId> {
+        self.remote_peer.as_ref()
+    }
+
+    /// The stream muxer agreed with the remote peer, once the upgrade is established
+    pub fn muxer(&self) -> Option<Muxer> {
+        self.muxer
+    }
+
+    /// Process bytes received from the remote peer
+    pub fn handle_input(&mut self, bytes: &[u8]) {
+        self.ensure_started();
+        self.received.extend_from_slice(bytes);
+        self.advance();
+    }
+
+    /// Get the bytes to be sent to the remote peer, if any
+    ///
+    /// The allocation of the returned bytes is reused for subsequent
+    /// output once they are dropped.
+    pub fn poll_transmit(&mut self) -> Option<BytesMut> {
+        self.ensure_started();
+        if self.outgoing.is_empty() {
+            return None;
+        }
+        Some(self.outgoing.split())
+    }
+
+    /// Get the next event of the upgrade, if any
+    pub fn poll_event(&mut self) -> Option<Event> {
+        self.ensure_started();
+        self.events.pop_front()
+    }
+
+    /// Get the secure transport of an established connection, along with
+    /// any bytes received beyond the end of the upgrade.
+    pub fn into_transport(self) -> Option<(SecureTransport, BytesMut)> {
+        match self.step {
+            Step::Established(transport) => Some((*transport, self.received)),
+            _ => None,
+        }
+    }
+
+    fn ensure_started(&mut self) {
+        if !self.started {
+            self.started = true;
+            if let Err(reason) = self.start() {
+                self.fail(reason);
+            }
+        }
+    }
+
+    /// Queue the messages that the local peer sends unprompted
+    fn start(&mut self) -> Result<(), PadawanError> {
+        if let Step::Initialization = self.step {
+            self.expect(ProtocolName::MULTISTREAM)?;
+        }
+        if self.role == Role::Dialer {
+            let protocols = self.protocols();
+            if self.is_lazy(&protocols) {
+                self.expect(protocols[0].clone())?;
+                self.start_security(&protocols[0])?;
+            } else if let Step::Negotiation = self.step {
+                self.propose(0)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn advance(&mut self) {
+        loop {
+            match self.step() {
+                Ok(true) => continue,
+                Ok(false) => break,
+                Err(reason) => {
+                    self.fail(reason);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn fail(&mut self, reason: PadawanError) {
+        let phase = self.phase();
+        self.step = Step::Failed(phase);
+        self.events.push_back(Event::Failed { phase, reason });
+    }
+
+    fn phase(&self) -> Phase {
+        match self.step {
+            Step::Initialization => Phase::Initialization,
+            Step::Negotiation => Phase::Negotiation,
+            Step::Noise(_) => Phase::Noise,
+            Step::Tls(_) => Phase::Tls,
+            #[cfg(feature = "plaintext")]
+            Step::Plaintext => Phase::Plaintext,
+            Step::Multiplex(_) | Step::Established(_) => Phase::Multiplex,
+            Step::Failed(phase) => phase,
+        }
+    }
+
+    /// Whether a single candidate can be proposed optimistically
+    fn is_lazy(&self, protocols: &[ProtocolName]) -> bool {
+        self.role == Role::Dialer && self.version == Version::V1Lazy && protocols.len() == 1
+    }
+
+    /// The protocols negotiated in the current step
+    fn protocols(&self) -> Vec<ProtocolName> {
+        match self.step {
+            Step::Multiplex(_) | Step::Established(_) => self.muxer_protocols(),
+            _ => self
+                .security
+                .iter()
+                .map(|security| security.protocol())
+                .collect(),
+        }
+    }
+
+    /// Make progress with the bytes received so far.
+    ///
+    /// Returns `true` if any progress was made.
+    fn step(&mut self) -> Result<bool, PadawanError> {
+        match self.step {
+            Step::Established(_) | Step::Failed(_) => return Ok(false),
+            Step::Noise(_) if self.expected.is_empty() => return self.step_noise(),
+            Step::Tls(_) if self.expected.is_empty() => return self.step_tls(),
+            #[cfg(feature = "plaintext")]
+            Step::Plaintext if self.expected.is_empty() => return self.step_plaintext(),
+            _ => {}
+        }
+        let message = match self.recv()? {
+            Some(message) => message,
+            None => return Ok(false),
+        };
+        if let Some(expected) = self.expected.pop_front() {
+            self.confirm(expected, Message::decode(&message)?)?;
+            return Ok(true);
+        }
+        match self.role {
+            Role::Dialer => self.on_response(Message::decode(&message)?)?,
+            Role::Listener => self.on_request(Message::decode_proposal(&message)?)?,
+        }
+        Ok(true)
+    }
+
+    /// Process the next frame of the noise handshake
+    fn step_noise(&mut self) -> Result<bool, PadawanError> {
+        let frame = match noise::wire::decode_frame(&mut self.received)? {
+            Some(frame) => frame,
+            None => return Ok(false),
+        };
+        let handshake = match self.step {
+            Step::Noise(ref mut handshake) => handshake,
+            _ => unreachable!("only called during the noise handshake"),
+        };
+        if let Some(peer_id) = handshake.read_message(&frame)? {
+            verify_peer(self.expected_peer, peer_id)?;
+            self.remote_peer = Some(peer_id);
+            self.events.push_back(Event::RemoteIdentified(peer_id));
+        }
+        if !handshake.is_finished() {
+            noise::wire::encode_frame(handshake.write_message(&self.keypair)?, &mut self.outgoing)?;
+        }
+        // The handshake may be concluded by the message of either peer
+        if !handshake.is_finished() {
+            return Ok(true);
+        }
+        let muxer = handshake.negotiated_muxer().cloned();
+        let step = std::mem::replace(&mut self.step, Step::Failed(Phase::Noise));
+        if let Step::Noise(handshake) = step {
+            let transport = handshake.into_inner().try_into()?;
+            self.step = Step::Multiplex(Box::new(SecureTransport::Noise(transport)));
+        }
+        match muxer {
+            Some(muxer) => {
+                tracing::debug!("Negotiated {} during the noise handshake", muxer);
+                self.on_agreed(muxer)?;
+            }
+            None => self.start_multiplex()?,
+        }
+        Ok(true)
+    }
+
+    /// Process the TLS records received so far
+    fn step_tls(&mut self) -> Result<bool, PadawanError> {
+        if self.received.is_empty() {
+            return Ok(false);
+        }
+        let session = match self.step {
+            Step::Tls(ref mut session) => session,
+            _ => unreachable!("only called during the TLS handshake"),
+        };
+        let processed = session.read_tls(&mut self.received);
+        // Send any alert on failure as well
+        session.write_tls(&mut self.outgoing)?;
+        processed?;
+        if session.is_handshaking() {
+            return Ok(true);
+        }
+        let peer_id = session.remote_peer_id()?;
+        verify_peer(self.expected_peer, peer_id)?;
+        self.remote_peer = Some(peer_id);
+        self.events.push_back(Event::RemoteIdentified(peer_id));
+        if let Step::Tls(session) = std::mem::replace(&mut self.step, Step::Failed(Phase::Tls)) {
+            self.step = Step::Multiplex(Box::new(SecureTransport::Tls(*session)));
+        }
+        self.start_multiplex()?;
+        Ok(true)
+    }
+
+    /// Process the plaintext identity of the remote peer, once received
+    #[cfg(feature = "plaintext")]
+    fn step_plaintext(&mut self) -> Result<bool, PadawanError> {
+        let peer_id = match plaintext::decode_exchange(&mut self.received)? {
+            Some(peer_id) => peer_id,
+            None => return Ok(false),
+        };
+        verify_peer(self.expected_peer, peer_id)?;
+        tracing::warn!("Connection to {} is not encrypted", peer_id);
+        self.remote_peer = Some(peer_id);
+        self.events.push_back(Event::RemoteIdentified(peer_id));
+        self.step = Step::Multiplex(Box::new(SecureTransport::Plaintext));
+        self.start_multiplex()?;
+        Ok(true)
+    }
+
+    /// Validate a message the remote peer was expected to send
+    fn confirm(&mut self, expected: ProtocolName, message: Message) -> Result<(), PadawanError> {
+        match message {
+            Message::Protocol(ref protocol) if *protocol == expected => {}
+            Message::NotAvailable => return Err(PadawanError::NegotiationFailed),
+            _ => return Err(PadawanError::UnexpectedMultistream),
+        }
+        if expected != ProtocolName::MULTISTREAM {
+            return self.on_agreed(expected);
+        }
+        match (&self.step, self.role) {
+            (Step::Initialization, Role::Dialer) => {
+                self.step = Step::Negotiation;
+                self.propose(0)
+            }
+            (Step::Initialization, Role::Listener) => {
+                self.step = Step::Negotiation;
+                Ok(())
+            }
+            (Step::Multiplex(_), Role::Dialer) if self.expected.is_empty() => self.propose(0),
+            _ => Ok(()),
+        }
+    }
+
+    /// Handle the response of the remote peer to a proposal of the dialer
+    fn on_response(&mut self, message: Message) -> Result<(), PadawanError> {
+        let protocols = self.protocols();
+        let proposed = &protocols[self.candidate];
+        match message {
+            Message::Protocol(ref protocol) if protocol == proposed => {
+                self.on_agreed(protocol.clone())
+            }
+            Message::NotAvailable if self.candidate + 1 < protocols.len() => {
+                tracing::debug!("Remote peer does not support {}", proposed);
+                self.propose(self.candidate + 1)
+            }
+            Message::NotAvailable => Err(PadawanError::NegotiationFailed),
+            _ => Err(PadawanError::UnexpectedMultistream),
+        }
+    }
+
+    /// Handle a request of the dialer as a listener
+    fn on_request(&mut self, message: Message) -> Result<(), PadawanError> {
+        let supported = self.protocols();
+        let response = match message {
+            Message::Protocol(protocol) if supported.contains(&protocol) => {
+                self.send(&protocol.encode())?;
+                return self.on_agreed(protocol);
+            }
+            Message::ListProtocols => Message::Protocols(supported),
+            message => {
+                tracing::debug!("Rejecting unsupported proposal {:?}", message);
+                Message::NotAvailable
+            }
+        };
+        self.send(&response.encode())
+    }
+
+    /// Move to the next step once a protocol is agreed
+    fn on_agreed(&mut self, protocol: ProtocolName) -> Result<(), PadawanError> {
+        self.events
+            .push_back(Event::ProtocolAgreed(protocol.clone()));
+        let phase = self.phase();
+        match std::mem::replace(&mut self.step, Step::Failed(phase)) {
+            Step::Initialization | Step::Negotiation => self.start_security(&protocol),
+            Step::Multiplex(transport) => {
+                self.muxer = Muxer::from_protocol(&protocol);
+                self.step = Step::Established(transport);
+                self.events.push_back(Event::Established);
+                Ok(())
+            }
+            step => {
+                self.step = step;
+                Ok(())
+            }
+        }
+    }
+
+    fn start_security(&mut self, protocol: &ProtocolName) -> Result<(), PadawanError> {
+        // Failures to set up the handshake are reported for its phase
+        #[cfg(feature = "plaintext")]
+        if *protocol == ProtocolName::PLAINTEXT {
+            self.step = Step::Failed(Phase::Plaintext);
+            return self.start_plaintext();
+        }
+        if *protocol == ProtocolName::TLS {
+            self.step = Step::Failed(Phase::Tls);
+            self.start_tls()
+        } else {
+            self.step = Step::Failed(Phase::Noise);
+            self.start_noise()
+        }
+    }
+
+    fn start_noise(&mut self) -> Result<(), PadawanError> {
+        let static_key = self.static_keys.current()?;
+        let config = self.noise_config.clone();
+        let handshake = match self.role {
+            Role::Dialer => NoiseHandshake::dialer_with_config(static_key, config)?,
+            Role::Listener => NoiseHandshake::listener_with_config(static_key, config)?,
+        };
+        let mut handshake = if self.early_muxer {
+            handshake.with_muxers(&self.muxer_protocols())
+        } else {
+            handshake
+        };
+        if handshake.is_my_turn() {
+            noise::wire::encode_frame(handshake.write_message(&self.keypair)?, &mut self.outgoing)?;
+        }
+        self.step = Step::Noise(Box::new(handshake));
+        Ok(())
+    }
+
+    fn start_tls(&mut self) -> Result<(), PadawanError> {
+        let mut session = match self.role {
+            Role::Dialer => tls::Session::client(&self.keypair)?,
+            Role::Listener => tls::Session::server(&self.keypair)?,
+        };
+        session.write_tls(&mut self.outgoing)?;
+        self.step = Step::Tls(Box::new(session));
+        Ok(())
+    }
+
+    #[cfg(feature = "plaintext")]
+    fn start_plaintext(&mut self) -> Result<(), PadawanError> {
+        plaintext::encode_exchange(&self.keypair.public(), &mut self.outgoing)?;
+        self.step = Step::Plaintext;
+        Ok(())
+    }
+
+    fn start_multiplex(&mut self) -> Result<(), PadawanError> {
+        self.expect(ProtocolName::MULTISTREAM)?;
+        let protocols = self.muxer_protocols();
+        if self.is_lazy(&protocols) {
+            self.expect(protocols[0].clone())?;
+        }
+        Ok(())
+    }
+
+    /// The stream muxers proposed to the remote peer, in order of preference
+    fn muxer_protocols(&self) -> Vec<ProtocolName> {
+        self.muxers.iter().map(|muxer| muxer.protocol()).collect()
+    }
+
+    /// Send a protocol that the remote peer is expected to echo
+    fn expect(&mut self, protocol: ProtocolName) -> Result<(), PadawanError> {
+        self.send(&protocol.encode())?;
+        self.expected.push_back(protocol);
+        Ok(())
+    }
+
+    fn propose(&mut self, candidate: usize) -> Result<(), PadawanError> {
+        self.candidate = candidate;
+        let proposal = match self.protocols().get(candidate) {
+            Some(protocol) => protocol.encode(),
+            None => return Err(PadawanError::NegotiationFailed),
+        };
+        self.send(&proposal)
+    }
+
+    /// Queue a message, encrypting it if the secure transport is available
+    fn send(&mut self, message: &[u8]) -> Result<(), PadawanError> {
+        match self.step {
+            Step::Multiplex(ref mut transport) | Step::Established(ref mut transport) => {
+                transport.send(message, &mut self.outgoing)
+            }
+            _ => {
+                self.outgoing.extend_from_slice(message);
+                Ok(())
+            }
+        }
+    }
+
+    /// Get the next complete message, decrypting it if the secure transport is available
+    fn recv(&mut self) -> Result<Option<Vec<u8>>, PadawanError> {
+        match self.step {
+            Step::Multiplex(ref mut transport) => transport.recv(&mut self.received),
+            _ => framed::decode_message(&mut self.received, framed::MAX_MESSAGE_SIZE),
+        }
+    }
+}
+
+/// Verify that the remote peer authenticated as the `expected` one, if any
+fn verify_peer(expected: Option<PeerId>, actual: PeerId) -> Result<(), PadawanError> {
+    match expected {
+        Some(expected) if expected != actual => Err(PadawanError::PeerIdMismatch {
+            expected: Box::new(expected),
+            actual: Box::new(actual),
+        }),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upgrade(role: Role, version: Version) -> Upgrade {
+        Upgrade::new(role, identity::Keypair::generate_ed25519()).with_version(version)
+    }
+
+    /// Exchange bytes between the two peers until neither has anything to send
+    ///
+    /// Returns the number of exchanges.
+    fn pump(dialer: &mut Upgrade, listener: &mut Upgrade) -> usize {
+        let mut exchanges = 0;
+        loop {
+            let (to_listener, to_dialer) = (dialer.poll_transmit(), listener.poll_transmit());
+            if to_listener.is_none() && to_dialer.is_none() {
+                break exchanges;
+            }
+            exchanges += 1;
+            if let Some(bytes) = to_listener {
+                listener.handle_input(&bytes);
+            }
+            if let Some(bytes) = to_dialer {
+                dialer.handle_input(&bytes);
+            }
+        }
+    }
+
+    fn events(upgrade: &mut Upgrade) -> Vec<Event> {
+        std::iter::from_fn(|| upgrade.poll_event()).collect()
+    }
+
+    fn assert_established(events: &[Event]) {
+        assert_established_with(events, ProtocolName::NOISE);
+    }
+
+    fn assert_established_with(events: &[Event], security: ProtocolName) {
+        assert!(matches!(
+            events,
+            [
+                Event::ProtocolAgreed(agreed),
+                Event::RemoteIdentified(_),
+                Event::ProtocolAgreed(yamux),
+                Event::Established
+            ] if *agreed == security && *yamux == ProtocolName::YAMUX
+        ));
+    }
+
+    #[test]
+    fn establish() {
+        for version in [Version::V1, Version::V1Lazy] {
+            for early_muxer in [true, false] {
+                let mut dialer = upgrade(Role::Dialer, version).with_early_muxer(early_muxer);
+                let mut listener = upgrade(Role::Listener, version);
+                pump(&mut dialer, &mut listener);
+                assert_established(&events(&mut dialer));
+                assert_established(&events(&mut listener));
+                assert_eq!(dialer.muxer(), Some(Muxer::Yamux));
+                assert!(dialer.into_transport().is_some());
+                assert!(listener.into_transport().is_some());
+            }
+        }
+    }
+
+    #[test]
+    fn reuse_static_key() {
+        let keypair = identity::Keypair::generate_ed25519();
+        let keys = StaticKeys::new(keypair.clone());
+        let mut remote_statics = Vec::new();
+        for _ in 0..2 {
+            let mut dialer = upgrade(Role::Dialer, Version::V1);
+            let mut listener =
+                Upgrade::new(Role::Listener, keypair.clone()).with_static_keys(keys.clone());
+            pump(&mut dialer, &mut listener);
+            assert_established(&events(&mut dialer));
+            let Some((SecureTransport::Noise(transport), _)) = dialer.into_transport() else {
+                panic!("expected a noise transport");
+            };
+            remote_statics.push(transport.remote_static().unwrap().to_vec());
+        }
+        assert_eq!(remote_statics[0], remote_statics[1]);
+        assert_eq!(remote_statics[0], keys.current().unwrap().public());
+    }
+
+    #[test]
+    fn establish_noise_protocols() {
+        for name in [
+            "Noise_XX_25519_AESGCM_SHA256",
+            "Noise_XX_25519_ChaChaPoly_BLAKE2s",
+            "Noise_IK_25519_ChaChaPoly_SHA256",
+            "Noise_IK_25519_AESGCM_BLAKE2b",
+            "Noise_XXfallback_25519_ChaChaPoly_SHA256",
+        ] {
+            let config: NoiseConfig = name.parse().unwrap();
+            let keypair = identity::Keypair::generate_ed25519();
+            let keys = StaticKeys::new(keypair.clone());
+            let remote_static = keys.current().unwrap().public().to_vec();
+            let mut dialer = upgrade(Role::Dialer, Version::V1)
+                .with_noise_config(config.clone().with_remote_static(&remote_static));
+            let mut listener = Upgrade::new(Role::Listener, keypair)
+                .with_static_keys(keys)
+                .with_noise_config(config);
+            pump(&mut dialer, &mut listener);
+            assert_established(&events(&mut dialer));
+            assert_established(&events(&mut listener));
+            let Some((SecureTransport::Noise(transport), _)) = dialer.into_transport() else {
+                panic!("expected a noise transport");
+            };
+            assert_eq!(transport.config().to_string(), name);
+        }
+    }
+
+    #[test]
+    fn fall_back_on_outdated_remote_static() {
+        let config: NoiseConfig = "Noise_XXfallback_25519_ChaChaPoly_SHA256".parse().unwrap();
+        let outdated = noise::generate_keypair().unwrap().public;
+        let listener_key = identity::Keypair::generate_ed25519();
+        let listener_id = PeerId::from_public_key(&listener_key.public());
+        let mut dialer = upgrade(Role::Dialer, Version::V1)
+            .with_noise_config(config.clone().with_remote_static(&outdated))
+            .with_expected_peer(listener_id);
+        let mut listener = Upgrade::new(Role::Listener, listener_key).with_noise_config(config);
+        pump(&mut dialer, &mut listener);
+        assert_established(&events(&mut dialer));
+        assert_established(&events(&mut listener));
+        assert_eq!(dialer.remote_peer_id(), Some(&listener_id));
+        assert!(listener.remote_peer_id().is_some());
+    }
+
+    #[test]
+    fn report_noise_protocol_mismatch() {
+        let assert_mismatch = |upgrade: &mut Upgrade, name: &str| {
+            assert!(matches!(
+                events(upgrade).last(),
+                Some(Event::Failed {
+                    phase: Phase::Noise,
+                    reason: PadawanError::NoiseProtocolMismatch(protocol)
+                }) if protocol == name
+            ));
+        };
+        let aes_gcm: NoiseConfig = "Noise_XX_25519_AESGCM_SHA256".parse().unwrap();
+        let mut dialer = upgrade(Role::Dialer, Version::V1).with_noise_config(aes_gcm);
+        let mut listener = upgrade(Role::Listener, Version::V1);
+        pump(&mut dialer, &mut listener);
+        assert_mismatch(&mut dialer, "Noise_XX_25519_AESGCM_SHA256");
+
+        let ik: NoiseConfig = "Noise_IK_25519_ChaChaPoly_SHA256".parse().unwrap();
+        let remote_static = noise::generate_keypair().unwrap().public;
+        let mut dialer = upgrade(Role::Dialer, Version::V1)
+            .with_noise_config(ik.with_remote_static(&remote_static));
+        let mut listener = upgrade(Role::Listener, Version::V1);
+        pump(&mut dialer, &mut listener);
+        assert_mismatch(&mut listener, "Noise_XX_25519_ChaChaPoly_SHA256");
+    }
+
+    #[test]
+    fn establish_tls() {
+        for version in [Version::V1, Version::V1Lazy] {
+            let listener_key = identity::Keypair::generate_ed25519();
+            let listener_id = PeerId::from_public_key(&listener_key.public());
+            let mut dialer = upgrade(Role::Dialer, version)
+                .with_security(&[Security::Tls])
+                .with_expected_peer(listener_id);
+            let mut listener = Upgrade::new(Role::Listener, listener_key)
+                .with_security(&[Security::Noise, Security::Tls]);
+            pump(&mut dialer, &mut listener);
+            assert_established_with(&events(&mut dialer), ProtocolName::TLS);
+            assert_established_with(&events(&mut listener), ProtocolName::TLS);
+            assert_eq!(dialer.remote_peer_id(), Some(&listener_id));
+            assert!(listener.remote_peer_id().is_some());
+            assert!(matches!(
+                dialer.into_transport(),
+                Some((SecureTransport::Tls(_), _))
+            ));
+        }
+
+        // Fall back to the next protocol supported by the listener
+        let mut dialer =
+            upgrade(Role::Dialer, Version::V1).with_security(&[Security::Tls, Security::Noise]);
+        let mut listener = upgrade(Role::Listener, Version::V1);
+        pump(&mut dialer, &mut listener);
+        assert_established(&events(&mut dialer));
+
+        let mut dialer = upgrade(Role::Dialer, Version::V1)
+            .with_security(&[Security::Tls])
+            .with_expected_peer(PeerId::random());
+        let mut listener = upgrade(Role::Listener, Version::V1).with_security(&[Security::Tls]);
+        pump(&mut dialer, &mut listener);
+        assert!(matches!(
+            events(&mut dialer).last(),
+            Some(Event::Failed {
+                phase: Phase::Tls,
+                reason: PadawanError::PeerIdMismatch { .. }
+            })
+        ));
+    }
+
+    #[cfg(feature = "plaintext")]
+    #[test]
+    fn establish_plaintext() {
+        for version in [Version::V1, Version::V1Lazy] {
+            let listener_key = identity::Keypair::generate_ed25519();
+            let listener_id = PeerId::from_public_key(&listener_key.public());
+            let mut dialer = upgrade(Role::Dialer, version)
+                .with_security(&[Security::Plaintext])
+                .with_expected_peer(listener_id);
+            let mut listener = Upgrade::new(Role::Listener, listener_key)
+                .with_security(&[Security::Noise, Security::Plaintext]);
+            pump(&mut dialer, &mut listener);
+            assert_established_with(&events(&mut dialer), ProtocolName::PLAINTEXT);
+            assert_established_with(&events(&mut listener), ProtocolName::PLAINTEXT);
+            assert_eq!(dialer.remote_peer_id(), Some(&listener_id));
+            assert!(matches!(
+                dialer.into_transport(),
+                Some((SecureTransport::Plaintext, _))
+            ));
+        }
+
+        // Never negotiated unless configured
+        let mut dialer = upgrade(Role::Dialer, Version::V1)
+            .with_security(&[Security::Plaintext, Security::Noise]);
+        let mut listener = upgrade(Role::Listener, Version::V1);
+        pump(&mut dialer, &mut listener);
+        assert_established(&events(&mut dialer));
+    }
+
+    #[test]
+    fn establish_early_muxer() {
+        let mut exchanges = Vec::new();
+        for (dialer_early, listener_early) in [(true, true), (true, false), (false, true)] {
+            let mut dialer = upgrade(Role::Dialer, Version::V1).with_early_muxer(dialer_early);
+            let mut listener =
+                upgrade(Role::Listener, Version::V1).with_early_muxer(listener_early);
+            exchanges.push(pump(&mut dialer, &mut listener));
+            assert_established(&events(&mut dialer));
+            assert_established(&events(&mut listener));
+        }
+        assert!(exchanges[0] < exchanges[1]);
+        assert_eq!(exchanges[1], exchanges[2]);
+    }
+
+    #[test]
+    fn establish_mplex() {
+        for early_muxer in [true, false] {
+            let mut dialer = upgrade(Role::Dialer, Version::V1).with_early_muxer(early_muxer);
+            let mut listener = upgrade(Role::Listener, Version::V1).with_muxers(&[Muxer::Mplex]);
+            pump(&mut dialer, &mut listener);
+            for events in [events(&mut dialer), events(&mut listener)] {
+                assert!(matches!(
+                    &events[..],
+                    [.., Event::ProtocolAgreed(mplex), Event::Established]
+                        if *mplex == ProtocolName::MPLEX
+                ));
+            }
+            assert_eq!(dialer.muxer(), Some(Muxer::Mplex));
+            assert_eq!(listener.muxer(), Some(Muxer::Mplex));
+        }
+
+        let mut dialer = upgrade(Role::Dialer, Version::V1).with_muxers(&[Muxer::Yamux]);
+        let mut listener = upgrade(Role::Listener, Version::V1).with_muxers(&[Muxer::Mplex]);
+        pump(&mut dialer, &mut listener);
+        assert!(matches!(
+            events(&mut dialer).last(),
+            Some(Event::Failed {
+                phase: Phase::Multiplex,
+                reason: PadawanError::NegotiationFailed
+            })
+        ));
+        assert!(dialer.muxer().is_none());
+    }
+
+    #[test]
+    fn establish_key_types() {
+        let keypairs = [
+            identity::Keypair::generate_ed25519(),
+            #[cfg(feature = "secp256k1")]
+            identity::Keypair::generate_secp256k1(),
+            #[cfg(feature = "ecdsa")]
+            identity::Keypair::generate_ecdsa(),
+            #[cfg(feature = "rsa")]
+            identity::Keypair::rsa_from_pkcs8(
+                &mut include_bytes!("../../testdata/rsa-2048.pk8").to_vec(),
+            )
+            .unwrap(),
+        ];
+        // Each key type is used by either side against an ed25519 peer
+        for keypair in keypairs {
+            let other = identity::Keypair::generate_ed25519();
+            for (dialer_key, listener_key) in [(keypair.clone(), other.clone()), (other, keypair)] {
+                let dialer_id = PeerId::from_public_key(&dialer_key.public());
+                let listener_id = PeerId::from_public_key(&listener_key.public());
+                let mut dialer =
+                    Upgrade::new(Role::Dialer, dialer_key).with_expected_peer(listener_id);
+                let mut listener =
+                    Upgrade::new(Role::Listener, listener_key).with_expected_peer(dialer_id);
+                pump(&mut dialer, &mut listener);
+                assert_established(&events(&mut dialer));
+                assert_established(&events(&mut listener));
+                assert_eq!(dialer.remote_peer_id(), Some(&listener_id));
+                assert_eq!(listener.remote_peer_id(), Some(&dialer_id));
+            }
+        }
+    }
+
+    #[test]
+    fn report_noise_setup_failure() {
+        // The initiator of an IK handshake requires the static key of the remote peer
+        let config = NoiseConfig::default().with_pattern(noise::config::HandshakePattern::Ik);
+        let mut dialer = upgrade(Role::Dialer, Version::V1).with_noise_config(config);
+        let mut listener = upgrade(Role::Listener, Version::V1);
+        pump(&mut dialer, &mut listener);
+        assert!(matches!(
+            events(&mut dialer).last(),
+            Some(Event::Failed {
+                phase: Phase::Noise,
+                reason: PadawanError::MissingRemoteNoiseKey
+            })
+        ));
+        assert_eq!(dialer.state(), HandshakeState::Failed);
+    }
+
+    #[test]
+    fn verify_expected_peer() {
+        let listener_key = identity::Keypair::generate_ed25519();
+        let listener_id = PeerId::from_public_key(&listener_key.public());
+        let mut dialer = upgrade(Role::Dialer, Version::V1).with_expected_peer(listener_id);
+        let mut listener = Upgrade::new(Role::Listener, listener_key.clone());
+        pump(&mut dialer, &mut listener);
+        assert_established(&events(&mut dialer));
+        assert_eq!(dialer.remote_peer_id(), Some(&listener_id));
+
+        let mut dialer = upgrade(Role::Dialer, Version::V1).with_expected_peer(PeerId::random());
+        let mut listener = Upgrade::new(Role::Listener, listener_key);
+        pump(&mut dialer, &mut listener);
+        assert!(matches!(
+            events(&mut dialer).last(),
+            Some(Event::Failed {
+                phase: Phase::Noise,
+                reason: PadawanError::PeerIdMismatch { actual, .. }
+            }) if **actual == listener_id
+        ));
+        assert!(dialer.remote_peer_id().is_none());
+    }
+
+    #[test]
+    fn listener_rejects_unsupported() {
+        let mut listener = upgrade(Role::Listener, Version::V1);
+        assert_eq!(
+            listener.poll_transmit().unwrap(),
+            ProtocolName::MULTISTREAM.encode()
+        );
+        let mut input = ProtocolName::MULTISTREAM.encode();
+        input.extend_from_slice(&ProtocolName::new("/tls/1.0.0").unwrap().encode());
+        input.extend_from_slice(&Message::ListProtocols.encode());
+        listener.handle_input(&input);
+
+        let mut expected = Message::NotAvailable.encode();
+        expected.extend_from_slice(&Message::Protocols(vec![ProtocolName::NOISE]).encode());
+        assert_eq!(listener.poll_transmit().unwrap(), expected);
+        assert_eq!(listener.state(), HandshakeState::Negotiation);
+        assert!(listener.poll_event().is_none());
+    }
+
+    #[test]
+    fn dialer_fails_when_rejected() {
+        let mut dialer = upgrade(Role::Dialer, Version::V1);
+        assert_eq!(
+            dialer.poll_transmit().unwrap(),
+            ProtocolName::MULTISTREAM.encode()
+        );
+        dialer.handle_input(&ProtocolName::MULTISTREAM.encode());
+        assert_eq!(
+            dialer.poll_transmit().unwrap(),
+            ProtocolName::NOISE.encode()
+        );
+        dialer.handle_input(&Message::NotAvailable.encode());
+        assert!(matches!(
+            dialer.poll_event(),
+            Some(Event::Failed {
+                phase: Phase::Negotiation,
+                reason: PadawanError::NegotiationFailed
+            })
+        ));
+        assert_eq!(dialer.state(), HandshakeState::Failed);
+    }
+
+    #[test]
+    fn lazy_dialer_fails_on_deferred_rejection() {
+        let mut dialer = upgrade(Role::Dialer, Version::V1Lazy);
+        let sent = dialer.poll_transmit().unwrap();
+        assert!(sent.starts_with(&ProtocolName::MULTISTREAM.encode()));
+        assert_eq!(dialer.state(), HandshakeState::Noise);
+
+        let mut input = ProtocolName::MULTISTREAM.encode();
+        input.extend_from_slice(&Message::NotAvailable.encode());
+        dialer.handle_input(&input);
+        assert!(matches!(
+            dialer.poll_event(),
+            Some(Event::Failed {
+                phase: Phase::Noise,
+                reason: PadawanError::NegotiationFailed
+            })
+        ));
+    }
+}
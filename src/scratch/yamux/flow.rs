@@ -0,0 +1,168 @@
+//! Flow control of the streams of a yamux session.
+//!
+//! The receive window of a stream starts at [`DEFAULT_WINDOW`][] and doubles
+//! whenever the remote peer fills half of it within a few round trips, up to
+//! the maximum window of a stream. The windows of all the streams of a session
+//! stop growing once they exceed its memory budget, and the streams opened by
+//! the remote peer are reset once their default windows would exceed it.
+use std::time::Duration;
+
+use super::DEFAULT_WINDOW;
+
+/// The number of round trips within which half of a window must be consumed
+/// for the window to grow
+const RTT_FACTOR: u32 = 4;
+
+/// The flow control of a yamux session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct YamuxConfig {
+    max_stream_window: u32,
+    memory_budget: usize,
+    rtt_interval: Duration,
+}
+
+impl Default for YamuxConfig {
+    fn default() -> Self {
+        Self {
+            max_stream_window: 16 * 1024 * 1024,
+            memory_budget: 128 * 1024 * 1024,
+            rtt_interval: Duration::from_secs(10),
+        }
+    }
+}
+
+impl YamuxConfig {
+    /// Set the size up to which the receive window of a stream may grow.
+    ///
+    /// It is never less than [`DEFAULT_WINDOW`][].
+    pub fn with_max_stream_window(mut self, window: u32) -> Self {
+        self.max_stream_window = window.max(DEFAULT_WINDOW);
+        self
+    }
+
+    /// Set the size beyond which the receive windows of all the streams stop growing.
+    ///
+    /// Streams opened by the remote peer beyond the budget are reset, since every
+    /// stream is granted the default window, as the specification requires.
+    pub fn with_memory_budget(mut self, budget: usize) -> Self {
+        self.memory_budget = budget;
+        self
+    }
+
+    /// Set the period of the round-trip time measurements
+    pub fn with_rtt_interval(mut self, interval: Duration) -> Self {
+        self.rtt_interval = interval;
+        self
+    }
+
+    /// The size up to which the receive window of a stream may grow
+    pub fn max_stream_window(&self) -> u32 {
+        self.max_stream_window
+    }
+
+    /// The size beyond which the receive windows of all the streams stop growing
+    pub fn memory_budget(&self) -> usize {
+        self.memory_budget
+    }
+
+    /// The period of the round-trip time measurements
+    pub fn rtt_interval(&self) -> Duration {
+        self.rtt_interval
+    }
+}
+
+/// The accounting of the receive windows shared by the streams of a session
+#[derive(Debug)]
+pub(super) struct Flow {
+    max_stream_window: u32,
+    memory_budget: usize,
+    /// The sum of the receive windows of the open streams
+    allocated: usize,
+    /// The last measured round-trip time
+    rtt: Option<Duration>,
+}
+
+impl Flow {
+    /// Create the accounting of a session, with no stream open
+    /// and no round-trip time measured yet
+    pub fn new(config: &YamuxConfig) -> Self {
+        Self {
+            max_stream_window: config.max_stream_window,
+            memory_budget: config.memory_budget,
+            allocated: 0,
+            rtt: None,
+        }
+    }
+
+    /// Record the latest measured round-trip time, against which
+    /// the growth of the windows is judged
+    pub fn set_rtt(&mut self, rtt: Duration) {
+        self.rtt = Some(rtt);
+    }
+
+    /// Whether the default window of a new stream fits in the memory budget
+    pub fn admits(&self) -> bool {
+        self.allocated + DEFAULT_WINDOW as usize <= self.memory_budget
+    }
+
+    /// Account for the default window of a new stream
+    pub fn open(&mut self) {
+        self.allocated += DEFAULT_WINDOW as usize;
+    }
+
+    /// Release the receive `window` of a dropped stream
+    pub fn release(&mut self, window: u32) {
+        self.allocated = self.allocated.saturating_sub(window as usize);
+    }
+
+    /// Reserve the growth of a receive `window` whose half was consumed `elapsed`
+    /// since its last update.
+    ///
+    /// The window doubles if it was consumed within a few round trips, as far as
+    /// the maximum window of a stream and the memory budget allow.
+    pub fn grow(&mut self, window: u32, elapsed: Duration) -> u32 {
+        let fast = match self.rtt {
+            Some(rtt) => elapsed < rtt * RTT_FACTOR,
+            None => false,
+        };
+        if !fast {
+            return 0;
+        }
+        let available = self.memory_budget.saturating_sub(self.allocated);
+        let growth = window
+            .min(self.max_stream_window.saturating_sub(window))
+            .min(available.min(u32::MAX as usize) as u32);
+        self.allocated += growth as usize;
+        growth
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grow_window() {
+        let config = YamuxConfig::default()
+            .with_max_stream_window(3 * DEFAULT_WINDOW)
+            .with_memory_budget(4 * DEFAULT_WINDOW as usize);
+        let mut flow = Flow::new(&config);
+        flow.open();
+        let fast = Duration::from_millis(10);
+        // Without a measured round-trip time the window never grows
+        assert_eq!(flow.grow(DEFAULT_WINDOW, fast), 0);
+
+        flow.set_rtt(Duration::from_millis(5));
+        assert_eq!(flow.grow(DEFAULT_WINDOW, Duration::from_millis(100)), 0);
+        assert_eq!(flow.grow(DEFAULT_WINDOW, fast), DEFAULT_WINDOW);
+        // Bounded by the maximum window of a stream
+        assert_eq!(flow.grow(2 * DEFAULT_WINDOW, fast), DEFAULT_WINDOW);
+
+        // Bounded by the memory budget, regardless of new streams
+        flow.open();
+        assert_eq!(flow.allocated, 4 * DEFAULT_WINDOW as usize);
+        assert_eq!(flow.grow(DEFAULT_WINDOW, fast), 0);
+        flow.release(3 * DEFAULT_WINDOW);
+        assert_eq!(flow.grow(DEFAULT_WINDOW, fast), DEFAULT_WINDOW);
+    }
+}
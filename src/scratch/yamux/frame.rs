@@ -0,0 +1,252 @@
+//! The frames of the [yamux specification][yamux-spec].
+//!
+//! [yamux-spec]: https://github.com/hashicorp/yamux/blob/master/spec.md
+use std::ops::BitOr;
+
+use bytes::{BufMut, BytesMut};
+
+use crate::error::PadawanError;
+
+/// The size of the header that precedes every frame
+pub const HEADER_SIZE: usize = 12;
+
+/// The version of the protocol
+const VERSION: u8 = 0;
+
+/// The type of a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameType {
+    /// Carries data of a stream
+    Data,
+    /// Grants the remote peer additional credit to send on a stream
+    WindowUpdate,
+    /// Measures the round-trip time, or keeps the session alive
+    Ping,
+    /// Terminates the session
+    GoAway,
+}
+
+impl FrameType {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::Data),
+            1 => Some(Self::WindowUpdate),
+            2 => Some(Self::Ping),
+            3 => Some(Self::GoAway),
+            _ => None,
+        }
+    }
+
+    fn as_u8(self) -> u8 {
+        match self {
+            Self::Data => 0,
+            Self::WindowUpdate => 1,
+            Self::Ping => 2,
+            Self::GoAway => 3,
+        }
+    }
+}
+
+/// The flags of a frame
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Flags(u16);
+
+impl Flags {
+    /// Opens a stream, or starts a ping
+    pub const SYN: Self = Self(1);
+    /// Acknowledges a new stream, or responds to a ping
+    pub const ACK: Self = Self(2);
+    /// Half-closes a stream
+    pub const FIN: Self = Self(4);
+    /// Resets a stream
+    pub const RST: Self = Self(8);
+
+    pub fn contains(self, flags: Self) -> bool {
+        self.0 & flags.0 == flags.0
+    }
+}
+
+impl BitOr for Flags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// The reason for terminating a session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GoAwayCode {
+    Normal = 0,
+    ProtocolError = 1,
+    InternalError = 2,
+}
+
+/// The header of a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub frame_type: FrameType,
+    pub flags: Flags,
+    pub stream_id: u32,
+    /// The size of the body of a data frame, the credit of a window update,
+    /// the opaque value of a ping, or the code of a go away
+    pub length: u32,
+}
+
+impl Header {
+    /// The header of a data frame carrying `length` bytes
+    pub fn data(stream_id: u32, length: u32) -> Self {
+        Self::new(FrameType::Data, stream_id, length)
+    }
+
+    /// The header of a window update granting additional `credit`
+    pub fn window_update(stream_id: u32, credit: u32) -> Self {
+        Self::new(FrameType::WindowUpdate, stream_id, credit)
+    }
+
+    /// The header of a ping carrying the `opaque` value
+    pub fn ping(opaque: u32) -> Self {
+        Self::new(FrameType::Ping, 0, opaque)
+    }
+
+    /// The header of a go away with the given `code`
+    pub fn go_away(code: GoAwayCode) -> Self {
+        Self::new(FrameType::GoAway, 0, code as u32)
+    }
+
+    fn new(frame_type: FrameType, stream_id: u32, length: u32) -> Self {
+        Self {
+            frame_type,
+            flags: Flags::default(),
+            stream_id,
+            length,
+        }
+    }
+
+    /// Add the given `flags` to the header
+    pub fn with_flags(mut self, flags: Flags) -> Self {
+        self.flags = self.flags | flags;
+        self
+    }
+
+    /// Append the encoded header to the `out` buffer
+    pub fn encode(&self, out: &mut BytesMut) {
+        out.reserve(HEADER_SIZE);
+        out.put_u8(VERSION);
+        out.put_u8(self.frame_type.as_u8());
+        out.put_u16(self.flags.0);
+        out.put_u32(self.stream_id);
+        out.put_u32(self.length);
+    }
+
+    /// Decode a header from its [`HEADER_SIZE`][] bytes
+    pub fn decode(bytes: &[u8; HEADER_SIZE]) -> Result<Self, PadawanError> {
+        let invalid = |reason: &str| PadawanError::YamuxProtocol(reason.to_string());
+        if bytes[0] != VERSION {
+            return Err(invalid("unsupported version"));
+        }
+        let frame_type =
+            FrameType::from_u8(bytes[1]).ok_or_else(|| invalid("unknown frame type"))?;
+        let u32_at =
+            |i: usize| u32::from_be_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+        Ok(Self {
+            frame_type,
+            flags: Flags(u16::from_be_bytes([bytes[2], bytes[3]])),
+            stream_id: u32_at(4),
+            length: u32_at(8),
+        })
+    }
+}
+
+/// A decoded frame
+#[derive(Debug)]
+pub struct Frame {
+    pub header: Header,
+    /// The body of a data frame, empty for any other type
+    pub body: BytesMut,
+}
+
+/// Append a frame to the `out` buffer.
+///
+/// The `body` must be empty for any type but data frames, whose header
+/// carries the size of the body.
+pub fn encode_frame(header: Header, body: &[u8], out: &mut BytesMut) {
+    debug_assert!(header.frame_type == FrameType::Data || body.is_empty());
+    out.reserve(HEADER_SIZE + body.len());
+    header.encode(out);
+    out.extend_from_slice(body);
+}
+
+/// Split the next complete frame from the front of the `buffer`.
+///
+/// Returns `None` if more bytes are needed.
+///
+/// # Errors
+///
+/// Fails if the header is invalid, or the body of a data frame exceeds `max_body`.
+pub fn decode_frame(buffer: &mut BytesMut, max_body: u32) -> Result<Option<Frame>, PadawanError> {
+    let header = match buffer.get(..HEADER_SIZE) {
+        Some(bytes) => Header::decode(bytes.try_into().expect("header size"))?,
+        None => return Ok(None),
+    };
+    let body_size = match header.frame_type {
+        FrameType::Data if header.length > max_body => {
+            return Err(PadawanError::YamuxProtocol(
+                "data frame exceeds the receive window".to_string(),
+            ));
+        }
+        FrameType::Data => header.length as usize,
+        _ => 0,
+    };
+    if buffer.len() < HEADER_SIZE + body_size {
+        buffer.reserve(HEADER_SIZE + body_size - buffer.len());
+        return Ok(None);
+    }
+    let mut frame = buffer.split_to(HEADER_SIZE + body_size);
+    let body = frame.split_off(HEADER_SIZE);
+    Ok(Some(Frame { header, body }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let mut buffer = BytesMut::new();
+        let open = Header::window_update(1, 0).with_flags(Flags::SYN);
+        encode_frame(open, &[], &mut buffer);
+        let close = Header::data(1, 5).with_flags(Flags::FIN);
+        encode_frame(close, b"hello", &mut buffer);
+        encode_frame(Header::ping(42).with_flags(Flags::ACK), &[], &mut buffer);
+
+        let frame = decode_frame(&mut buffer, 5).unwrap().unwrap();
+        assert_eq!(frame.header, open);
+        assert!(frame.header.flags.contains(Flags::SYN));
+        assert!(!frame.header.flags.contains(Flags::ACK));
+        let frame = decode_frame(&mut buffer, 5).unwrap().unwrap();
+        assert_eq!(frame.header, close);
+        assert_eq!(&frame.body[..], b"hello");
+        let frame = decode_frame(&mut buffer, 5).unwrap().unwrap();
+        assert_eq!(frame.header.frame_type, FrameType::Ping);
+        assert_eq!(frame.header.length, 42);
+        assert!(decode_frame(&mut buffer, 5).unwrap().is_none());
+    }
+
+    #[test]
+    fn reject_invalid_frames() {
+        let mut buffer = BytesMut::new();
+        encode_frame(Header::data(1, 6), b"hello!", &mut buffer);
+        let mut partial = buffer.split_to(HEADER_SIZE + 3);
+        assert!(decode_frame(&mut partial, 6).unwrap().is_none());
+        assert!(matches!(
+            decode_frame(&mut partial, 5),
+            Err(PadawanError::YamuxProtocol(_))
+        ));
+
+        let mut unknown = BytesMut::from(&[0, 4, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0][..]);
+        assert!(decode_frame(&mut unknown, 5).is_err());
+        let mut version = BytesMut::from(&[1, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0][..]);
+        assert!(decode_frame(&mut version, 5).is_err());
+    }
+}
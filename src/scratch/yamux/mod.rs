@@ -0,0 +1,589 @@
+//! Implementation of the [yamux][yamux-spec] stream multiplexer.
+//!
+//! A [`Session`][] multiplexes [`Substream`][]s as any other
+//! [`session`][`crate::scratch::session`] of a stream muxer, while [`Yamux`][]
+//! handles the frames of the protocol.
+//!
+//! The receive windows of the streams are tuned to the round-trip time, which
+//! the session measures periodically, within the limits of its [`YamuxConfig`][].
+//!
+//! [yamux-spec]: https://github.com/hashicorp/yamux/blob/master/spec.md
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::{Bytes, BytesMut};
+use tokio::sync::oneshot;
+
+use super::session::{self, Muxer, SessionState};
+use crate::error::PadawanError;
+
+use flow::Flow;
+pub use flow::YamuxConfig;
+use frame::{Flags, Frame, FrameType, GoAwayCode, Header};
+use stream::StreamState;
+pub use stream::Substream;
+
+pub mod flow;
+pub mod frame;
+pub mod stream;
+
+/// The initial receive window of every stream, as set by the specification.
+/// It grows up to the [maximum window][`YamuxConfig::with_max_stream_window`] of a stream.
+pub const DEFAULT_WINDOW: u32 = 256 * 1024;
+
+/// The maximum size of the data carried by a single frame, so that
+/// streams sending at the same time are interleaved
+pub const MAX_DATA_SIZE: usize = 16 * 1024;
+
+/// The side of the session, which determines the identifiers of the
+/// streams it opens
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Opens streams with odd identifiers, e.g. the dialer
+    Client,
+    /// Opens streams with even identifiers, e.g. the listener
+    Server,
+}
+
+/// Requests to the session from its [`Control`][] and [`Substream`][]s
+pub(crate) type Command = session::Command<Yamux>;
+
+/// A handle for opening and accepting streams, and closing the session
+pub type Control = session::Control<Yamux>;
+
+/// A yamux session over the stream `S`
+pub type Session<S> = session::Session<S, Yamux>;
+
+/// Requests to a yamux session beyond those common to every muxer
+#[derive(Debug)]
+pub enum Request {
+    Ping(oneshot::Sender<Duration>),
+}
+
+impl Control {
+    /// Measure the round-trip time to the remote peer
+    pub async fn ping(&self) -> Result<Duration, PadawanError> {
+        let (reply, pong) = oneshot::channel();
+        self.request(Command::Muxer(Request::Ping(reply)))?;
+        pong.await.map_err(|_| PadawanError::SessionClosed)
+    }
+}
+
+impl<S> Session<S> {
+    /// Create a new session over the given stream
+    pub fn new(io: S, mode: Mode) -> Self {
+        Self::with_muxer(io, Yamux::new(mode))
+    }
+
+    /// Set the flow control of the session
+    pub fn with_config(mut self, config: YamuxConfig) -> Self {
+        self.state.muxer.flow = Arc::new(Mutex::new(Flow::new(&config)));
+        self.state.muxer.config = config;
+        self
+    }
+}
+
+/// The frame handling of a yamux session.
+///
+/// Closing the session through its [`Control`][] notifies the remote peer, which
+/// may finish the streams already open. A protocol violation of the remote peer
+/// is reported to it before the session fails, like a [`GoAway`][`FrameType::GoAway`]
+/// frame reporting an error.
+pub struct Yamux {
+    config: YamuxConfig,
+    /// The accounting of the receive windows shared with the streams
+    flow: Arc<Mutex<Flow>>,
+    /// The identifier of the next stream opened by the local peer
+    next_id: u32,
+    /// Pings awaiting their response, by opaque value, along with the
+    /// requester unless sent for measuring the round-trip time
+    pings: HashMap<u32, (Instant, Option<oneshot::Sender<Duration>>)>,
+    next_ping: u32,
+    /// Whether the remote peer accepts no more streams
+    remote_closing: bool,
+}
+
+impl Yamux {
+    fn new(mode: Mode) -> Self {
+        let config = YamuxConfig::default();
+        Self {
+            flow: Arc::new(Mutex::new(Flow::new(&config))),
+            config,
+            next_id: match mode {
+                Mode::Client => 1,
+                Mode::Server => 2,
+            },
+            pings: HashMap::new(),
+            next_ping: 0,
+            remote_closing: false,
+        }
+    }
+}
+
+impl Muxer for Yamux {
+    type Id = u32;
+    type Frame = (Header, Bytes);
+    type Stream = StreamState;
+    type Substream = Substream;
+    type Request = Request;
+
+    const NAME: &'static str = "yamux";
+
+    fn on_input(session: &mut SessionState<Self>) -> Result<(), PadawanError> {
+        let max_body = session.muxer.config.max_stream_window();
+        while let Some(frame) = frame::decode_frame(&mut session.received, max_body)? {
+            session.on_frame(frame)?;
+        }
+        Ok(())
+    }
+
+    fn encode((header, body): &Self::Frame, out: &mut BytesMut) {
+        frame::encode_frame(*header, body, out);
+    }
+
+    fn reset(id: u32) -> Self::Frame {
+        (
+            Header::window_update(id, 0).with_flags(Flags::RST),
+            Bytes::new(),
+        )
+    }
+
+    fn open_stream(session: &mut SessionState<Self>) -> Result<Substream, PadawanError> {
+        let yamux = &mut session.muxer;
+        if session.closing || yamux.remote_closing {
+            return Err(PadawanError::SessionClosed);
+        }
+        let id = yamux.next_id;
+        yamux.next_id = yamux
+            .next_id
+            .checked_add(2)
+            .ok_or(PadawanError::SessionClosed)?;
+        let state = Arc::new(Mutex::new(StreamState::default()));
+        session.streams.insert(id, state.clone());
+        session.send_header(Header::window_update(id, 0).with_flags(Flags::SYN));
+        Ok(session.substream(id, state))
+    }
+
+    fn on_request(session: &mut SessionState<Self>, request: Request) {
+        match request {
+            Request::Ping(reply) => session.ping(Some(reply)),
+        }
+    }
+
+    fn on_close(session: &mut SessionState<Self>) {
+        session.send_header(Header::go_away(GoAwayCode::Normal));
+    }
+
+    fn on_violation(session: &mut SessionState<Self>, err: &PadawanError) -> bool {
+        let violation = matches!(err, PadawanError::YamuxProtocol(_));
+        if violation {
+            session.send_header(Header::go_away(GoAwayCode::ProtocolError));
+        }
+        violation
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(self.config.rtt_interval())
+    }
+
+    /// Send a ping for measuring the round-trip time, unless one is pending
+    fn on_tick(session: &mut SessionState<Self>) {
+        if session
+            .muxer
+            .pings
+            .values()
+            .all(|(_, reply)| reply.is_some())
+        {
+            session.ping(None);
+        }
+    }
+}
+
+impl SessionState<Yamux> {
+    fn on_frame(&mut self, frame: Frame) -> Result<(), PadawanError> {
+        let header = frame.header;
+        match header.frame_type {
+            FrameType::Ping => return self.on_ping(header),
+            FrameType::GoAway => return self.on_go_away(header),
+            FrameType::Data | FrameType::WindowUpdate => {}
+        }
+        if header.stream_id == 0 {
+            return Err(protocol_error("stream frame for the session"));
+        }
+        if header.flags.contains(Flags::SYN) {
+            self.on_syn(header.stream_id)?;
+        }
+        let state = match self.streams.get(&header.stream_id) {
+            Some(state) => state.clone(),
+            None => {
+                tracing::trace!("Ignoring frame of closed stream {}", header.stream_id);
+                return Ok(());
+            }
+        };
+        let mut state = state.lock().expect("stream state lock");
+        if header.frame_type == FrameType::Data {
+            if header.length > state.recv_window {
+                return Err(protocol_error("data exceeds the receive window"));
+            }
+            state.recv_window -= header.length;
+            state.buffer.extend_from_slice(&frame.body);
+        } else {
+            state.send_window = state.send_window.saturating_add(header.length);
+        }
+        if header.flags.contains(Flags::FIN) {
+            state.remote_closed = true;
+        }
+        let reset = header.flags.contains(Flags::RST);
+        if reset {
+            state.reset = true;
+        }
+        state.wake();
+        drop(state);
+        if reset {
+            self.streams.remove(&header.stream_id);
+        }
+        Ok(())
+    }
+
+    /// Accept a stream opened by the remote peer
+    fn on_syn(&mut self, id: u32) -> Result<(), PadawanError> {
+        if id % 2 == self.muxer.next_id % 2 {
+            return Err(protocol_error("stream identifier of the local peer"));
+        }
+        if self.streams.contains_key(&id) {
+            return Err(protocol_error("stream identifier already in use"));
+        }
+        let admitted = self.muxer.flow.lock().expect("flow lock").admits();
+        if !admitted {
+            tracing::debug!("Resetting stream {} beyond the memory budget", id);
+        }
+        if self.closing || !admitted {
+            self.send(&Yamux::reset(id));
+            return Ok(());
+        }
+        let state = Arc::new(Mutex::new(StreamState::default()));
+        let substream = self.substream(id, state.clone());
+        self.streams.insert(id, state);
+        self.send_header(Header::window_update(id, 0).with_flags(Flags::ACK));
+        self.accept(substream);
+        Ok(())
+    }
+
+    fn on_ping(&mut self, header: Header) -> Result<(), PadawanError> {
+        if header.flags.contains(Flags::SYN) {
+            self.send_header(Header::ping(header.length).with_flags(Flags::ACK));
+        } else if let Some((sent, pong)) = self.muxer.pings.remove(&header.length) {
+            let rtt = sent.elapsed();
+            tracing::trace!("Measured a round-trip time of {:?}", rtt);
+            self.muxer.flow.lock().expect("flow lock").set_rtt(rtt);
+            if let Some(pong) = pong {
+                let _ = pong.send(rtt);
+            }
+        }
+        Ok(())
+    }
+
+    fn on_go_away(&mut self, header: Header) -> Result<(), PadawanError> {
+        if header.length != GoAwayCode::Normal as u32 {
+            return Err(PadawanError::YamuxGoAway(header.length));
+        }
+        tracing::debug!("Remote peer accepts no more yamux streams");
+        self.muxer.remote_closing = true;
+        Ok(())
+    }
+
+    /// Create the handle of a new stream, accounting for its receive window
+    fn substream(&mut self, id: u32, state: Arc<Mutex<StreamState>>) -> Substream {
+        let flow = self.muxer.flow.clone();
+        flow.lock().expect("flow lock").open();
+        Substream::new(id, state, flow, self.commands())
+    }
+
+    fn ping(&mut self, reply: Option<oneshot::Sender<Duration>>) {
+        let yamux = &mut self.muxer;
+        let opaque = yamux.next_ping;
+        yamux.next_ping = yamux.next_ping.wrapping_add(1);
+        yamux.pings.insert(opaque, (Instant::now(), reply));
+        self.send_header(Header::ping(opaque).with_flags(Flags::SYN));
+    }
+
+    /// Queue a frame without a body for sending
+    fn send_header(&mut self, header: Header) {
+        self.send(&(header, Bytes::new()));
+    }
+}
+
+fn protocol_error(reason: &str) -> PadawanError {
+    PadawanError::YamuxProtocol(reason.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch::session::spawn_pair;
+    use bytes::BytesMut;
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+    use tokio::sync::mpsc;
+
+    fn session_pair() -> (Control, Control) {
+        let (client, server) = tokio::io::duplex(4096);
+        spawn_pair(
+            Session::new(client, Mode::Client),
+            Session::new(server, Mode::Server),
+        )
+    }
+
+    /// Connect a pair of streams through a link delaying every chunk by `latency`
+    fn delayed_pair(latency: Duration) -> (DuplexStream, DuplexStream) {
+        let (client, client_link) = tokio::io::duplex(1024 * 1024);
+        let (server, server_link) = tokio::io::duplex(1024 * 1024);
+        let (client_read, client_write) = tokio::io::split(client_link);
+        let (server_read, server_write) = tokio::io::split(server_link);
+        tokio::spawn(delay(client_read, server_write, latency));
+        tokio::spawn(delay(server_read, client_write, latency));
+        (client, server)
+    }
+
+    async fn delay<R, W>(mut read: R, mut write: W, latency: Duration)
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let (chunks, mut delayed) = mpsc::unbounded_channel::<(tokio::time::Instant, Vec<u8>)>();
+        tokio::spawn(async move {
+            while let Some((deadline, chunk)) = delayed.recv().await {
+                tokio::time::sleep_until(deadline).await;
+                if write.write_all(&chunk).await.is_err() {
+                    return;
+                }
+            }
+            let _ = write.shutdown().await;
+        });
+        let mut chunk = [0; MAX_DATA_SIZE];
+        while let Ok(n @ 1..) = read.read(&mut chunk).await {
+            let deadline = tokio::time::Instant::now() + latency;
+            let _ = chunks.send((deadline, chunk[..n].to_vec()));
+        }
+    }
+
+    #[tokio::test]
+    async fn open_and_accept() {
+        let (client, server) = session_pair();
+        let mut outbound = client.open_stream().await.unwrap();
+        let mut inbound = server.accept().await.unwrap();
+        assert_eq!(outbound.id(), inbound.id());
+        assert_eq!(outbound.id() % 2, 1);
+
+        outbound.write_all(b"ping").await.unwrap();
+        let mut received = [0; 4];
+        inbound.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"ping");
+        inbound.write_all(b"pong").await.unwrap();
+        inbound.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        outbound.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"pong");
+
+        // The server opens streams with even identifiers
+        let outbound = server.open_stream().await.unwrap();
+        assert_eq!(outbound.id(), 2);
+        assert_eq!(client.accept().await.unwrap().id(), 2);
+    }
+
+    #[tokio::test]
+    async fn transfer_beyond_window() {
+        let (client, server) = session_pair();
+        let size = 4 * DEFAULT_WINDOW as usize;
+        let sent: Vec<u8> = (0..size).map(|i| i as u8).collect();
+        let mut transfers = Vec::new();
+        for _ in 0..3 {
+            let mut outbound = client.open_stream().await.unwrap();
+            let sent = sent.clone();
+            transfers.push(tokio::spawn(async move {
+                outbound.write_all(&sent).await.unwrap();
+                outbound.shutdown().await.unwrap();
+            }));
+        }
+        for _ in 0..3 {
+            let mut inbound = server.accept().await.unwrap();
+            let mut received = Vec::new();
+            inbound.read_to_end(&mut received).await.unwrap();
+            assert!(received == sent);
+        }
+        for transfer in transfers {
+            transfer.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn reset_on_drop() {
+        let (client, server) = session_pair();
+        let mut outbound = client.open_stream().await.unwrap();
+        outbound.write_all(b"ping").await.unwrap();
+        drop(outbound);
+
+        let mut inbound = server.accept().await.unwrap();
+        let mut received = [0; 4];
+        inbound.read_exact(&mut received).await.unwrap();
+        let err = inbound.read(&mut received).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+        let err = inbound.write_all(b"pong").await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+    }
+
+    #[tokio::test]
+    async fn tune_windows_to_rtt() {
+        let budget = 4 * DEFAULT_WINDOW as usize;
+        let config = YamuxConfig::default()
+            .with_max_stream_window(16 * DEFAULT_WINDOW)
+            .with_memory_budget(budget)
+            .with_rtt_interval(Duration::from_millis(20));
+        let (client, server) = delayed_pair(Duration::from_millis(5));
+        let (client, server) = spawn_pair(
+            Session::new(client, Mode::Client).with_config(config.clone()),
+            Session::new(server, Mode::Server).with_config(config),
+        );
+        assert!(server.ping().await.unwrap() >= Duration::from_millis(10));
+
+        let sent = vec![7; 8 * DEFAULT_WINDOW as usize];
+        let mut transfers = Vec::new();
+        for _ in 0..2 {
+            let mut outbound = client.open_stream().await.unwrap();
+            let sent = sent.clone();
+            transfers.push(tokio::spawn(async move {
+                outbound.write_all(&sent).await.unwrap();
+                outbound.shutdown().await.unwrap();
+            }));
+        }
+        let mut inbound = Vec::new();
+        for _ in 0..2 {
+            let mut stream = server.accept().await.unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            assert!(received == sent);
+            inbound.push(stream);
+        }
+        for transfer in transfers {
+            transfer.await.unwrap();
+        }
+        // The windows grow while they are consumed within a few round trips,
+        // but never beyond the memory budget of the session
+        let windows: Vec<u32> = inbound.iter().map(Substream::receive_window).collect();
+        assert!(windows.iter().any(|&window| window > DEFAULT_WINDOW));
+        assert!(windows.iter().map(|&window| window as usize).sum::<usize>() <= budget);
+    }
+
+    #[tokio::test]
+    async fn reset_streams_beyond_memory_budget() {
+        let (client, server) = tokio::io::duplex(4096);
+        let config = YamuxConfig::default().with_memory_budget(2 * DEFAULT_WINDOW as usize);
+        let (client, server) = spawn_pair(
+            Session::new(client, Mode::Client),
+            Session::new(server, Mode::Server).with_config(config),
+        );
+        let mut outbound = Vec::new();
+        for _ in 0..3 {
+            outbound.push(client.open_stream().await.unwrap());
+        }
+        let mut rejected = outbound.pop().unwrap();
+        let err = rejected.read(&mut [0; 4]).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+        let mut inbound = Vec::new();
+        for stream in &outbound {
+            inbound.push(server.accept().await.unwrap());
+            assert_eq!(inbound.last().unwrap().id(), stream.id());
+        }
+
+        // Dropping a stream releases its window for the next one
+        drop(inbound.pop());
+        let stream = client.open_stream().await.unwrap();
+        assert_eq!(server.accept().await.unwrap().id(), stream.id());
+    }
+
+    #[tokio::test]
+    async fn ping() {
+        let (client, server) = session_pair();
+        assert!(client.ping().await.is_ok());
+        assert!(server.ping().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn go_away() {
+        let (client, server) = session_pair();
+        let mut outbound = client.open_stream().await.unwrap();
+        let mut inbound = server.accept().await.unwrap();
+        client.close().unwrap();
+        assert!(matches!(
+            client.open_stream().await,
+            Err(PadawanError::SessionClosed)
+        ));
+        assert!(client.accept().await.is_none());
+
+        // The session of the server terminates once the client shuts down the underlying stream
+        assert!(outbound.write_all(b"ping").await.is_err());
+        let mut received = Vec::new();
+        assert!(inbound.read_to_end(&mut received).await.is_err());
+        assert!(server.accept().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn reject_protocol_violation() {
+        let (client, mut server) = tokio::io::duplex(4096);
+        let client = Session::new(client, Mode::Client);
+        // A stream opened with an identifier of the client
+        let mut frame = BytesMut::new();
+        Header::window_update(1, 0)
+            .with_flags(Flags::SYN)
+            .encode(&mut frame);
+        server.write_all(&frame).await.unwrap();
+        assert!(matches!(
+            client.run().await,
+            Err(PadawanError::YamuxProtocol(_))
+        ));
+
+        // Skip the ping measuring the round-trip time
+        let mut received = [0; frame::HEADER_SIZE];
+        let mut header = Header::ping(0);
+        while header.frame_type == FrameType::Ping {
+            server.read_exact(&mut received).await.unwrap();
+            header = Header::decode(&received).unwrap();
+        }
+        assert_eq!(header, Header::go_away(GoAwayCode::ProtocolError));
+    }
+
+    #[tokio::test]
+    async fn go_away_after_partial_write() {
+        // A small buffer, so that frames are written partially
+        let (client, mut server) = tokio::io::duplex(64);
+        let client = Session::new(client, Mode::Client);
+        let control = client.control();
+        let session = tokio::spawn(client.run());
+        let mut outbound = control.open_stream().await.unwrap();
+        outbound.write_all(&[7; MAX_DATA_SIZE]).await.unwrap();
+
+        // The data frame is pending once more than the opening and ping frames are received
+        let mut received = vec![0; 3 * frame::HEADER_SIZE];
+        server.read_exact(&mut received).await.unwrap();
+        let mut frame = BytesMut::new();
+        Header::window_update(1, 0)
+            .with_flags(Flags::SYN)
+            .encode(&mut frame);
+        server.write_all(&frame).await.unwrap();
+        server.read_to_end(&mut received).await.unwrap();
+        assert!(matches!(
+            session.await.unwrap(),
+            Err(PadawanError::YamuxProtocol(_))
+        ));
+
+        let mut received = BytesMut::from(&received[..]);
+        let mut frames = Vec::new();
+        while let Some(frame) = frame::decode_frame(&mut received, DEFAULT_WINDOW).unwrap() {
+            frames.push(frame);
+        }
+        assert!(received.is_empty());
+        assert!(frames.iter().any(|frame| frame.body.len() == MAX_DATA_SIZE));
+        let last = frames.last().unwrap();
+        assert_eq!(last.header, Header::go_away(GoAwayCode::ProtocolError));
+    }
+}
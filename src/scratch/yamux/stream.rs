@@ -0,0 +1,225 @@
+//! The substreams multiplexed over a yamux session.
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Instant;
+
+use bytes::{Buf, Bytes, BytesMut};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc;
+
+use super::flow::Flow;
+use super::frame::{Flags, Header};
+use super::{Command, DEFAULT_WINDOW, MAX_DATA_SIZE};
+use crate::scratch::session;
+
+/// The state of a stream shared between the session and the [`Substream`][]
+#[derive(Debug)]
+pub struct StreamState {
+    /// Data received but not yet read
+    pub(super) buffer: BytesMut,
+    /// The credit left to the remote peer for sending
+    pub(super) recv_window: u32,
+    /// The receive window granted to the remote peer, as tuned so far
+    pub(super) window: u32,
+    /// The bytes read since the last window update
+    pub(super) consumed: u32,
+    /// When the last window update was sent
+    last_update: Instant,
+    /// The credit left to the local peer for sending
+    pub(super) send_window: u32,
+    /// Whether the local peer sent its last data
+    pub(super) local_closed: bool,
+    /// Whether the remote peer sent its last data
+    pub(super) remote_closed: bool,
+    /// Whether the stream was reset, or the session terminated
+    pub(super) reset: bool,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+impl Default for StreamState {
+    fn default() -> Self {
+        Self {
+            buffer: BytesMut::new(),
+            recv_window: DEFAULT_WINDOW,
+            window: DEFAULT_WINDOW,
+            consumed: 0,
+            last_update: Instant::now(),
+            send_window: DEFAULT_WINDOW,
+            local_closed: false,
+            remote_closed: false,
+            reset: false,
+            read_waker: None,
+            write_waker: None,
+        }
+    }
+}
+
+impl session::Stream for StreamState {
+    fn reset(&mut self) {
+        self.reset = true;
+        self.wake();
+    }
+}
+
+impl StreamState {
+    /// Wake the pending reader and writer of the stream
+    pub(super) fn wake(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Compute the credit to grant the remote peer once half of the window
+    /// was consumed, growing the window if it was consumed quickly enough
+    fn window_update(&mut self, flow: &Mutex<Flow>) -> Option<u32> {
+        if self.remote_closed || self.consumed < self.window / 2 {
+            return None;
+        }
+        let growth = flow
+            .lock()
+            .expect("flow lock")
+            .grow(self.window, self.last_update.elapsed());
+        if growth > 0 {
+            tracing::trace!("Receive window grown to {}", self.window + growth);
+        }
+        self.window += growth;
+        let credit = std::mem::take(&mut self.consumed) + growth;
+        self.recv_window += credit;
+        self.last_update = Instant::now();
+        Some(credit)
+    }
+}
+
+/// A bidirectional stream multiplexed over a yamux session.
+///
+/// Writes are pending while the remote peer grants no credit, and credit is
+/// granted back once half of the receive window is read. Shutting down the
+/// stream half-closes it, while dropping it resets it unless both directions
+/// are already closed.
+pub struct Substream {
+    id: u32,
+    state: Arc<Mutex<StreamState>>,
+    flow: Arc<Mutex<Flow>>,
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+impl Substream {
+    pub(super) fn new(
+        id: u32,
+        state: Arc<Mutex<StreamState>>,
+        flow: Arc<Mutex<Flow>>,
+        commands: mpsc::UnboundedSender<Command>,
+    ) -> Self {
+        Self {
+            id,
+            state,
+            flow,
+            commands,
+        }
+    }
+
+    /// The identifier of the stream within the session
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// The receive window granted to the remote peer, as tuned so far
+    pub fn receive_window(&self) -> u32 {
+        self.state.lock().expect("stream state lock").window
+    }
+
+    fn send(&self, header: Header, body: Bytes) -> io::Result<()> {
+        self.commands
+            .send(Command::Frame((header, body)))
+            .map_err(|_| io::ErrorKind::BrokenPipe.into())
+    }
+}
+
+impl std::fmt::Debug for Substream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Substream").field("id", &self.id).finish()
+    }
+}
+
+impl AsyncRead for Substream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut state = self.state.lock().expect("stream state lock");
+        if state.buffer.is_empty() {
+            if state.remote_closed {
+                return Poll::Ready(Ok(()));
+            }
+            if state.reset {
+                return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+            }
+            state.read_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = state.buffer.len().min(buf.remaining());
+        buf.put_slice(&state.buffer[..n]);
+        state.buffer.advance(n);
+        state.consumed += n as u32;
+        if let Some(credit) = state.window_update(&self.flow) {
+            self.send(Header::window_update(self.id, credit), Bytes::new())?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for Substream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut state = self.state.lock().expect("stream state lock");
+        if state.reset {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
+        if state.local_closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        if state.send_window == 0 {
+            state.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = buf.len().min(state.send_window as usize).min(MAX_DATA_SIZE);
+        state.send_window -= n as u32;
+        let body = Bytes::copy_from_slice(&buf[..n]);
+        self.send(Header::data(self.id, n as u32), body)?;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Frames are handed over to the session right away
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut state = self.state.lock().expect("stream state lock");
+        if !state.local_closed && !state.reset {
+            state.local_closed = true;
+            let fin = Header::window_update(self.id, 0).with_flags(Flags::FIN);
+            self.send(fin, Bytes::new())?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for Substream {
+    fn drop(&mut self) {
+        let state = self.state.lock().expect("stream state lock");
+        let reset = !(state.reset || state.local_closed && state.remote_closed);
+        self.flow.lock().expect("flow lock").release(state.window);
+        let _ = self.commands.send(Command::Drop { id: self.id, reset });
+    }
+}
@@ -35,6 +35,13 @@ impl<T: NetworkBehaviour> Padawan<T> {
         }
     }
 
+    /// Use the identity of the given keypair instead of an auto-generated one
+    pub fn with_keypair(mut self, keypair: identity::Keypair) -> Self {
+        self.peer_id = PeerId::from_public_key(&keypair.public());
+        self.keypair = keypair;
+        self
+    }
+
     /// The peer-id of the client
     pub fn peer_id(&self) -> &PeerId {
         &self.peer_id
@@ -80,7 +87,7 @@ pub async fn handshake(
                 peer_id, endpoint, ..
             } => {
                 info!("Established connection with {:?}", peer_id);
-                if endpoint.get_remote_address() == &remote {
+                if is_remote(endpoint.get_remote_address(), &remote) {
                     break;
                 }
             }
@@ -88,7 +95,7 @@ pub async fn handshake(
                 peer_id, endpoint, ..
             } => {
                 info!("Connection with {:?} is closed", peer_id);
-                if endpoint.get_remote_address() == &remote {
+                if is_remote(endpoint.get_remote_address(), &remote) {
                     break;
                 }
             }
@@ -97,3 +104,14 @@ pub async fn handshake(
     }
     Ok(())
 }
+
+/// Compare the address of an endpoint with the dialed address,
+/// ignoring any `/p2p` component.
+fn is_remote(address: &multiaddr::Multiaddr, remote: &multiaddr::Multiaddr) -> bool {
+    let without_peer_id = |addr: &multiaddr::Multiaddr| {
+        addr.iter()
+            .filter(|protocol| !matches!(protocol, multiaddr::Protocol::P2p(_)))
+            .collect::<multiaddr::Multiaddr>()
+    };
+    without_peer_id(address) == without_peer_id(remote)
+}
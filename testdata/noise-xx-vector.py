@@ -0,0 +1,173 @@
+#!/usr/bin/env python3
+"""Generate testdata/noise-xx-vector.txt
+
+A libp2p noise handshake (Noise_XX_25519_ChaChaPoly_SHA256) implemented from
+the noise and libp2p-noise specifications on top of the primitives of the
+`cryptography` package, independently of snow and of this crate.
+
+    python3 testdata/noise-xx-vector.py > testdata/noise-xx-vector.txt
+"""
+import hashlib
+import hmac
+
+from cryptography.hazmat.primitives import serialization
+from cryptography.hazmat.primitives.asymmetric.ed25519 import Ed25519PrivateKey
+from cryptography.hazmat.primitives.asymmetric.x25519 import X25519PrivateKey
+from cryptography.hazmat.primitives.ciphers.aead import ChaCha20Poly1305
+
+PROTOCOL_NAME = b"Noise_XX_25519_ChaChaPoly_SHA256"
+SIGNATURE_PREFIX = b"noise-libp2p-static-key:"
+RAW = (serialization.Encoding.Raw, serialization.PublicFormat.Raw)
+
+INITIATOR = dict(identity=0x11, static=0x13, ephemeral=0x15,
+                 muxers=["/yamux/1.0.0", "/mplex/6.7.0"])
+RESPONDER = dict(identity=0x12, static=0x14, ephemeral=0x16,
+                 muxers=["/mplex/6.7.0"])
+
+
+def sha256(data):
+    return hashlib.sha256(data).digest()
+
+
+def hkdf(chaining_key, material):
+    temp = hmac.new(chaining_key, material, hashlib.sha256).digest()
+    first = hmac.new(temp, b"\x01", hashlib.sha256).digest()
+    second = hmac.new(temp, first + b"\x02", hashlib.sha256).digest()
+    return first, second
+
+
+class CipherState:
+    def __init__(self, key=None):
+        self.key = key
+        self.nonce = 0
+
+    def encrypt(self, ad, plaintext):
+        if self.key is None:
+            return plaintext
+        nonce = b"\x00" * 4 + self.nonce.to_bytes(8, "little")
+        self.nonce += 1
+        return ChaCha20Poly1305(self.key).encrypt(nonce, plaintext, ad)
+
+    def decrypt(self, ad, ciphertext):
+        if self.key is None:
+            return ciphertext
+        nonce = b"\x00" * 4 + self.nonce.to_bytes(8, "little")
+        self.nonce += 1
+        return ChaCha20Poly1305(self.key).decrypt(nonce, ciphertext, ad)
+
+
+class SymmetricState:
+    def __init__(self):
+        self.h = PROTOCOL_NAME.ljust(32, b"\x00")
+        self.ck = self.h
+        self.cipher = CipherState()
+        self.mix_hash(b"")  # empty prologue
+
+    def mix_hash(self, data):
+        self.h = sha256(self.h + data)
+
+    def mix_key(self, material):
+        self.ck, key = hkdf(self.ck, material)
+        self.cipher = CipherState(key)
+
+    def encrypt_and_hash(self, plaintext):
+        ciphertext = self.cipher.encrypt(self.h, plaintext)
+        self.mix_hash(ciphertext)
+        return ciphertext
+
+    def decrypt_and_hash(self, ciphertext):
+        plaintext = self.cipher.decrypt(self.h, ciphertext)
+        self.mix_hash(ciphertext)
+        return plaintext
+
+    def split(self):
+        first, second = hkdf(self.ck, b"")
+        return CipherState(first), CipherState(second)
+
+
+def x25519(byte):
+    private = X25519PrivateKey.from_private_bytes(bytes([byte]) * 32)
+    return private, private.public_key().public_bytes(*RAW)
+
+
+def protobuf(field, data):
+    """A length-delimited protobuf field, shorter than 128 bytes"""
+    assert len(data) < 128
+    return bytes([field << 3 | 2, len(data)]) + data
+
+
+def payload(peer, static_public):
+    identity = Ed25519PrivateKey.from_private_bytes(bytes([peer["identity"]]) * 32)
+    # The libp2p protobuf encoding of the ed25519 public key
+    public_key = b"\x08\x01" + protobuf(2, identity.public_key().public_bytes(*RAW))
+    signature = identity.sign(SIGNATURE_PREFIX + static_public)
+    extensions = b"".join(protobuf(2, muxer.encode()) for muxer in peer["muxers"])
+    return protobuf(1, public_key) + protobuf(2, signature) + protobuf(4, extensions)
+
+
+def handshake():
+    """Return the messages of the handshake, each read back by the other side"""
+    initiator, responder = SymmetricState(), SymmetricState()
+    i_static, i_static_public = x25519(INITIATOR["static"])
+    i_ephemeral, i_ephemeral_public = x25519(INITIATOR["ephemeral"])
+    r_static, r_static_public = x25519(RESPONDER["static"])
+    r_ephemeral, r_ephemeral_public = x25519(RESPONDER["ephemeral"])
+    messages = []
+
+    # -> e
+    initiator.mix_hash(i_ephemeral_public)
+    message = i_ephemeral_public + initiator.encrypt_and_hash(b"")
+    messages.append(("-> e", message))
+    responder.mix_hash(message[:32])
+    assert responder.decrypt_and_hash(message[32:]) == b""
+
+    # <- e, ee, s, es
+    responder.mix_hash(r_ephemeral_public)
+    responder.mix_key(r_ephemeral.exchange(i_ephemeral.public_key()))
+    message = r_ephemeral_public + responder.encrypt_and_hash(r_static_public)
+    responder.mix_key(r_static.exchange(i_ephemeral.public_key()))
+    message += responder.encrypt_and_hash(payload(RESPONDER, r_static_public))
+    messages.append(("<- e, ee, s, es, responder identity", message))
+
+    initiator.mix_hash(message[:32])
+    initiator.mix_key(i_ephemeral.exchange(r_ephemeral.public_key()))
+    assert initiator.decrypt_and_hash(message[32:80]) == r_static_public
+    initiator.mix_key(i_ephemeral.exchange(r_static.public_key()))
+    assert initiator.decrypt_and_hash(message[80:]) == payload(RESPONDER, r_static_public)
+
+    # -> s, se
+    message = initiator.encrypt_and_hash(i_static_public)
+    initiator.mix_key(i_static.exchange(r_ephemeral.public_key()))
+    message += initiator.encrypt_and_hash(payload(INITIATOR, i_static_public))
+    messages.append(("-> s, se, initiator identity", message))
+
+    assert responder.decrypt_and_hash(message[:48]) == i_static_public
+    responder.mix_key(r_ephemeral.exchange(i_static.public_key()))
+    assert responder.decrypt_and_hash(message[48:]) == payload(INITIATOR, i_static_public)
+    # Both sides derive the same transport keys
+    assert responder.h == initiator.h and responder.ck == initiator.ck
+
+    to_responder, to_initiator = initiator.split()
+    messages.append(('-> "ping", the first transport message', to_responder.encrypt(b"", b"ping")))
+    messages.append(('<- "pong", the first transport message', to_initiator.encrypt(b"", b"pong")))
+    return messages
+
+
+def main():
+    print("# A libp2p noise handshake (Noise_XX_25519_ChaChaPoly_SHA256), generated")
+    print("# independently of this crate and of snow by noise-xx-vector.py.")
+    print("#")
+    print("# Identity keys: ed25519 secrets of 32 0x11 bytes (initiator), and 0x12 bytes (responder)")
+    print("# Static X25519 keys: 32 0x13 bytes (initiator), and 0x14 bytes (responder)")
+    print("# Ephemeral X25519 keys: 32 0x15 bytes (initiator), and 0x16 bytes (responder)")
+    print("# The initiator advertises the /yamux/1.0.0 and /mplex/6.7.0 stream muxers,")
+    print("# and the responder only /mplex/6.7.0.")
+    print("#")
+    print("# Messages, hex-encoded without the frame length prefix:")
+    for description, message in handshake():
+        print(f"# {description}")
+        print(message.hex())
+
+
+if __name__ == "__main__":
+    main()